@@ -71,47 +71,40 @@ async fn consumer(thread: usize, args: Args) {
     let mut bytes = 0usize;
     let mut start = std::time::Instant::now();
     let mut expected = 0;
-    loop {
-        match rsq.rx.recv_async().await {
-            Ok(msg) => match &*msg {
-                Msg::ChannelMsg(msg) => {
-                    let msg_content = msg.content();
-                    if msg_content.len() <= 5 {
-                        let msg_str = std::str::from_utf8(msg.content()).unwrap();
-                        match msg_str {
-                            "start" => {
-                                if expected == 0 {
-                                    i = 0;
-                                    bytes = 0;
-                                    start = std::time::Instant::now();
-                                }
-                                expected += 1;
-                            }
-                            "stop" => {
-                                expected -= 1;
-                                if expected == 0 {
-                                    let elapsed = start.elapsed();
-                                    let msgs_per_sec = i * 1000000 / (elapsed.as_micros() + 1);
-                                    let mb_per_sec = (bytes * 1000000
-                                        / (elapsed.as_micros() as usize + 1))
-                                        / (1024 * 1024);
-                                    tracing::info!("thread {thread}: {i} msgs / {bytes} in {elapsed:?} ({msgs_per_sec}/s, {mb_per_sec}MB/s)");
-                                }
-                            }
-                            _ => println!("unknown msg {msg_str}"),
+    while let Ok(msg) = rsq.rx.recv_async().await {
+        if let Msg::ChannelMsg(msg) = &*msg {
+            let msg_content = msg.content();
+            if msg_content.len() <= 5 {
+                let msg_str = std::str::from_utf8(msg.content()).unwrap();
+                match msg_str {
+                    "start" => {
+                        if expected == 0 {
+                            i = 0;
+                            bytes = 0;
+                            start = std::time::Instant::now();
                         }
-                    } else {
-                        i += 1;
-                        bytes += msg_content.len();
+                        expected += 1;
                     }
-
-                    if i % 100000 == 0 && i > 0 {
-                        tracing::info!("thread {thread}: i={i}");
+                    "stop" => {
+                        expected -= 1;
+                        if expected == 0 {
+                            let elapsed = start.elapsed();
+                            let msgs_per_sec = i * 1000000 / (elapsed.as_micros() + 1);
+                            let mb_per_sec = (bytes * 1000000 / (elapsed.as_micros() as usize + 1))
+                                / (1024 * 1024);
+                            tracing::info!("thread {thread}: {i} msgs / {bytes} in {elapsed:?} ({msgs_per_sec}/s, {mb_per_sec}MB/s)");
+                        }
                     }
+                    _ => println!("unknown msg {msg_str}"),
                 }
-                _ => {}
-            },
-            Err(_) => break,
+            } else {
+                i += 1;
+                bytes += msg_content.len();
+            }
+
+            if i % 100000 == 0 && i > 0 {
+                tracing::info!("thread {thread}: i={i}");
+            }
         }
     }
 }
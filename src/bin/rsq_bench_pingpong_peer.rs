@@ -1,12 +1,12 @@
 #![warn(rust_2018_idioms)]
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::{Error, Result};
 
 use rsq::client::Rsq;
-use rsq::messaging::channel::ChannelId;
-use rsq::messaging::msg::Msg;
+use rsq::messaging::msg::{ControlMsg, Msg, StatusMsg};
 use rsq::messaging::peer::PeerId;
 
 #[monoio::main(enable_timer = true)]
@@ -17,19 +17,34 @@ async fn main() -> Result<(), Error> {
     let rsq = Rsq::new(&addr).await;
 
     let mut args: Vec<String> = std::env::args().collect();
-    let channel_id = if args.len() >= 2 {
-        ChannelId(args.remove(1))
+    let channel_name = if args.len() >= 2 {
+        args.remove(1)
     } else {
-        ChannelId("pingpong_test_channel".into())
+        "pingpong_test_channel".into()
     };
-    let msg = Msg::new_channel_msg(PeerId::new("receiver"), channel_id.clone(), "pong".into());
+
+    rsq.tx
+        .send_async(Arc::new(Msg::channel_join(channel_name.clone())))
+        .await?;
     rsq.tx
-        .send_async(Msg::channel_join(channel_id.clone()))
+        .send_async(Arc::new(Msg::ControlMsg(ControlMsg::ChannelCreate(
+            channel_name,
+        ))))
         .await?;
+    let channel_id = loop {
+        if let Msg::StatusMsg(StatusMsg::ChannelId(_, channel_id)) = &*rsq.rx.recv_async().await? {
+            break *channel_id;
+        }
+    };
+    let msg = Arc::new(Msg::new_channel_msg(
+        PeerId::new("receiver"),
+        channel_id,
+        "pong".into(),
+    ));
 
     loop {
         match rsq.rx.recv_async().await {
-            Ok(Msg::ChannelMsg(_msg)) => {
+            Ok(received) if matches!(*received, Msg::ChannelMsg(_)) => {
                 rsq.tx.send_async(msg.clone()).await.unwrap();
             }
             Ok(_) => {}
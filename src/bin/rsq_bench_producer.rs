@@ -1,7 +1,6 @@
 #![warn(rust_2018_idioms)]
 
 use std::error::Error;
-use std::mem::swap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -11,7 +10,6 @@ use argh::FromArgs;
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 use rsq::client::Rsq;
-use rsq::messaging::channel::ChannelId;
 use rsq::messaging::msg::{Msg, StatusMsg};
 use rsq::messaging::peer::PeerId;
 
@@ -116,7 +114,7 @@ async fn producer(thread: usize, args: Args) -> Result<Stats, Box<dyn Error>> {
 
     let msg = Arc::new(Msg::new_channel_msg(
         PeerId::new("sender"),
-        channel_id.clone(),
+        channel_id,
         "a".repeat(args.msg_size).into(),
     ));
 
@@ -125,7 +123,7 @@ async fn producer(thread: usize, args: Args) -> Result<Stats, Box<dyn Error>> {
     rsq.tx
         .send_async(Arc::new(Msg::new_channel_msg(
             PeerId::new("sender"),
-            channel_id.clone(),
+            channel_id,
             "start".into(),
         )))
         .await?;
@@ -133,15 +131,8 @@ async fn producer(thread: usize, args: Args) -> Result<Stats, Box<dyn Error>> {
     let start = std::time::Instant::now();
     let iterations = (args.msg_count / args.threads).max(1);
 
-    let mut stats = Stats::default();
-    for n in 0..iterations {
+    for _ in 0..iterations {
         rsq.tx.send_async(msg.clone()).await?;
-        let elapsed = start.elapsed();
-        // if elapsed.as_millis() >= 1000 {
-        // stats
-        //     .update(n * args.msg_size, n, elapsed)
-        //     .log(&format!("thread {thread}: "));
-        // }
     }
     tracing::info!(".");
     rsq.tx
@@ -176,14 +167,6 @@ struct Stats {
 }
 
 impl Stats {
-    pub fn new(bytes: usize, msgs: usize, elapsed: Duration) -> Self {
-        Self {
-            bytes,
-            msgs,
-            elapsed,
-        }
-    }
-
     pub fn msgs_per_sec(&self) -> u64 {
         (self.msgs * 1_000_000) as u64 / self.elapsed.as_micros().max(1) as u64
     }
@@ -220,15 +203,6 @@ impl Stats {
         res
     }
 
-    pub fn update(&mut self, bytes: usize, msgs: usize, elapsed: Duration) -> Stats {
-        let mut new = Stats::new(bytes, msgs, elapsed);
-        let diff = Stats::new(bytes - self.bytes, msgs - self.msgs, elapsed - self.elapsed);
-
-        swap(self, &mut new);
-
-        diff
-    }
-
     fn log(&self, prefix: &str) {
         let msgs_per_sec = self.msgs_per_sec();
         let mb_per_sec = self.bytes_per_sec() / (1024 * 1024);
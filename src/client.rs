@@ -1,14 +1,28 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{Error, Result};
 
 use monoio::{
-    io::{sink::Sink, stream::Stream, Splitable},
-    net::TcpStream,
+    io::{sink::Sink, stream::Stream, AsyncReadRent, Splitable},
+    net::{TcpStream, UnixStream},
 };
 use monoio_codec::{FramedRead, FramedWrite};
 
-use crate::{messaging::msg::Msg, monoio_bincode::BincodeCodec};
+use crate::{
+    compression::{self, Compression},
+    messaging::{
+        channel::ChannelId,
+        fragment::{self, Fragment},
+        headers::Headers,
+        msg::{ChannelMsg, Msg},
+        peer::PeerId,
+    },
+    monoio_bincode::{BincodeCodec, CompressionHandle},
+};
 
 type Rx = flume::Receiver<Arc<Msg>>;
 type Tx = flume::Sender<Arc<Msg>>;
@@ -23,11 +37,17 @@ pub struct Rsq {
 
 impl Rsq {
     pub async fn new(addr: &SocketAddr) -> Rsq {
+        Self::with_compression(addr, Vec::new()).await
+    }
+
+    /// Connects, offering the server to use one of the `compression`
+    /// algorithms (in order of preference) for the connection.
+    pub async fn with_compression(addr: &SocketAddr, compression: Vec<Compression>) -> Rsq {
         let (out_tx, out_rx) = flume::bounded(10000);
         let (in_tx, in_rx) = flume::bounded(10000);
         let (done_tx, done) = local_sync::oneshot::channel();
 
-        monoio::spawn(Self::connect(*addr, in_tx, out_rx, done_tx));
+        monoio::spawn(Self::connect(*addr, in_tx, out_rx, done_tx, compression));
 
         Rsq {
             tx: out_tx,
@@ -36,7 +56,34 @@ impl Rsq {
         }
     }
 
-    pub async fn connect(addr: SocketAddr, rx: Tx, tx: Rx, done: OneshotTx) -> Result<(), Error> {
+    /// Connects to a server listening on the Unix socket at `path`.
+    pub async fn new_unix(path: &Path) -> Rsq {
+        let (out_tx, out_rx) = flume::bounded(10000);
+        let (in_tx, in_rx) = flume::bounded(10000);
+        let (done_tx, done) = local_sync::oneshot::channel();
+
+        monoio::spawn(Self::connect_unix(
+            path.to_path_buf(),
+            in_tx,
+            out_rx,
+            done_tx,
+            Vec::new(),
+        ));
+
+        Rsq {
+            tx: out_tx,
+            rx: in_rx,
+            done,
+        }
+    }
+
+    pub async fn connect(
+        addr: SocketAddr,
+        rx: Tx,
+        tx: Rx,
+        done: OneshotTx,
+        compression: Vec<Compression>,
+    ) -> Result<(), Error> {
         use crate::messaging::msg::*;
 
         rx.send_async(Arc::new(Msg::new_status(StatusMsg::Connecting)))
@@ -53,8 +100,65 @@ impl Rsq {
         stream.set_nodelay(true)?;
 
         let (stream_in, stream_out) = stream.into_split();
+        Self::run(stream_in, stream_out, rx, tx, done, compression).await
+    }
+
+    /// Like `connect`, for a server listening on the Unix socket at `path`.
+    pub async fn connect_unix(
+        path: PathBuf,
+        rx: Tx,
+        tx: Rx,
+        done: OneshotTx,
+        compression: Vec<Compression>,
+    ) -> Result<(), Error> {
+        use crate::messaging::msg::*;
+
+        rx.send_async(Arc::new(Msg::new_status(StatusMsg::Connecting)))
+            .await?;
+
+        let stream = match UnixStream::connect(&path).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!("connect error: {e}");
+                return Err(e.into());
+            }
+        };
+
+        let (stream_in, stream_out) = stream.into_split();
+        Self::run(stream_in, stream_out, rx, tx, done, compression).await
+    }
+
+    /// Runs a connection until either side closes it.
+    async fn run<R, W>(
+        stream_in: R,
+        stream_out: W,
+        rx: Tx,
+        tx: Rx,
+        done: OneshotTx,
+        compression: Vec<Compression>,
+    ) -> Result<(), Error>
+    where
+        R: AsyncReadRent,
+        W: monoio::io::AsyncWriteRent,
+    {
+        use crate::messaging::msg::*;
+
         let mut msgs_in = FramedRead::new(stream_in, BincodeCodec::<Msg>::new());
-        let mut msgs_out = FramedWrite::new(stream_out, BincodeCodec::<Msg>::new());
+        let negotiated = CompressionHandle::default();
+        let mut msgs_out = FramedWrite::new(
+            stream_out,
+            BincodeCodec::<Msg>::with_compression(
+                negotiated.clone(),
+                compression::DEFAULT_THRESHOLD,
+            ),
+        );
+
+        if !compression.is_empty() {
+            msgs_out
+                .send(Arc::new(Msg::hello(Hello { compression })))
+                .await?;
+            msgs_out.flush().await?;
+        }
 
         rx.send_async(Arc::new(Msg::new_status(
             crate::messaging::msg::StatusMsg::Connected,
@@ -63,16 +167,11 @@ impl Rsq {
 
         monoio::select! {
             _ = async {
-                loop {
-                    match tx.recv_async().await {
-                        Ok(msg) => {
-                            msgs_out.send(msg).await?;
-                            if tx.is_empty() {
-                                msgs_out.flush().await?;
-                            }
-                        },
-                        // The stream has been exhausted.
-                        Err(_) => break,
+                // until the stream has been exhausted
+                while let Ok(msg) = tx.recv_async().await {
+                    msgs_out.send(msg).await?;
+                    if tx.is_empty() {
+                        msgs_out.flush().await?;
                     }
                 }
                 Ok::<(), anyhow::Error>(())
@@ -85,6 +184,9 @@ impl Rsq {
                     // A message was received from the current connection.
                     // pass it on to the application.
                     Some(Ok(msg)) => {
+                        if let Msg::StatusMsg(StatusMsg::Welcome(welcome)) = &*msg {
+                            negotiated.set(welcome.compression);
+                        }
                         rx.send_async(msg).await?;
                     }
                     // An error occurred.
@@ -116,9 +218,81 @@ impl Rsq {
         Ok(())
     }
 
+    /// Sends everything read from `reader` to `channel` as a streamed
+    /// message, in fragments of up to `fragment::DEFAULT_FRAGMENT_SIZE` bytes.
+    ///
+    /// Receivers can turn the fragments back into a stream using
+    /// `fragment::Reassembler`.
+    pub async fn send_stream<R: AsyncReadRent>(
+        &self,
+        sender: PeerId,
+        channel: ChannelId,
+        headers: Headers,
+        mut reader: R,
+    ) -> Result<(), Error> {
+        let mut fragment = Fragment::start();
+        loop {
+            let buf = Vec::with_capacity(fragment::DEFAULT_FRAGMENT_SIZE);
+            let (res, buf) = reader.read(buf).await;
+            if res? == 0 {
+                fragment = fragment.into_last();
+            }
+
+            let msg = ChannelMsg::new(sender.clone(), channel, buf)
+                .with_headers(headers.clone().with_fragment(fragment));
+            self.tx.send_async(Arc::new(Msg::ChannelMsg(msg))).await?;
+
+            if fragment.last {
+                return Ok(());
+            }
+            fragment = fragment.next();
+        }
+    }
+
     pub async fn finish(self) -> Result<(), Error> {
         drop(self.tx);
         self.done.await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messaging::msg::StatusMsg;
+
+    #[monoio::test]
+    async fn connect_unix() {
+        let path = std::env::temp_dir().join(format!("rsq-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = crate::unix::bind(&path).unwrap();
+
+        let rsq = Rsq::new_unix(&path).await;
+        let (stream, _) = listener.accept().await.unwrap();
+        for status in [StatusMsg::Connecting, StatusMsg::Connected] {
+            let msg = rsq.rx.recv_async().await.unwrap();
+            assert_eq!(*msg, Msg::new_status(status));
+        }
+
+        let msg = Msg::new_channel_msg(
+            PeerId::new("client"),
+            ChannelId::default(),
+            b"hello".to_vec(),
+        );
+        rsq.tx.send_async(Arc::new(msg.clone())).await.unwrap();
+        let mut msgs_in = FramedRead::new(stream, BincodeCodec::<Msg>::new());
+        assert_eq!(*msgs_in.next().await.unwrap().unwrap(), msg);
+
+        // the server closing the connection ends it
+        drop(msgs_in);
+        let rx = rsq.rx.clone();
+        rsq.finish().await.unwrap();
+        let statuses: Vec<_> = rx.drain().collect();
+        assert_eq!(
+            statuses.last().map(|msg| &**msg),
+            Some(&Msg::new_status(StatusMsg::Disconnected))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Frame body compression.
+//!
+//! A frame is a big endian `u32` length followed by the bincode encoded
+//! message. If the length has `COMPRESSED` set, the remaining 31 bits are the
+//! length of a compressed body:
+//!
+//! ```text
+//! | algorithm: u8 | uncompressed length: u32 BE | compressed data |
+//! ```
+//!
+//! Peers announce the algorithms they can decode with `ControlMsg::Hello`, and
+//! compressed frames are only sent with an algorithm the receiver announced.
+
+use std::io;
+
+use bincode::{Decode, Encode};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::wire;
+
+/// Length prefix flag marking a compressed frame body.
+pub const COMPRESSED: u32 = 1 << 31;
+
+/// Default size below which frames are sent uncompressed.
+pub const DEFAULT_THRESHOLD: usize = 512;
+
+/// Largest uncompressed body that will be decompressed.
+///
+/// The uncompressed length is sent by the peer, and decompression allocates
+/// it up front, so it has to be checked first.
+pub const MAX_UNCOMPRESSED: usize = wire::MAX_FRAME_LEN;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize, Encode, Decode)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Algorithms this build supports, in order of preference.
+    pub const SUPPORTED: &'static [Compression] = &[Compression::Zstd, Compression::Lz4];
+
+    /// Picks the first algorithm of `offered` that is supported.
+    pub fn negotiate(offered: &[Compression]) -> Option<Compression> {
+        offered
+            .iter()
+            .copied()
+            .find(|algorithm| Self::SUPPORTED.contains(algorithm))
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression algorithm {id}"),
+            )),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+        }
+    }
+
+    fn decompress(self, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+        let res = match self {
+            Compression::Zstd => zstd::bulk::decompress(data, len)?,
+            Compression::Lz4 => lz4_flex::block::decompress(data, len)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+        if res.len() != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed length mismatch",
+            ));
+        }
+        Ok(res)
+    }
+}
+
+/// Returns the body length of a frame given its raw length prefix, and
+/// whether the body is compressed.
+pub fn frame_len(prefix: u32) -> (usize, bool) {
+    ((prefix & !COMPRESSED) as usize, prefix & COMPRESSED != 0)
+}
+
+/// Returns `true` if `frame` (including its length prefix) is compressed.
+pub fn is_compressed(frame: &[u8]) -> bool {
+    frame.first().is_some_and(|b| b & 0x80 != 0)
+}
+
+/// Compresses a frame if it is at least `threshold` bytes and compression
+/// actually makes it smaller.
+pub fn compress_frame(frame: Bytes, algorithm: Compression, threshold: usize) -> Bytes {
+    if frame.len() < threshold || is_compressed(&frame) {
+        return frame;
+    }
+
+    let body = &frame[4..];
+    let compressed = match algorithm.compress(body) {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            tracing::warn!("compression failed: {e}");
+            return frame;
+        }
+    };
+
+    let len = 1 + 4 + compressed.len();
+    if len >= body.len() {
+        return frame;
+    }
+
+    let mut dst = BytesMut::with_capacity(4 + len);
+    dst.put_u32(len as u32 | COMPRESSED);
+    dst.put_u8(algorithm.id());
+    dst.put_u32(body.len() as u32);
+    dst.extend_from_slice(&compressed);
+    dst.freeze()
+}
+
+/// Decompresses the body of a compressed frame (without length prefix),
+/// returning the plain body.
+pub fn decompress_body(body: &[u8]) -> io::Result<Vec<u8>> {
+    if body.len() < 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "short compressed frame",
+        ));
+    }
+
+    let algorithm = Compression::from_id(body[0])?;
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if len > MAX_UNCOMPRESSED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compressed frame too large",
+        ));
+    }
+
+    algorithm.decompress(&body[5..], len)
+}
+
+/// Turns a compressed frame into a plain one. Plain frames are returned as is.
+pub fn decompress_frame(frame: BytesMut) -> io::Result<BytesMut> {
+    if !is_compressed(&frame) {
+        return Ok(frame);
+    }
+
+    let body = decompress_body(&frame[4..])?;
+    let mut dst = BytesMut::with_capacity(4 + body.len());
+    dst.put_u32(body.len() as u32);
+    dst.extend_from_slice(&body);
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plain_frame(body: &[u8]) -> Bytes {
+        let mut frame = BytesMut::new();
+        frame.put_u32(body.len() as u32);
+        frame.extend_from_slice(body);
+        frame.freeze()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let frame = plain_frame(&b"compressible ".repeat(100));
+        for algorithm in Compression::SUPPORTED {
+            let compressed = compress_frame(frame.clone(), *algorithm, DEFAULT_THRESHOLD);
+            assert!(is_compressed(&compressed));
+            assert!(compressed.len() < frame.len());
+            let prefix =
+                u32::from_be_bytes([compressed[0], compressed[1], compressed[2], compressed[3]]);
+            assert_eq!(frame_len(prefix), (compressed.len() - 4, true));
+
+            // compressing again leaves it alone
+            let again = compress_frame(compressed.clone(), *algorithm, 0);
+            assert_eq!(again, compressed);
+
+            let decompressed = decompress_frame(BytesMut::from(&compressed[..])).unwrap();
+            assert_eq!(&decompressed[..], &frame[..]);
+        }
+    }
+
+    #[test]
+    fn uncompressed() {
+        // below the threshold
+        let frame = plain_frame(&b"compressible ".repeat(10));
+        assert_eq!(
+            compress_frame(frame.clone(), Compression::Zstd, 1024),
+            frame
+        );
+
+        // not getting any smaller
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let body: Vec<u8> = (0..1024)
+            .map(|_| {
+                // xorshift
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let frame = plain_frame(&body);
+        assert_eq!(compress_frame(frame.clone(), Compression::Lz4, 0), frame);
+        assert!(!is_compressed(&frame));
+        assert_eq!(decompress_frame(BytesMut::from(&frame[..])).unwrap(), frame);
+    }
+
+    #[test]
+    fn negotiate() {
+        use Compression::*;
+        assert_eq!(Compression::negotiate(&[Lz4, Zstd]), Some(Lz4));
+        assert_eq!(Compression::negotiate(&[Zstd]), Some(Zstd));
+        assert_eq!(Compression::negotiate(&[]), None);
+        assert!(Compression::from_id(0).is_err());
+        for algorithm in Compression::SUPPORTED {
+            assert_eq!(Compression::from_id(algorithm.id()).unwrap(), *algorithm);
+        }
+    }
+
+    #[test]
+    fn oversized_length() {
+        let mut body = vec![Compression::Zstd.id()];
+        body.extend_from_slice(&(MAX_UNCOMPRESSED as u32 + 1).to_be_bytes());
+        body.extend_from_slice(&[0; 8]);
+        let err = decompress_body(&body).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut frame = BytesMut::new();
+        frame.put_u32(body.len() as u32 | COMPRESSED);
+        frame.extend_from_slice(&body);
+        assert!(decompress_frame(frame).is_err());
+    }
+}
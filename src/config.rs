@@ -0,0 +1,244 @@
+//! Server configuration file.
+//!
+//! ```toml
+//! listen = ["0.0.0.0:6142", "unix:/run/rsq.sock,uid=1000"]
+//! data-dir = "/var/lib/rsq"
+//!
+//! [limits]
+//! max-connections = 10000
+//! io-uring-entries = 32768
+//! compression-threshold = 1024
+//!
+//! [logging]
+//! filter = "rsq=debug"
+//!
+//! [channels]
+//! linger = 10
+//! history = 100
+//!
+//! [[retention]]
+//! channels = "config.*"
+//! retain = true
+//! persistent = true
+//!
+//! [[acl]]
+//! peers = "unix:1000:*"
+//! publish = ["clipboard"]
+//! subscribe = ["clipboard", "status.*"]
+//! ```
+//!
+//! Listeners use the syntax of `--listen` (see `listener`). Retention rules
+//! set the options of newly created channels, the first rule matching a
+//! channel's name applies. See `messaging::acl` for ACL rules.
+//!
+//! Listeners, the data directory and the number of io_uring entries are
+//! only read at startup. Everything else can be reloaded. Settings also
+//! given on the command line keep their command line value. Listeners of
+//! both are opened.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::listener::{self, ListenerConfig};
+use crate::messaging::acl::AclRule;
+use crate::messaging::channel::ChannelOptions;
+
+#[derive(PartialEq, Eq, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub listen: Vec<String>,
+    pub data_dir: Option<PathBuf>,
+    pub limits: Limits,
+    pub logging: Logging,
+    pub channels: Channels,
+    pub retention: Vec<Retention>,
+    pub acl: Vec<AclRule>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Limits {
+    /// Connections open at the same time, capped by the file descriptor
+    /// limit.
+    pub max_connections: Option<u64>,
+    pub io_uring_entries: Option<u32>,
+    pub compression_threshold: Option<usize>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// Log filter, in `RUST_LOG` syntax.
+    pub filter: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Channels {
+    /// Seconds to keep empty channels around.
+    pub linger: Option<u64>,
+    /// Number of messages channels keep for resuming subscribers.
+    pub history: Option<usize>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    /// Pattern of the channel names the rule applies to.
+    pub channels: String,
+    #[serde(default)]
+    pub persistent: bool,
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default)]
+    pub compact: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("listener {0}: {1}")]
+    Listener(String, listener::ParseError),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl Config {
+    /// Reads and validates the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Returns the listeners.
+    pub fn listeners(&self) -> Result<Vec<ListenerConfig>, ConfigError> {
+        self.listen
+            .iter()
+            .map(|listen| {
+                listen
+                    .parse()
+                    .map_err(|e| ConfigError::Listener(listen.clone(), e))
+            })
+            .collect()
+    }
+
+    /// Returns the options of new channels, by channel name pattern.
+    pub fn channel_defaults(&self) -> Vec<(String, ChannelOptions)> {
+        self.retention
+            .iter()
+            .map(|rule| {
+                let options = ChannelOptions {
+                    persistent: rule.persistent,
+                    retain: rule.retain,
+                    compact: rule.compact,
+                    ..Default::default()
+                };
+                (rule.channels.clone(), options)
+            })
+            .collect()
+    }
+
+    /// Returns the settings that differ from `other` and are only read at
+    /// startup.
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.listen != other.listen {
+            changed.push("listen");
+        }
+        if self.data_dir != other.data_dir {
+            changed.push("data-dir");
+        }
+        if self.limits.io_uring_entries != other.limits.io_uring_entries {
+            changed.push("limits.io-uring-entries");
+        }
+        changed
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.listeners()?;
+        if self.retention.iter().any(|rule| rule.channels.is_empty()) {
+            return Err(ConfigError::Invalid(
+                "retention rule without channels".to_string(),
+            ));
+        }
+        if self.acl.iter().any(|rule| rule.peers.is_empty()) {
+            return Err(ConfigError::Invalid("ACL rule without peers".to_string()));
+        }
+        if self.limits.io_uring_entries == Some(0) {
+            return Err(ConfigError::Invalid(
+                "limits.io-uring-entries can't be 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config: Config = r#"
+            listen = ["0.0.0.0:6142", "unix:/run/rsq.sock,uid=1000"]
+
+            [limits]
+            max-connections = 100
+
+            [channels]
+            linger = 5
+            history = 50
+
+            [[retention]]
+            channels = "config.*"
+            retain = true
+
+            [[acl]]
+            peers = "unix:1000:*"
+            publish = ["clipboard"]
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.listeners().unwrap().len(), 2);
+        assert_eq!(config.limits.max_connections, Some(100));
+        assert_eq!(config.channels.linger, Some(5));
+        assert_eq!(config.channels.history, Some(50));
+        let defaults = config.channel_defaults();
+        assert_eq!(defaults[0].0, "config.*");
+        assert!(defaults[0].1.retain);
+        assert_eq!(config.acl[0].publish, vec!["clipboard"]);
+        assert!(config.acl[0].subscribe.is_empty());
+
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("listen = [\"tls:0.0.0.0:6143\"]".parse::<Config>().is_err());
+        assert!("[limits]\nmax-connection = 1".parse::<Config>().is_err());
+        assert!("[[acl]]\npeers = \"\"".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn restart_required() {
+        let config: Config = "listen = [\"0.0.0.0:6142\"]".parse().unwrap();
+        let mut other = config.clone();
+        other.channels.linger = Some(1);
+        assert!(config.restart_required(&other).is_empty());
+
+        other.listen.push("unix:/run/rsq.sock".to_string());
+        assert_eq!(config.restart_required(&other), vec!["listen"]);
+    }
+}
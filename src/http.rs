@@ -0,0 +1,947 @@
+//! Minimal server side HTTP/1.1 support.
+//!
+//! Only what the HTTP gateway needs: requests with an optional
+//! `Content-Length` body (no chunked uploads), keep-alive, simple responses,
+//! server-sent event streams, and verification of webhook signatures.
+//!
+//! The gateway itself (`listener()`) publishes request bodies to channels,
+//! and streams or long polls channel messages as events.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use monoio::io::{AsyncReadRent, OwnedReadHalf, OwnedWriteHalf, Splitable};
+use monoio::net::{TcpListener, TcpStream};
+use sha1::Sha1;
+use sha2::Sha256;
+
+use crate::listener::Kind;
+use crate::messaging::errors::TxError;
+use crate::messaging::frame::Frame;
+use crate::messaging::msg::{ChannelMsg, Msg};
+use crate::messaging::peer::{Peer, PeerId, PeerRx, PeerTx};
+use crate::messaging::queue::peer_queue;
+use crate::metrics;
+use crate::monoio_bincode::Framed;
+use crate::msg_stream::{FrameWriter, ReadBuffer};
+use crate::server::{connection_peer_id, shutdown_requested, Endpoint, Shared};
+use crate::wire;
+
+/// Largest request head (request line and headers) accepted.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Largest request body accepted, so it fits a maximum size frame.
+pub const MAX_BODY_LEN: usize = wire::MAX_FRAME_LEN - 1024;
+
+/// An HTTP request.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    version: String,
+    headers: Vec<(String, String)>,
+    pub body: BytesMut,
+}
+
+impl Request {
+    /// Parses a request head from the start of `buf`.
+    ///
+    /// Returns the request without body, the length of the head and the
+    /// length of the body, or `None` if `buf` doesn't hold a complete head
+    /// yet.
+    pub fn parse(buf: &[u8]) -> io::Result<Option<(Request, usize, usize)>> {
+        let len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if buf.len() > MAX_HEAD_LEN => {
+                return Err(invalid_data("request head too large"));
+            }
+            None => return Ok(None),
+        };
+
+        let head = std::str::from_utf8(&buf[..len]).map_err(invalid_data)?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (method, path, version) = match (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) {
+            (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => {
+                (method.to_string(), path.to_string(), version.to_string())
+            }
+            _ => return Err(invalid_data("invalid request line")),
+        };
+
+        let headers: Vec<_> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        let request = Request {
+            method,
+            path,
+            version,
+            headers,
+            body: BytesMut::new(),
+        };
+
+        if request.header("transfer-encoding").is_some() {
+            return Err(invalid_data("chunked requests are not supported"));
+        }
+        let body_len = match request.header("content-length") {
+            Some(value) => value.parse().map_err(invalid_data)?,
+            None => 0,
+        };
+        if body_len > MAX_BODY_LEN {
+            return Err(invalid_data("request body too large"));
+        }
+
+        Ok(Some((request, len, body_len)))
+    }
+
+    /// Returns the value of header `name`, compared case insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the token of an `Authorization: Bearer` header.
+    pub fn bearer_token(&self) -> Option<&str> {
+        let (scheme, token) = self.header("authorization")?.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    }
+
+    /// Returns the webhook signature sent with the request, if any.
+    ///
+    /// Both GitHub's `X-Hub-Signature-256` and the older SHA-1 based
+    /// `X-Hub-Signature` are understood.
+    pub fn signature(&self) -> Option<&str> {
+        self.header("x-hub-signature-256")
+            .or_else(|| self.header("x-hub-signature"))
+    }
+
+    /// Returns `true` if the connection should be kept open after responding.
+    pub fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version != "HTTP/1.0",
+        }
+    }
+}
+
+/// Reads requests sent by a client.
+pub struct RequestReader<IO> {
+    reader: ReadBuffer<IO>,
+}
+
+impl<IO: AsyncReadRent> RequestReader<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            reader: ReadBuffer::new(io),
+        }
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> IO {
+        self.reader.into_inner()
+    }
+
+    /// Returns the next request, including its body.
+    pub async fn next(&mut self) -> Option<io::Result<Request>> {
+        loop {
+            let (mut request, head_len, body_len) = match Request::parse(self.reader.buffered()) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => {
+                    let buffered = self.reader.buffered().len();
+                    match self.reader.fill(buffered + 1).await {
+                        Ok(()) => continue,
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && buffered == 0 => {
+                            return None
+                        }
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            };
+
+            if let Err(e) = self.reader.fill(head_len + body_len).await {
+                return Some(Err(e));
+            }
+            request.body = self
+                .reader
+                .split_to(head_len + body_len)
+                .split_off(head_len);
+
+            return Some(Ok(request));
+        }
+    }
+}
+
+/// Returns a response with status `status` and `body`.
+pub fn response(status: u16, content_type: &str, body: &[u8]) -> Bytes {
+    let mut response = BytesMut::from(
+        format!(
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\r\n",
+            status,
+            reason(status),
+            content_type,
+            body.len()
+        )
+        .as_bytes(),
+    );
+    response.extend_from_slice(body);
+    response.freeze()
+}
+
+/// Returns a plain text response.
+pub fn text_response(status: u16, text: &str) -> Bytes {
+    response(status, "text/plain", text.as_bytes())
+}
+
+/// Returns the head of a server-sent event stream response.
+///
+/// The stream lasts until the connection is closed.
+pub fn event_stream() -> Bytes {
+    Bytes::from_static(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )
+}
+
+/// Returns a server-sent event.
+///
+/// Every line of `data` becomes a `data` field.
+pub fn event(id: Option<&str>, event: Option<&str>, data: &str) -> Bytes {
+    let mut buf = String::with_capacity(data.len() + 32);
+    if let Some(id) = id {
+        buf.push_str(&format!("id: {id}\n"));
+    }
+    if let Some(event) = event {
+        buf.push_str(&format!("event: {event}\n"));
+    }
+    for line in data.split('\n') {
+        buf.push_str("data: ");
+        buf.push_str(line.strip_suffix('\r').unwrap_or(line));
+        buf.push('\n');
+    }
+    buf.push('\n');
+    Bytes::from(buf)
+}
+
+/// Returns the id of the event for message `seq` of a channel created at
+/// `epoch` (in milliseconds since the Unix epoch).
+///
+/// Sequence numbers start over when a channel is removed and created again,
+/// the epoch tells the two apart.
+pub fn event_id(epoch: u64, seq: u64) -> String {
+    format!("{epoch}-{seq}")
+}
+
+/// Parses an event id returned by `event_id()`.
+pub fn parse_event_id(id: &str) -> Option<(u64, u64)> {
+    let (epoch, seq) = id.trim().split_once('-')?;
+    Some((epoch.parse().ok()?, seq.parse().ok()?))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Verifies a webhook signature of the form `sha256=<hex>` (or `sha1=<hex>`)
+/// over `body`, computed with the shared `secret`.
+pub fn verify_signature(secret: &[u8], signature: &str, body: &[u8]) -> bool {
+    let (algorithm, digest) = match signature.split_once('=') {
+        Some(split) => split,
+        None => return false,
+    };
+    let digest = match hex::decode(digest.trim()) {
+        Ok(digest) => digest,
+        Err(_) => return false,
+    };
+
+    match algorithm {
+        "sha256" => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+            mac.update(body);
+            mac.verify_slice(&digest).is_ok()
+        }
+        "sha1" => {
+            let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+            mac.update(body);
+            mac.verify_slice(&digest).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Signs `body` with `secret`, in the format `verify_signature()` expects
+/// (`sha256=<hex>`).
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Parses the status line of a response from the start of `buf`.
+///
+/// Returns the status code, or `None` if `buf` doesn't hold a complete
+/// status line yet.
+pub fn parse_status(buf: &[u8]) -> io::Result<Option<u16>> {
+    let len = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None if buf.len() > MAX_HEAD_LEN => {
+            return Err(invalid_data("response head too large"));
+        }
+        None => return Ok(None),
+    };
+
+    let line = std::str::from_utf8(&buf[..len]).map_err(invalid_data)?;
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+            status.parse().map(Some).map_err(invalid_data)
+        }
+        _ => Err(invalid_data("invalid status line")),
+    }
+}
+
+/// Compares two secrets in constant time (for secrets of equal length).
+pub fn secret_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Returns the percent decoded value of query parameter `name` of `path`.
+pub fn query_param(path: &str, name: &str) -> Option<String> {
+    let (_, query) = path.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(param, _)| *param == name)
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+/// Decodes `%xx` escapes in a path segment.
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = segment.bytes();
+    let mut decoded = Vec::with_capacity(segment.len());
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Escapes `%` and control characters as `%xx`, so that `value` can be sent
+/// as header value. `percent_decode()` reverses it.
+pub fn percent_encode_header(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '%' || c.is_ascii_control() {
+            encoded.push_str(&format!("%{:02X}", c as u8));
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Settings of the HTTP gateway.
+pub struct Gateway {
+    /// Bearer token clients have to send.
+    pub token: Option<String>,
+
+    /// If set, requests must be signed with this secret.
+    pub webhook_secret: Option<String>,
+}
+
+/// `Peer` handle for HTTP clients subscribed to a channel's events.
+struct HttpPeer {
+    tx: PeerTx,
+
+    peer_id: PeerId,
+}
+
+impl Peer for HttpPeer {
+    fn get_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        &self.tx
+    }
+}
+
+/// Accepts HTTP gateway connections.
+pub async fn listener(
+    state: Rc<RefCell<Shared>>,
+    listener: TcpListener,
+    endpoint: Rc<Endpoint>,
+    gateway: Rc<Gateway>,
+) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("HTTP accept error: {e}");
+                continue;
+            }
+        };
+        if !endpoint.config.allows(addr.ip()) {
+            tracing::info!("rejecting connection from {}", addr);
+            continue;
+        }
+        let _ = stream.set_nodelay(true);
+
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+        let gateway = Rc::clone(&gateway);
+        monoio::spawn(async move {
+            endpoint.opened();
+            if let Err(e) = process_http(state, gateway, stream, addr).await {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+            endpoint.closed();
+        });
+    }
+}
+
+/// Process an individual HTTP client
+async fn process_http(
+    state: Rc<RefCell<Shared>>,
+    gateway: Rc<Gateway>,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let (stream_in, stream_out) = stream.into_split();
+    let mut requests = RequestReader::new(stream_in);
+    let mut writer = FrameWriter::new(stream_out);
+    let peer_id = connection_peer_id(Kind::Http, addr);
+
+    while let Some(request) = requests.next().await {
+        let request = match request {
+            Ok(request) => request,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                metrics::DECODE_ERRORS.inc();
+                writer.push(text_response(400, &format!("{e}\n")));
+                writer.flush().await?;
+                return Err(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let response = match route_http(&gateway, &request) {
+            Ok(HttpRoute::Publish(channel)) => publish_http(&state, &peer_id, channel, &request),
+            Ok(HttpRoute::Shutdown) => {
+                tracing::info!("shutdown requested by {}", addr);
+                state.borrow().shutdown.store(true, Ordering::Relaxed);
+                text_response(202, "shutting down\n")
+            }
+            Ok(HttpRoute::Events(channel)) => {
+                let last_event_id = request.header("last-event-id").map(str::to_string);
+                return serve_events(state, peer_id, channel, last_event_id, requests, writer)
+                    .await;
+            }
+            Ok(HttpRoute::Poll(channel)) => {
+                let after = query_param(&request.path, "after");
+                let timeout = query_param(&request.path, "timeout")
+                    .and_then(|secs| secs.parse().ok())
+                    .map_or(POLL_TIMEOUT, Duration::from_secs)
+                    .min(MAX_POLL_TIMEOUT);
+                poll_events(&state, &peer_id, channel, after, timeout).await
+            }
+            Err(response) => response,
+        };
+
+        writer.push(response);
+        writer.flush().await?;
+
+        if !request.keep_alive() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Requests understood by the HTTP gateway.
+enum HttpRoute {
+    /// `POST /channels/<name>` publishes the request body to a channel.
+    Publish(String),
+    /// `GET /channels/<name>/events` subscribes to a channel as a stream of
+    /// server-sent events.
+    Events(String),
+    /// `GET /channels/<name>/poll?after=<event id>&timeout=<seconds>` waits
+    /// for messages of a channel and returns them as JSON.
+    Poll(String),
+    /// `POST /admin/shutdown` shuts the server down gracefully, by setting
+    /// `Shared::shutdown`. Only available on gateways with a token.
+    Shutdown,
+}
+
+/// Routes and authenticates an HTTP gateway request.
+///
+/// Returns the response to send right away for invalid requests.
+fn route_http(gateway: &Gateway, request: &Request) -> Result<HttpRoute, Bytes> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    if path == "/admin/shutdown" {
+        // without a token, anyone could shut the server down
+        if gateway.token.is_none() {
+            return Err(text_response(403, "admin commands need a token\n"));
+        }
+        if request.method != "POST" {
+            return Err(text_response(405, "method not allowed\n"));
+        }
+        authorize(gateway.token.as_deref(), request)?;
+        return Ok(HttpRoute::Shutdown);
+    }
+
+    let rest = match path.strip_prefix("/channels/") {
+        Some(rest) => rest,
+        None => return Err(text_response(404, "not found\n")),
+    };
+    let (name, events, poll) = if let Some(name) = rest.strip_suffix("/events") {
+        (name, true, false)
+    } else if let Some(name) = rest.strip_suffix("/poll") {
+        (name, false, true)
+    } else {
+        (rest, false, false)
+    };
+    let channel = match percent_decode(name) {
+        Some(name) if !name.is_empty() && !name.contains('/') => name,
+        _ => return Err(text_response(404, "not found\n")),
+    };
+
+    let expected = if events || poll { "GET" } else { "POST" };
+    if request.method != expected {
+        return Err(text_response(405, "method not allowed\n"));
+    }
+
+    authorize(gateway.token.as_deref(), request)?;
+
+    if events {
+        return Ok(HttpRoute::Events(channel));
+    }
+    if poll {
+        return Ok(HttpRoute::Poll(channel));
+    }
+
+    if let Some(secret) = &gateway.webhook_secret {
+        match request.signature() {
+            Some(signature) if verify_signature(secret.as_bytes(), signature, &request.body) => {}
+            _ => return Err(text_response(403, "invalid signature\n")),
+        }
+    }
+
+    Ok(HttpRoute::Publish(channel))
+}
+
+/// Checks the bearer token of a request, if one is required.
+pub fn authorize(token: Option<&str>, request: &Request) -> Result<(), Bytes> {
+    if let Some(token) = token {
+        match request.bearer_token() {
+            Some(sent) if secret_eq(sent.as_bytes(), token.as_bytes()) => {}
+            _ => return Err(text_response(401, "invalid token\n")),
+        }
+    }
+    Ok(())
+}
+
+/// Publishes the body of an HTTP request to `channel`.
+///
+/// Responds with the number of recipients.
+fn publish_http(
+    state: &Rc<RefCell<Shared>>,
+    peer_id: &PeerId,
+    channel: String,
+    request: &Request,
+) -> Bytes {
+    let mut state = state.borrow_mut();
+    let channel_id = state.router.channel_get_or_add(channel);
+
+    let mut msg = ChannelMsg::new(peer_id.clone(), channel_id, request.body.to_vec());
+    msg.headers_mut().content_type = request.header("content-type").map(str::to_string);
+    let frame = Frame::new(Msg::ChannelMsg(msg).framed());
+
+    match state.router.forward(frame, channel_id, peer_id) {
+        Ok(recipients) => {
+            let body = serde_json::json!({ "recipients": recipients });
+            response(200, "application/json", body.to_string().as_bytes())
+        }
+        Err(e @ TxError::NotPermitted) => text_response(403, &format!("{e}\n")),
+        Err(e @ TxError::ShuttingDown) => text_response(503, &format!("{e}\n")),
+        Err(e) => text_response(500, &format!("{e}\n")),
+    }
+}
+
+/// Subscribes an HTTP client to `channel`, resuming after event
+/// `last_event_id`. Subscribing and unsubscribing of `quiet` clients isn't
+/// announced to the channel.
+///
+/// Returns the subscribed peer, its queue and the channel's epoch, or the
+/// response to send if the client may not subscribe.
+fn http_subscribe(
+    state: &Rc<RefCell<Shared>>,
+    peer_id: &PeerId,
+    channel: String,
+    last_event_id: Option<&str>,
+    quiet: bool,
+) -> Result<(HttpPeer, PeerRx, u64), Bytes> {
+    let (tx, rx) = peer_queue();
+    let peer = HttpPeer {
+        tx,
+        peer_id: peer_id.clone(),
+    };
+
+    let mut state = state.borrow_mut();
+    if quiet {
+        state.router.peer_add_quiet(&peer);
+    } else {
+        state.router.peer_add(&peer);
+    }
+    let channel_id = state.router.channel_get_or_add(channel);
+    let epoch = state
+        .router
+        .channel_info(channel_id)
+        .map_or(0, |info| info.created);
+    // ids of another incarnation of the channel don't tell what was seen,
+    // so everything still kept is sent
+    let from = last_event_id.map(|id| match parse_event_id(id) {
+        Some((id_epoch, seq)) if id_epoch == epoch => seq + 1,
+        _ => 0,
+    });
+    match state.router.attach_from(channel_id, &peer, from) {
+        Ok(()) => Ok((peer, rx, epoch)),
+        Err(e) => {
+            state.router.peer_remove(peer.get_id());
+            Err(text_response(403, &format!("{e}\n")))
+        }
+    }
+}
+
+/// Streams the messages of `channel` to an HTTP client as server-sent
+/// events, until the client disconnects.
+///
+/// Event ids are `<epoch>-<seq>`, the creation time of the channel in
+/// milliseconds and the message's sequence number within the channel. A
+/// client resuming with `Last-Event-ID` gets the messages of the channel
+/// history, log and retained message it hasn't seen yet. Channels keep no
+/// history unless configured to (`--channel-history`), and it is only kept
+/// in memory, so messages published while a client is reconnecting may be
+/// missed.
+async fn serve_events(
+    state: Rc<RefCell<Shared>>,
+    peer_id: PeerId,
+    channel: String,
+    last_event_id: Option<String>,
+    mut requests: RequestReader<OwnedReadHalf<TcpStream>>,
+    mut writer: FrameWriter<OwnedWriteHalf<TcpStream>>,
+) -> Result<(), Box<dyn Error>> {
+    let (peer, rx, epoch) =
+        match http_subscribe(&state, &peer_id, channel, last_event_id.as_deref(), false) {
+            Ok(subscribed) => subscribed,
+            Err(response) => {
+                writer.push(response);
+                writer.flush().await?;
+                return Ok(());
+            }
+        };
+
+    writer.push(event_stream());
+    writer.flush().await?;
+
+    // nothing more is expected from the client, reading only notices it
+    // closing the connection
+    let from_client_handle =
+        monoio::spawn(async move { while let Some(Ok(_)) = requests.next().await {} });
+    let to_client_handle = monoio::spawn(events_to_client(rx, writer, epoch));
+
+    monoio::select!(
+        _ = from_client_handle => {},
+        e = to_client_handle => {
+            if let Err(e) = e {
+                tracing::info!("{:?}: {e}", peer.get_id());
+            }
+        }
+    );
+
+    state.borrow_mut().router.peer_remove(peer.get_id());
+
+    Ok(())
+}
+
+/// Interval of comments sent on idle event streams, so dead connections are
+/// noticed.
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+async fn events_to_client(
+    mut rx: PeerRx,
+    mut writer: FrameWriter<OwnedWriteHalf<TcpStream>>,
+    epoch: u64,
+) -> Result<(), anyhow::Error> {
+    loop {
+        monoio::select! {
+            frame = rx.recv_async() => {
+                let mut next = Some(frame?);
+                while let Some(frame) = next.take() {
+                    if let Some((id, kind, data)) = channel_event(&frame, epoch) {
+                        writer.push(event(id.as_deref(), kind, &data));
+                    }
+                    if !writer.is_full() {
+                        next = rx.try_recv();
+                    }
+                }
+            }
+            _ = monoio::time::sleep(EVENT_KEEPALIVE) => {
+                writer.push(Bytes::from_static(b": keep-alive\n\n"));
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+/// Waits up to `timeout` for messages of `channel` published after event
+/// `after`, and responds with the messages as JSON:
+///
+/// `{"events": [{"id": "<id>", "data": "<text>"}], "last_event_id": "<id>"}`
+///
+/// Event ids and resuming work as for `serve_events()`, binary data is sent
+/// base64 encoded with `"encoding": "base64"`. Clients pass the
+/// `last_event_id` of a response as `after` of the next poll. Between polls
+/// the client isn't subscribed, so it only gets what the channel history,
+/// log and retained message still hold. Polling clients don't show up as
+/// channel members.
+async fn poll_events(
+    state: &Rc<RefCell<Shared>>,
+    peer_id: &PeerId,
+    channel: String,
+    after: Option<String>,
+    timeout: Duration,
+) -> Bytes {
+    let (peer, mut rx, epoch) =
+        match http_subscribe(state, peer_id, channel, after.as_deref(), true) {
+            Ok(subscribed) => subscribed,
+            Err(response) => return response,
+        };
+
+    // status messages, e.g. about other subscribers, don't end the poll
+    let deadline = Instant::now() + timeout;
+    let mut events = Vec::new();
+    loop {
+        while let Some(frame) = rx.try_recv() {
+            events.extend(channel_event(&frame, epoch));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !events.is_empty() || remaining.is_zero() {
+            break;
+        }
+        match monoio::time::timeout(remaining, rx.recv_async()).await {
+            Ok(Ok(frame)) => events.extend(channel_event(&frame, epoch)),
+            _ => break,
+        }
+    }
+    state.borrow_mut().router.peer_remove(peer.get_id());
+
+    let mut last_event_id = after;
+    let events: Vec<_> = events
+        .into_iter()
+        .map(|(id, event, data)| {
+            let mut json = serde_json::json!({ "id": id, "data": data });
+            if let Some(encoding) = event {
+                json["encoding"] = encoding.into();
+            }
+            if id.is_some() {
+                last_event_id = id;
+            }
+            json
+        })
+        .collect();
+
+    let body = serde_json::json!({ "events": events, "last_event_id": last_event_id });
+    response(200, "application/json", body.to_string().as_bytes())
+}
+
+/// Time a long poll waits for messages by default.
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest time a long poll waits for messages.
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Turns a channel message frame into the id, type and data of a
+/// server-sent event.
+///
+/// Content that isn't UTF-8 is sent base64 encoded, as `base64` event.
+/// Status messages and fragments of streamed messages are skipped.
+fn channel_event(
+    frame: &Frame,
+    epoch: u64,
+) -> Option<(Option<String>, Option<&'static str>, String)> {
+    if frame.is_expired() {
+        metrics::DROPPED.inc();
+        return None;
+    }
+    let msg = match Msg::decode_frame(&frame.data) {
+        Ok(Msg::ChannelMsg(msg)) if msg.headers().fragment.is_none() => msg,
+        _ => return None,
+    };
+
+    let id = frame.seq.map(|seq| event_id(epoch, seq));
+    Some(match std::str::from_utf8(msg.content()) {
+        Ok(text) => (id, None, text.to_string()),
+        Err(_) => {
+            let data = base64::engine::general_purpose::STANDARD.encode(msg.content());
+            (id, Some("base64"), data)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request() {
+        let request = b"POST /channels/builds HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Authorization: Bearer s3cret\r\n\
+            Content-Length: 5\r\n\r\nhello";
+
+        assert!(Request::parse(&request[..20]).unwrap().is_none());
+
+        let (parsed, head_len, body_len) = Request::parse(request).unwrap().unwrap();
+        assert_eq!(head_len + body_len, request.len());
+        assert_eq!(parsed.method, "POST");
+        assert_eq!(parsed.path, "/channels/builds");
+        assert_eq!(parsed.bearer_token(), Some("s3cret"));
+        assert!(parsed.keep_alive());
+
+        assert!(Request::parse(b"POST /\r\n\r\n").is_err());
+        assert!(Request::parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+    }
+
+    #[monoio::test]
+    async fn reader() {
+        let data = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nfoo\
+            GET /b HTTP/1.0\r\n\r\n";
+
+        let mut reader = RequestReader::new(&data[..]);
+        let request = reader.next().await.unwrap().unwrap();
+        assert_eq!(request.path, "/a");
+        assert_eq!(&request.body[..], b"foo");
+        let request = reader.next().await.unwrap().unwrap();
+        assert_eq!(request.path, "/b");
+        assert!(request.body.is_empty());
+        assert!(!request.keep_alive());
+        assert!(reader.next().await.is_none());
+    }
+
+    #[test]
+    fn signature() {
+        // example from GitHub's webhook documentation
+        let secret = b"It's a Secret to Everybody";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(secret, signature, b"Hello, World!"));
+        assert!(!verify_signature(secret, signature, b"Hello, World?"));
+        assert!(!verify_signature(b"wrong", signature, b"Hello, World!"));
+        assert!(!verify_signature(secret, "md5=00", b"Hello, World!"));
+        assert_eq!(sign(secret, b"Hello, World!"), signature);
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            parse_status(b"HTTP/1.1 204 No Content\r\n").unwrap(),
+            Some(204)
+        );
+        assert_eq!(parse_status(b"HTTP/1.1 200").unwrap(), None);
+        assert!(parse_status(b"SSH-2.0\r\n").is_err());
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(
+            &event(Some("1-3"), None, "hello")[..],
+            b"id: 1-3\ndata: hello\n\n"
+        );
+        assert_eq!(
+            &event(None, Some("greeting"), "a\r\nb")[..],
+            b"event: greeting\ndata: a\ndata: b\n\n"
+        );
+    }
+
+    #[test]
+    fn event_ids() {
+        let id = event_id(1760000000000, 3);
+        assert_eq!(parse_event_id(&id), Some((1760000000000, 3)));
+        assert_eq!(parse_event_id("3"), None);
+        assert_eq!(parse_event_id("a-3"), None);
+    }
+
+    #[test]
+    fn query() {
+        let path = "/channels/news/poll?after=1-3&timeout=10&x=a%20b";
+        assert_eq!(query_param(path, "after").as_deref(), Some("1-3"));
+        assert_eq!(query_param(path, "x").as_deref(), Some("a b"));
+        assert_eq!(query_param(path, "before"), None);
+        assert_eq!(query_param("/channels/news/poll", "after"), None);
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(percent_decode("a%2Fb").as_deref(), Some("a/b"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        assert_eq!(percent_decode("bad%2"), None);
+
+        let encoded = percent_encode_header("50% off\r\nX-Evil: 1");
+        assert_eq!(encoded, "50%25 off%0D%0AX-Evil: 1");
+        assert_eq!(
+            percent_decode(&encoded).as_deref(),
+            Some("50% off\r\nX-Evil: 1")
+        );
+    }
+}
@@ -1,4 +1,16 @@
 pub mod client;
+pub mod compression;
+pub mod config;
+pub mod http;
+pub mod listener;
 pub mod messaging;
+pub mod metrics;
 pub mod monoio_bincode;
+pub mod mqtt;
 pub mod msg_stream;
+pub mod resp;
+pub mod server;
+pub mod unix;
+pub mod webhook;
+pub mod websocket;
+pub mod wire;
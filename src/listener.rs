@@ -0,0 +1,266 @@
+//! Listener settings.
+//!
+//! A listener is given as `[<kind>:]<address>[,<setting>...]`, e.g.
+//! `unix:/run/rsq.sock,uid=1000` or `http:0.0.0.0:8080,token=secret`. The
+//! kind defaults to `tcp`. `metrics` listeners serve Prometheus metrics.
+//!
+//! Settings:
+//!
+//! - `max-connections=<n>`: connections open at the same time
+//! - `max-frame=<bytes>`: largest frame accepted (`tcp`, `unix` and `ws`)
+//! - `allow=<ip>`: only accept clients from this address (can be repeated,
+//!   not for `unix`)
+//! - `peercred`: identify clients by their user and process id (`unix`)
+//! - `uid=<uid>`: only accept clients of this user, implies `peercred`
+//!   (`unix`, can be repeated)
+//! - `token=<token>`: secret clients have to send: as bearer token (`http`,
+//!   `metrics`), with `ControlMsg::Auth` (`tcp`, `unix`, `ws`), as CONNECT
+//!   password (`mqtt`) or with `AUTH` (`resp`)
+//!
+//! TLS isn't supported yet, `tls:` listeners are rejected. Connections that
+//! need it have to be terminated by a proxy in front of a `tcp` listener.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::wire;
+
+/// Protocol spoken on a listener.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Kind {
+    /// Native protocol over TCP.
+    Tcp,
+    /// Native protocol over a Unix domain socket.
+    Unix,
+    WebSocket,
+    /// HTTP gateway.
+    Http,
+    Mqtt,
+    /// Redis pub/sub.
+    Resp,
+    /// Prometheus metrics, over HTTP.
+    Metrics,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ListenerConfig {
+    pub kind: Kind,
+    /// Socket address, or path of a Unix socket.
+    pub addr: String,
+    pub max_connections: Option<u64>,
+    pub max_frame_len: usize,
+    pub allow: Vec<IpAddr>,
+    pub peercred: bool,
+    pub uids: Vec<u32>,
+    pub token: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseError(String);
+
+impl Kind {
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix {
+            "tcp" => Some(Kind::Tcp),
+            "unix" => Some(Kind::Unix),
+            "ws" => Some(Kind::WebSocket),
+            "http" => Some(Kind::Http),
+            "mqtt" => Some(Kind::Mqtt),
+            "resp" => Some(Kind::Resp),
+            "metrics" => Some(Kind::Metrics),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Tcp => "tcp",
+            Kind::Unix => "unix",
+            Kind::WebSocket => "ws",
+            Kind::Http => "http",
+            Kind::Mqtt => "mqtt",
+            Kind::Resp => "resp",
+            Kind::Metrics => "metrics",
+        })
+    }
+}
+
+impl ListenerConfig {
+    pub fn new(kind: Kind, addr: &str) -> Self {
+        Self {
+            kind,
+            addr: addr.to_string(),
+            max_connections: None,
+            max_frame_len: wire::MAX_FRAME_LEN,
+            allow: Vec::new(),
+            peercred: false,
+            uids: Vec::new(),
+            token: None,
+        }
+    }
+
+    /// Returns `true` if clients from `ip` may connect.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.contains(&ip)
+    }
+
+    /// Returns `true` if clients of user `uid` may connect.
+    pub fn allows_uid(&self, uid: u32) -> bool {
+        self.uids.is_empty() || self.uids.contains(&uid)
+    }
+
+    fn set(&mut self, setting: &str) -> Result<(), ParseError> {
+        let (key, value) = match setting.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (setting, None),
+        };
+        let kind = self.kind;
+        let only = |kinds: &[Kind]| {
+            if kinds.contains(&kind) {
+                Ok(())
+            } else {
+                Err(ParseError(format!(
+                    "{key} is not supported by {kind} listeners"
+                )))
+            }
+        };
+        let value = || value.ok_or_else(|| ParseError(format!("{key} needs a value")));
+        let invalid = || ParseError(format!("invalid {key}"));
+
+        match key {
+            "max-connections" => {
+                self.max_connections = Some(value()?.parse().map_err(|_| invalid())?)
+            }
+            "max-frame" => {
+                only(&[Kind::Tcp, Kind::Unix, Kind::WebSocket])?;
+                let max_frame_len = value()?.parse().map_err(|_| invalid())?;
+                if max_frame_len > wire::MAX_FRAME_LEN {
+                    return Err(ParseError(format!(
+                        "max-frame can't exceed {}",
+                        wire::MAX_FRAME_LEN
+                    )));
+                }
+                self.max_frame_len = max_frame_len;
+            }
+            "allow" => {
+                only(&[
+                    Kind::Tcp,
+                    Kind::WebSocket,
+                    Kind::Http,
+                    Kind::Mqtt,
+                    Kind::Resp,
+                    Kind::Metrics,
+                ])?;
+                self.allow.push(value()?.parse().map_err(|_| invalid())?);
+            }
+            "peercred" => {
+                only(&[Kind::Unix])?;
+                self.peercred = true;
+            }
+            "uid" => {
+                only(&[Kind::Unix])?;
+                self.uids.push(value()?.parse().map_err(|_| invalid())?);
+                self.peercred = true;
+            }
+            "token" => {
+                self.token = Some(value()?.to_string());
+            }
+            _ => return Err(ParseError(format!("unknown setting {key}"))),
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ListenerConfig {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or_default();
+
+        let (kind, addr) = match addr.split_once(':') {
+            Some(("tls", _)) => {
+                return Err(ParseError(
+                    "TLS listeners are not supported, terminate TLS in a proxy".to_string(),
+                ))
+            }
+            Some((prefix, rest)) => match Kind::from_prefix(prefix) {
+                Some(kind) => (kind, rest),
+                None => (Kind::Tcp, addr),
+            },
+            None => (Kind::Tcp, addr),
+        };
+        if addr.is_empty() {
+            return Err(ParseError(format!("{kind} listener without address")));
+        }
+
+        let mut config = ListenerConfig::new(kind, addr);
+        for setting in parts {
+            config.set(setting)?;
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config: ListenerConfig = "0.0.0.0:6142".parse().unwrap();
+        assert_eq!(config, ListenerConfig::new(Kind::Tcp, "0.0.0.0:6142"));
+
+        let config: ListenerConfig = "tcp:[::1]:6142,max-connections=10,max-frame=1024"
+            .parse()
+            .unwrap();
+        assert_eq!(config.addr, "[::1]:6142");
+        assert_eq!(config.max_connections, Some(10));
+        assert_eq!(config.max_frame_len, 1024);
+
+        let config: ListenerConfig = "unix:/run/rsq.sock,uid=1000".parse().unwrap();
+        assert_eq!(config.kind, Kind::Unix);
+        assert_eq!(config.addr, "/run/rsq.sock");
+        assert!(config.peercred);
+        assert!(config.allows_uid(1000));
+        assert!(!config.allows_uid(0));
+
+        let config: ListenerConfig = "http:127.0.0.1:8080,token=secret,allow=127.0.0.1"
+            .parse()
+            .unwrap();
+        assert_eq!(config.token.as_deref(), Some("secret"));
+        assert!(config.allows("127.0.0.1".parse().unwrap()));
+        assert!(!config.allows("10.0.0.1".parse().unwrap()));
+
+        let config: ListenerConfig = "metrics:127.0.0.1:9090,token=secret".parse().unwrap();
+        assert_eq!(config.kind, Kind::Metrics);
+        assert_eq!(config.token.as_deref(), Some("secret"));
+
+        let config: ListenerConfig = "mqtt:0.0.0.0:1883,token=secret".parse().unwrap();
+        assert_eq!(config.token.as_deref(), Some("secret"));
+
+        assert!("tls:0.0.0.0:6143".parse::<ListenerConfig>().is_err());
+        assert!("unix:".parse::<ListenerConfig>().is_err());
+        assert!("unix:/run/rsq.sock,allow=127.0.0.1"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("http:0.0.0.0:80,max-frame=1024"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("0.0.0.0:6142,max-frame=huge"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("0.0.0.0:6142,speed=fast".parse::<ListenerConfig>().is_err());
+    }
+}
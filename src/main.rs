@@ -2,36 +2,40 @@
 //!
 
 use argh::FromArgs;
-use bytes::Bytes;
 use fdlimit::{raise_fd_limit, Outcome};
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::error::Error;
-use std::net::SocketAddr;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicU64, Ordering};
-
-use flume::Receiver;
-use monoio::io::{
-    AsyncWriteRent, AsyncWriteRentExt, BufWriter, OwnedReadHalf, OwnedWriteHalf, Splitable,
-};
-use monoio::net::{TcpListener, TcpStream};
-use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
-
-use rsq::messaging::msg::{ControlMsg, Msg};
-use rsq::messaging::peer::{Peer, PeerId, PeerTx};
-use rsq::messaging::router::Router;
-
-// Codec
-use rsq::monoio_bincode::Framed;
-use rsq::msg_stream::FrameDecoder;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use monoio::net::TcpListener;
+use tracing_subscriber::{fmt::format::FmtSpan, reload, EnvFilter};
+
+use rsq::compression;
+use rsq::config::Config;
+use rsq::http::{self, Gateway};
+use rsq::listener::{Kind, ListenerConfig};
+use rsq::messaging::acl::Acl;
+use rsq::messaging::msg::StatusMsg;
+use rsq::metrics;
+use rsq::mqtt;
+use rsq::resp;
+use rsq::server::{self, shutdown_requested, Endpoint, Shared, MAX_CONNECTIONS, SHUTDOWN_POLL};
+use rsq::unix;
+use rsq::webhook::{self, DeliveryLog, Webhook};
+use rsq::websocket;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+/// Listen address used if no listener is configured.
+const DEFAULT_ADDR: &str = "0.0.0.0:6142";
 
-//// counting allocator
+// counting allocator
 // use std::alloc::{GlobalAlloc, Layout, System};
 // use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -65,267 +69,438 @@ static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
 /// rsq server
 #[derive(FromArgs, Clone)]
 struct Args {
-    /// listen address
-    #[argh(option, default = "String::from(\"0.0.0.0:6142\")")]
-    addr: String,
+    /// listen address (default: 0.0.0.0:6142, unless other listeners are
+    /// configured)
+    #[argh(option)]
+    addr: Option<String>,
+
+    /// number of messages channels keep for subscribers resuming after a
+    /// disconnect (default: 0)
+    #[argh(option)]
+    channel_history: Option<usize>,
+
+    /// seconds to keep empty channels around before removing them
+    /// (default: 0, removed as soon as they are empty)
+    #[argh(option)]
+    channel_linger: Option<u64>,
+
+    /// frames smaller than this are sent uncompressed (default: 512)
+    #[argh(option)]
+    compression_threshold: Option<usize>,
+
+    /// configuration file, reloaded on SIGHUP
+    #[argh(option)]
+    config: Option<String>,
+
+    /// directory for persistent server state
+    #[argh(option)]
+    data_dir: Option<String>,
+
+    /// HTTP gateway listen address
+    #[argh(option)]
+    http_addr: Option<String>,
+
+    /// bearer token required by the HTTP gateway
+    #[argh(option)]
+    http_token: Option<String>,
+
+    /// additional listener, as [<kind>:]<address>[,<setting>...], with kind
+    /// tcp, unix, ws, http, mqtt, resp or metrics (can be repeated)
+    #[argh(option)]
+    listen: Vec<String>,
+
+    /// listen address serving Prometheus metrics
+    #[argh(option)]
+    metrics_addr: Option<String>,
+
+    /// MQTT listen address
+    #[argh(option)]
+    mqtt_addr: Option<String>,
+
+    /// RESP (Redis pub/sub) listen address
+    #[argh(option)]
+    resp_addr: Option<String>,
+
+    /// seconds to wait for queued messages to be sent when shutting down
+    #[argh(option, default = "10")]
+    shutdown_timeout: u64,
+
+    /// identify clients connecting through a Unix socket by their user and
+    /// process id (SO_PEERCRED)
+    #[argh(switch)]
+    unix_peercred: bool,
+
+    /// post the messages of a channel to an HTTP endpoint, given as
+    /// <channel>=<url> (can be repeated)
+    #[argh(option)]
+    webhook: Vec<String>,
+
+    /// shared secret for verifying signatures of webhooks posted to the
+    /// HTTP gateway
+    #[argh(option)]
+    webhook_secret: Option<String>,
+
+    /// secret for signing messages posted to webhook endpoints
+    #[argh(option)]
+    webhook_signing_secret: Option<String>,
+
+    /// websocket listen address
+    #[argh(option)]
+    ws_addr: Option<String>,
 }
 
-fn main() -> std::result::Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env().add_directive("rsq=info".parse()?))
-        .with_span_events(FmtSpan::FULL)
-        .init();
+/// Replaces the log filter.
+type LogReload = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error>>;
 
+fn main() -> std::result::Result<(), Box<dyn Error>> {
     let args: Args = argh::from_env();
 
+    let config = match &args.config {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(log_filter(&config)?)
+        .with_span_events(FmtSpan::FULL)
+        .with_filter_reloading();
+    let log_handle = subscriber.reload_handle();
+    subscriber.init();
+    let log_reload: LogReload = Box::new(move |filter| log_handle.reload(filter));
+
     let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-        .with_entries(32768)
+        .with_entries(config.limits.io_uring_entries.unwrap_or(32768))
+        .enable_timer()
         .build()
         .unwrap();
 
-    rt.block_on(server(args))
+    rt.block_on(server(args, config, log_reload))
 }
 
-async fn server(args: Args) -> Result<(), Box<dyn Error>> {
+/// Returns the log filter set in `config`, or the default one.
+fn log_filter(config: &Config) -> Result<EnvFilter, Box<dyn Error>> {
+    Ok(match &config.logging.filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::from_default_env().add_directive("rsq=info".parse()?),
+    })
+}
+
+async fn server(args: Args, config: Config, log_reload: LogReload) -> Result<(), Box<dyn Error>> {
     // figure out possible number of connections
-    let max_connections = if let Outcome::LimitRaised { from: _, to } = raise_fd_limit()? {
+    let fd_connections = if let Outcome::LimitRaised { from: _, to } = raise_fd_limit()? {
         to - 64
     } else {
         512
     };
 
-    tracing::info!("server listening on {}", args.addr);
-    tracing::info!("server accepting up to {} connections", max_connections);
-
     // Create the shared state.
     //
     // The server task will hold a handle to this. For every new client, the
     // `state` handle is cloned and passed into the task that processes the
     // client connection.
     let state = Rc::new(RefCell::new(Shared::new()));
+    apply_config(&mut state.borrow_mut(), &args, &config, fd_connections);
+
+    tracing::info!(
+        "server accepting up to {} connections",
+        MAX_CONNECTIONS.load(Ordering::Relaxed)
+    );
+
+    let data_dir = match &args.data_dir {
+        Some(data_dir) => Some(PathBuf::from(data_dir)),
+        None => config.data_dir.clone(),
+    };
+    if let Some(data_dir) = &data_dir {
+        std::fs::create_dir_all(data_dir)?;
+        let mut state = state.borrow_mut();
+        state
+            .router
+            .set_schedule_store(data_dir.join("schedule.bin"))?;
+        state
+            .router
+            .set_log_store(data_dir.join("channel_logs.bin"))?;
+    }
 
-    // Bind a TCP listener to the socket address.
-    let listener = TcpListener::bind(&args.addr)?;
+    monoio::spawn(channel_gc(state.clone()));
+    monoio::spawn(scheduler(state.clone()));
+
+    // a second signal exits right away
+    let terminate = Arc::clone(&state.borrow().shutdown);
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&terminate))?;
+        signal_hook::flag::register(signal, Arc::clone(&terminate))?;
+    }
+
+    if let Some(path) = &args.config {
+        let hangup = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, hangup.clone())?;
+        monoio::spawn(config_reloader(
+            state.clone(),
+            args.clone(),
+            PathBuf::from(path),
+            config.clone(),
+            fd_connections,
+            hangup,
+            log_reload,
+        ));
+    }
+
+    // outgoing webhooks are logged next to the other persistent state
+    let delivery_log = match &data_dir {
+        Some(data_dir) if !args.webhook.is_empty() => Some(Rc::new(RefCell::new(
+            DeliveryLog::open(&data_dir.join("webhooks.log"))?,
+        ))),
+        _ => None,
+    };
+    for (i, webhook) in args.webhook.iter().enumerate() {
+        let (channel, url) = webhook
+            .split_once('=')
+            .ok_or("webhooks have to be given as <channel>=<url>")?;
+        let mut webhook = Webhook::new(url)?;
+        if let Some(secret) = &args.webhook_signing_secret {
+            webhook = webhook.with_secret(secret.clone());
+        }
+        tracing::info!("posting messages of {} to {}", channel, url);
+        monoio::spawn(webhook::subscriber(
+            state.clone(),
+            i + 1,
+            channel.to_string(),
+            webhook,
+            delivery_log.clone(),
+        ));
+    }
+
+    for config in listener_configs(&args, &config)? {
+        start_listener(&state, config, &args)?;
+    }
 
     // std::thread::spawn(|| loop {
     //     std::thread::sleep(Duration::from_secs(1));
     //     println!("Current memory: {}", A.count.load(Ordering::Relaxed));
     // });
-    loop {
-        while OPEN_CONNECTIONS.load(Ordering::Relaxed) >= max_connections {
-            use monoio::time::{sleep, Duration};
+    shutdown_requested(&state).await;
+    shutdown(&state, Duration::from_secs(args.shutdown_timeout)).await;
 
-            tracing::info!("connection limit ({}) reached", max_connections);
-            sleep(Duration::from_secs(1)).await;
-        }
+    Ok(())
+}
 
-        // Asynchronously wait for an inbound TcpStream.
-        let (stream, addr) = listener.accept().await?;
-        stream.set_nodelay(true)?;
+/// Shuts the server down gracefully.
+///
+/// Listeners stop by themselves. Messages published from now on are
+/// rejected. Peers are told about the shutdown and get until `timeout` for
+/// their queued messages to be sent. Scheduled messages are persisted;
+/// channels, retained messages and channel logs are not.
+async fn shutdown(state: &Rc<RefCell<Shared>>, timeout: Duration) {
+    tracing::info!("shutting down");
+    {
+        let mut state = state.borrow_mut();
+        state.router.close();
+        state.router.notify_peers(StatusMsg::ShuttingDown);
+    }
 
-        // Clone a handle to the `Shared` state for the new connection.
-        let state = Rc::clone(&state);
+    let deadline = Instant::now() + timeout;
+    loop {
+        // also gives writers time to send what they took off the queues
+        monoio::time::sleep(SHUTDOWN_POLL).await;
+        let queued = state.borrow().router.queued();
+        if queued == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!("dropping {} queued messages", queued);
+            metrics::DROPPED.add(queued as u64);
+            break;
+        }
+    }
 
-        // Spawn our handler to be run asynchronously.
-        monoio::spawn(async move {
-            tracing::debug!("accepted connection");
-            OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
-            if let Err(e) = process(state, stream, addr).await {
-                tracing::info!("an error occurred; error = {:?}", e);
-            }
-            OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
-        });
+    if let Err(e) = state.borrow_mut().router.persist() {
+        tracing::error!("error persisting scheduled messages and channel logs: {e}");
     }
+    tracing::info!("shutdown complete");
 }
 
-/// Data that is shared between all client connections
-struct Shared {
-    connections: HashMap<SocketAddr, PeerTx>,
-    router: Router,
+/// Applies the settings that can change while the server is running.
+fn apply_config(state: &mut Shared, args: &Args, config: &Config, fd_connections: u64) {
+    let max_connections = match config.limits.max_connections {
+        Some(max) => max.min(fd_connections),
+        None => fd_connections,
+    };
+    MAX_CONNECTIONS.store(max_connections, Ordering::Relaxed);
+
+    // command line options take precedence over the configuration file
+    let linger = args
+        .channel_linger
+        .or(config.channels.linger)
+        .unwrap_or_default();
+    state.router.set_linger(Duration::from_secs(linger));
+    let history = args
+        .channel_history
+        .or(config.channels.history)
+        .unwrap_or_default();
+    state.router.set_history_len(history);
+    state.router.set_channel_defaults(config.channel_defaults());
+    state.router.set_acl(Acl::new(config.acl.clone()));
+    state.compression_threshold = args
+        .compression_threshold
+        .or(config.limits.compression_threshold)
+        .unwrap_or(compression::DEFAULT_THRESHOLD);
 }
 
-/// `Peer` handle for TCP connections.
-struct ConnectionPeer {
-    /// Sender message channel.
-    ///
-    /// Messages sent here will be sent to the Rx half.
-    tx: PeerTx,
+/// Reloads the configuration file when `hangup` gets set (on SIGHUP).
+///
+/// Connections stay open. Changed settings that are only read at startup
+/// are reported, but not applied.
+async fn config_reloader(
+    state: Rc<RefCell<Shared>>,
+    args: Args,
+    path: PathBuf,
+    mut config: Config,
+    fd_connections: u64,
+    hangup: Arc<AtomicBool>,
+    log_reload: LogReload,
+) {
+    loop {
+        monoio::time::sleep(Duration::from_secs(1)).await;
+        if !hangup.swap(false, Ordering::Relaxed) {
+            continue;
+        }
 
-    peer_id: PeerId,
-}
+        tracing::info!("reloading {}", path.display());
+        let new = match Config::load(&path) {
+            Ok(new) => new,
+            Err(e) => {
+                tracing::error!("keeping the current configuration: {e}");
+                continue;
+            }
+        };
+        let filter = match log_filter(&new) {
+            Ok(filter) => filter,
+            Err(e) => {
+                tracing::error!("keeping the current configuration: {e}");
+                continue;
+            }
+        };
 
-impl Shared {
-    /// Create a new, empty, instance of `Shared`.
-    fn new() -> Self {
-        Shared {
-            connections: HashMap::new(),
-            router: Router::new(),
+        for setting in config.restart_required(&new) {
+            tracing::warn!("{setting} changed, restart the server to apply");
+        }
+        if let Err(e) = log_reload(filter) {
+            tracing::error!("can't change the log filter: {e}");
         }
+        apply_config(&mut state.borrow_mut(), &args, &new, fd_connections);
+        config = new;
     }
 }
 
-impl ConnectionPeer {
-    /// Create a new instance of `Connection`.
-    fn new(peer_addr: SocketAddr) -> (Receiver<Bytes>, ConnectionPeer) {
-        // Generate peer id. using the socket address for now.
-        let peer_id = PeerId::new(&peer_addr.to_string());
-
-        // Create a channel for this peer
-        let (tx, rx) = flume::unbounded();
-
-        (rx, ConnectionPeer { tx, peer_id })
+/// Collects the listeners given by `--listen`, the `--*-addr` options and
+/// the configuration file.
+fn listener_configs(args: &Args, config: &Config) -> Result<Vec<ListenerConfig>, Box<dyn Error>> {
+    let mut configs = Vec::new();
+    let addrs = [
+        (Kind::Tcp, &args.addr),
+        (Kind::WebSocket, &args.ws_addr),
+        (Kind::Mqtt, &args.mqtt_addr),
+        (Kind::Resp, &args.resp_addr),
+        (Kind::Http, &args.http_addr),
+        (Kind::Metrics, &args.metrics_addr),
+    ];
+    for (kind, addr) in addrs.iter() {
+        if let Some(addr) = addr {
+            configs.push(ListenerConfig::new(*kind, addr));
+        }
     }
-}
-
-impl Peer for ConnectionPeer {
-    fn get_id(&self) -> &PeerId {
-        &self.peer_id
+    for listen in &args.listen {
+        configs.push(listen.parse()?);
     }
-
-    fn get_sink(&self) -> &PeerTx {
-        &self.tx
+    configs.extend(config.listeners()?);
+    if configs.is_empty() {
+        configs.push(ListenerConfig::new(Kind::Tcp, DEFAULT_ADDR));
     }
-}
 
-/// Process an individual chat client
-async fn process(
-    state: Rc<RefCell<Shared>>,
-    stream: TcpStream,
-    addr: SocketAddr,
-) -> Result<(), Box<dyn Error>> {
-    let peer_addr = stream.peer_addr()?;
-    let (stream_in, stream_out) = stream.into_split();
-    //let msgs_in = FramedRead::new(stream_in, BincodeCodec::<Msg>::new());
-    let msgs_in = FrameDecoder::new(stream_in);
-
-    // Register our peer with state which internally sets up some channels.
-    let (rx, peer) = ConnectionPeer::new(peer_addr);
-
-    let peer_name = addr.to_string();
-    let msg = format!("new connection from {}", &peer_name);
-    tracing::info!("{}", msg);
-
-    // A client has connected
-    {
-        let mut state = state.borrow_mut();
-        state.connections.insert(peer_addr, peer.tx.clone());
-        state.router.peer_add(&peer);
+    for config in &mut configs {
+        match config.kind {
+            Kind::Unix => config.peercred |= args.unix_peercred,
+            Kind::Http if config.token.is_none() => config.token = args.http_token.clone(),
+            _ => {}
+        }
     }
 
-    let peer_id = peer.get_id().clone();
+    Ok(configs)
+}
 
-    let from_client_handle = monoio::spawn(from_client(state.clone(), msgs_in, peer));
-    let to_client_handle = monoio::spawn(to_client(rx, stream_out));
+/// Binds a listener and spawns the task accepting its connections.
+fn start_listener(
+    state: &Rc<RefCell<Shared>>,
+    config: ListenerConfig,
+    args: &Args,
+) -> io::Result<()> {
+    let state = state.clone();
+    let name = format!("{}:{}", config.kind, config.addr);
+
+    if config.kind == Kind::Unix {
+        let listener = unix::bind(&config.addr)?;
+        tracing::info!("server listening on {}", name);
+        let endpoint = Rc::new(Endpoint::new(config));
+        monoio::spawn(server::unix_listener(state, listener, endpoint));
+        return Ok(());
+    }
 
-    //
-    monoio::select!(
-        e = from_client_handle => {
-            if let Err(e) = e {
-                tracing::error!("{peer_name}: {e}");
+    let listener = TcpListener::bind(&config.addr)?;
+    let kind = config.kind;
+    let gateway = Rc::new(Gateway {
+        token: config.token.clone(),
+        webhook_secret: args.webhook_secret.clone(),
+    });
+    let endpoint = Rc::new(Endpoint::new(config));
+    match kind {
+        Kind::Tcp => {
+            tracing::info!("server listening on {}", name);
+            monoio::spawn(server::tcp_listener(state, listener, endpoint));
+        }
+        Kind::WebSocket => {
+            tracing::info!("WebSocket listening on {}", name);
+            monoio::spawn(websocket::listener(state, listener, endpoint));
+        }
+        Kind::Http => {
+            if gateway.token.is_none() {
+                tracing::warn!("HTTP gateway {} accepts requests without token", name);
             }
-        },
-        e = to_client_handle => {
-            if let Err(e) = e {
-                tracing::error!("{peer_name}: {e}");
+            tracing::info!("HTTP gateway listening on {}", name);
+            monoio::spawn(http::listener(state, listener, endpoint, gateway));
+        }
+        Kind::Mqtt => {
+            tracing::info!("MQTT listening on {}", name);
+            monoio::spawn(mqtt::listener(state, listener, endpoint));
+        }
+        Kind::Resp => {
+            tracing::info!("Redis pub/sub listening on {}", name);
+            monoio::spawn(resp::listener(state, listener, endpoint));
+        }
+        Kind::Metrics => {
+            if endpoint.config.token.is_none() {
+                tracing::info!("metrics on {} are served without token", name);
             }
+            tracing::info!("metrics listening on {}", name);
+            monoio::spawn(metrics::listener(state, listener, endpoint));
         }
-    );
-
-    // If this section is reached it means that the client was disconnected!
-    {
-        let mut state = state.borrow_mut();
-        state.connections.remove(&addr);
-        state.router.peer_remove(&peer_id);
+        Kind::Unix => unreachable!(),
     }
 
-    tracing::info!("{peer_name} disconnected");
-
     Ok(())
 }
 
-async fn from_client(
-    state: Rc<RefCell<Shared>>,
-    mut msgs_in: FrameDecoder<OwnedReadHalf<TcpStream>>,
-    peer: ConnectionPeer,
-) -> Result<(), anyhow::Error> {
+/// Periodically removes channels that have been empty for too long.
+async fn channel_gc(state: Rc<RefCell<Shared>>) {
     loop {
-        match msgs_in.next().await {
-            Some(Ok(bytes)) => {
-                // Deserialize message header
-                let msg_slice = &bytes[4..];
-                let (msg, _hdr_len) = bincode::decode_from_slice_with_context::<bool, Msg, _>(
-                    msg_slice,
-                    bincode::config::standard(),
-                    true,
-                )
-                .unwrap();
-
-                // handle message
-                match msg {
-                    Msg::ChannelMsg(msg) => {
-                        //tracing::info!("msg len: {}", msg.content().len());
-                        let mut state = state.borrow_mut();
-                        let _count =
-                            state
-                                .router
-                                .forward(bytes.freeze(), msg.channel(), peer.get_id());
-                    }
-                    Msg::ControlMsg(controlmsg) => match controlmsg {
-                        ControlMsg::ChannelJoin(channel) => {
-                            let mut state = state.borrow_mut();
-                            let channel_id = state.router.channel_get_or_add(channel);
-                            state.router.attach(channel_id, &peer)?;
-                        }
-                        ControlMsg::ChannelLeave(channel) => {
-                            let mut state = state.borrow_mut();
-                            state.router.detach(channel, &peer)?;
-                        }
-                        ControlMsg::ChannelCreate(name) => {
-                            let mut state = state.borrow_mut();
-                            let channel_id = state.router.channel_get_or_add(name.clone());
-                            peer.get_sink()
-                                .send_async(
-                                    Msg::StatusMsg(rsq::messaging::msg::StatusMsg::ChannelId(
-                                        name, channel_id,
-                                    ))
-                                    .framed(),
-                                )
-                                .await?;
-                        }
-                    },
-                    _ => {
-                        tracing::error!("unhandled message: {:?}", msg);
-                    }
-                };
-            }
-            Some(Err(e)) => {
-                tracing::info!("from_client error: {e}");
-                break;
-            }
-            None => break,
-        }
+        monoio::time::sleep(Duration::from_secs(1)).await;
+        state.borrow_mut().router.gc(Instant::now());
     }
-
-    Ok(())
 }
 
-async fn to_client(
-    rx: Receiver<Bytes>,
-    writer: OwnedWriteHalf<TcpStream>,
-) -> Result<(), anyhow::Error> {
-    // A message was received for the peer. Send it to the framed TCP
-    // stream.
-    let mut writer = BufWriter::with_capacity(8 * 1024, writer);
+/// Forwards scheduled messages once they are due.
+async fn scheduler(state: Rc<RefCell<Shared>>) {
     loop {
-        match rx.recv_async().await {
-            Ok(payload) => {
-                let (res, _buf) = writer.write_all(payload).await;
-                let _ = res?;
-                if rx.is_empty() {
-                    writer.flush().await?;
-                }
-            }
-            Err(e) => return Err(e.into()),
-        }
+        monoio::time::sleep(Duration::from_millis(100)).await;
+        state.borrow_mut().router.deliver_due();
     }
 }
@@ -0,0 +1,91 @@
+//! Access control for channels.
+//!
+//! Rules select peers by a pattern on their id (see `pattern::matches()`),
+//! e.g. `unix:1000:*` for Unix socket clients of user 1000, or
+//! `mqtt:10.0.0.*` for MQTT clients of a subnet (network clients are named
+//! `<listener kind>:<address>:<connection>`). The first rule matching a peer
+//! decides which channels it may publish and subscribe to. Peers no rule
+//! matches have unrestricted access.
+
+use serde::Deserialize;
+
+use super::pattern;
+use super::peer::PeerId;
+
+#[derive(PartialEq, Eq, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    /// Pattern of the peer ids the rule applies to.
+    pub peers: String,
+    /// Patterns of the channels the peers may publish to.
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Patterns of the channels the peers may subscribe to.
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn may_publish(&self, peer_id: &PeerId, channel: &str) -> bool {
+        match self.rule(peer_id) {
+            Some(rule) => rule.publish.iter().any(|p| pattern::matches(p, channel)),
+            None => true,
+        }
+    }
+
+    pub fn may_subscribe(&self, peer_id: &PeerId, channel: &str) -> bool {
+        match self.rule(peer_id) {
+            Some(rule) => rule.subscribe.iter().any(|p| pattern::matches(p, channel)),
+            None => true,
+        }
+    }
+
+    fn rule(&self, peer_id: &PeerId) -> Option<&AclRule> {
+        self.rules
+            .iter()
+            .find(|rule| pattern::matches(&rule.peers, peer_id.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let acl = Acl::new(vec![
+            AclRule {
+                peers: "unix:1000:*".to_string(),
+                publish: vec!["clipboard".to_string()],
+                subscribe: vec!["clipboard".to_string(), "status.*".to_string()],
+            },
+            AclRule {
+                peers: "unix:*".to_string(),
+                publish: Vec::new(),
+                subscribe: Vec::new(),
+            },
+        ]);
+
+        let user = PeerId::new("unix:1000:42:1");
+        assert!(acl.may_publish(&user, "clipboard"));
+        assert!(!acl.may_publish(&user, "status.cpu"));
+        assert!(acl.may_subscribe(&user, "status.cpu"));
+
+        let other = PeerId::new("unix:1001:43:2");
+        assert!(!acl.may_publish(&other, "clipboard"));
+        assert!(!acl.may_subscribe(&other, "clipboard"));
+
+        let tcp = PeerId::new("tcp:127.0.0.1:50000:3");
+        assert!(acl.may_publish(&tcp, "anything"));
+        assert!(acl.may_subscribe(&tcp, "anything"));
+    }
+}
@@ -1,9 +1,15 @@
 use bincode::{BorrowDecode, Decode, Encode};
-use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use slotmap::{new_key_type, KeyData};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
+use super::frame::Frame;
+use super::log::{ChannelLog, LogWriter};
+use super::msg::Msg;
 use super::peer::{Peer, PeerId, PeerTx};
+use super::util::unix_time_ms;
+use crate::metrics;
 
 new_key_type! {
     pub struct ChannelId;
@@ -34,11 +40,69 @@ impl<'de, Context> BorrowDecode<'de, Context> for ChannelId {
     }
 }
 
+/// Per-channel settings that can be changed at runtime.
+///
+/// Channels, their retained messages and history are only kept in memory,
+/// they don't survive a server restart. With a data directory, the logs of
+/// compacted channels are persisted along with the channel's options and
+/// owner (see `LogStore`), so persistent compacted channels survive.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize, Encode, Decode)]
+pub struct ChannelOptions {
+    pub description: Option<String>,
+    /// Persistent channels are never removed automatically, only by
+    /// `ChannelDelete`.
+    pub persistent: bool,
+    /// Keep the last published message and deliver it to new subscribers.
+    ///
+    /// Publishing a message with empty content clears the retained message.
+    pub retain: bool,
+    /// Keep the latest message per key and replay them to new subscribers.
+    ///
+    /// Messages without key are not kept. Publishing a message with empty
+    /// content deletes its key.
+    pub compact: bool,
+}
+
+/// Channel metadata as reported to clients.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize, Encode, Decode)]
+pub struct ChannelInfo {
+    pub id: ChannelId,
+    pub name: String,
+    /// Creation time in milliseconds since the Unix epoch.
+    pub created: u64,
+    pub owner: Option<PeerId>,
+    pub subscribers: u64,
+    /// Number of messages published to the channel.
+    pub messages: u64,
+    /// Number of bytes published to the channel, including framing.
+    pub bytes: u64,
+    /// Number of messages delivered to subscribers.
+    pub deliveries: u64,
+    pub options: ChannelOptions,
+}
+
 #[derive(Debug)]
 pub struct Channel {
     id: ChannelId,
     name: String,
+    created: u64,
+    owner: Option<PeerId>,
+    options: ChannelOptions,
     subscriptions: HashMap<PeerId, PeerTx>,
+    retained: Option<Frame>,
+    log: Option<ChannelLog>,
+    /// Persists the log, see `LogStore`.
+    log_writer: Option<LogWriter>,
+    /// The latest messages, replayed to subscribers resuming after a message
+    /// they have seen.
+    history: VecDeque<Frame>,
+    /// Number of messages kept in `history`.
+    history_len: usize,
+    /// Set while the channel has no subscribers.
+    empty_since: Option<Instant>,
+    messages: u64,
+    bytes: u64,
+    deliveries: u64,
 }
 
 impl Channel {
@@ -46,7 +110,19 @@ impl Channel {
         Channel {
             id,
             name,
+            created: unix_time_ms(),
+            owner: None,
+            options: ChannelOptions::default(),
             subscriptions: HashMap::new(),
+            retained: None,
+            log: None,
+            log_writer: None,
+            history: VecDeque::new(),
+            history_len: 0,
+            empty_since: Some(Instant::now()),
+            messages: 0,
+            bytes: 0,
+            deliveries: 0,
         }
     }
 
@@ -58,34 +134,280 @@ impl Channel {
         &self.name
     }
 
-    pub(crate) fn set_id(&mut self, id: ChannelId) {
-        self.id = id
+    pub fn owner(&self) -> Option<&PeerId> {
+        self.owner.as_ref()
+    }
+
+    pub(crate) fn set_owner(&mut self, owner: PeerId) {
+        self.owner = Some(owner);
+        self.store_settings();
     }
 
-    pub fn subscribe(&mut self, peer: &dyn Peer) {
-        self.subscriptions
-            .insert(peer.get_id().clone(), peer.get_sink().clone());
+    pub fn is_owner(&self, peer_id: &PeerId) -> bool {
+        self.owner.as_ref() == Some(peer_id)
+    }
+
+    pub fn options(&self) -> &ChannelOptions {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: ChannelOptions) {
+        if self.options.retain && !options.retain {
+            self.retained = None;
+        }
+        if !options.compact {
+            if let (Some(_), Some(writer)) = (self.log.take(), &self.log_writer) {
+                writer.remove(&self.name);
+            }
+        } else if self.log.is_none() {
+            self.log = Some(ChannelLog::new());
+        }
+        self.options = options;
+        self.store_settings();
+    }
+
+    /// Sets where the channel log is persisted. Logs are persisted from
+    /// then on, as far as the channel is compacted.
+    pub(crate) fn set_log_writer(&mut self, writer: Option<LogWriter>) {
+        self.log_writer = writer;
+    }
+
+    /// Persists owner and options of a compacted channel.
+    fn store_settings(&self) {
+        if let (Some(_), Some(writer)) = (&self.log, &self.log_writer) {
+            writer.channel(&self.name, self.owner.as_ref(), &self.options);
+        }
+    }
+
+    /// Adds a message loaded from the `LogStore` to the channel log.
+    ///
+    /// It is numbered like a newly published message.
+    pub(crate) fn restore(&mut self, key: String, frame: Frame) {
+        if let Some(log) = &mut self.log {
+            log.append(key, Some(frame.with_seq(self.messages)));
+            self.messages += 1;
+        }
+    }
+
+    /// Sets the number of messages kept for subscribers resuming with
+    /// `subscribe_from()`.
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn retained(&self) -> Option<&Frame> {
+        self.retained.as_ref().filter(|frame| !frame.is_expired())
+    }
+
+    /// Compacts the channel log if enough entries have been superseded.
+    pub fn compact(&mut self) {
+        if let Some(log) = &mut self.log {
+            if log.needs_compaction() {
+                log.compact();
+            }
+        }
+    }
+
+    /// Updates retained message, log and history with a published message.
+    ///
+    /// With `retain`, the message is retained even if the channel doesn't
+    /// retain messages.
+    fn record(&mut self, payload: &Frame, retain: bool) {
+        let retain = retain || self.options.retain;
+        if !(retain || self.options.compact || self.history_len > 0) {
+            return;
+        }
+
+        let (msg, content_len) = match Msg::decode_header(&payload.data) {
+            Ok((Msg::ChannelMsg(msg), content_len)) => (msg, content_len),
+            _ => return,
+        };
+
+        // fragments are only meaningful as part of their stream
+        if msg.headers().fragment.is_some() {
+            return;
+        }
+
+        let frame = (content_len > 0).then(|| payload.detach());
+
+        if let Some(frame) = frame.as_ref().filter(|_| self.history_len > 0) {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+            self.history.push_back(frame.clone());
+        }
+
+        if retain {
+            self.retained = frame.clone();
+        }
+
+        if let (Some(log), Some(key)) = (&mut self.log, msg.key()) {
+            if let Some(writer) = &self.log_writer {
+                writer.append(&self.name, key, frame.as_ref());
+            }
+            log.append(key.to_string(), frame);
+        }
+    }
+
+    pub fn info(&self) -> ChannelInfo {
+        ChannelInfo {
+            id: self.id,
+            name: self.name.clone(),
+            created: self.created,
+            owner: self.owner.clone(),
+            subscribers: self.subscriptions.len() as u64,
+            messages: self.messages,
+            bytes: self.bytes,
+            deliveries: self.deliveries,
+            options: self.options.clone(),
+        }
+    }
+
+    /// Subscribe `peer` to this channel.
+    ///
+    /// The channel log and a retained message are sent to a newly subscribed
+    /// peer right away.
+    /// Returns `true` if the peer was not subscribed before.
+    pub fn subscribe(&mut self, peer: &dyn Peer) -> bool {
+        self.subscribe_from(peer, None)
+    }
+
+    /// Subscribe `peer` to this channel, resuming at message `from`.
+    ///
+    /// Like `subscribe()`, but if `from` is given, the messages of the
+    /// channel history are sent as well. Of all of them, only messages
+    /// numbered `from` or later are sent.
+    pub fn subscribe_from(&mut self, peer: &dyn Peer, from: Option<u64>) -> bool {
+        if !self.is_subscribed(peer.get_id()) {
+            let mut replay: Vec<&Frame> = Vec::new();
+            if from.is_some() {
+                replay.extend(self.history.iter().filter(|frame| !frame.is_expired()));
+            }
+            if let Some(log) = &self.log {
+                replay.extend(log.replay());
+            }
+            replay.extend(self.retained());
+
+            // a message can be in the history, the log and be retained
+            replay.retain(|frame| match (frame.seq, from) {
+                (Some(seq), Some(from)) => seq >= from,
+                _ => true,
+            });
+            replay.sort_by_key(|frame| frame.seq);
+            replay.dedup_by_key(|frame| frame.seq);
+
+            let sink = peer.get_sink();
+            for frame in replay {
+                let _ = sink.send(frame.clone());
+            }
+        }
+
+        let new = self
+            .subscriptions
+            .insert(peer.get_id().clone(), peer.get_sink().clone())
+            .is_none();
+        self.update_empty();
+        new
     }
 
     pub fn unsubscribe(&mut self, peer: &dyn Peer) -> Option<()> {
-        self.subscriptions.remove(peer.get_id()).map(|_| ())
+        self.unsubscribe_id(peer.get_id())
+    }
+
+    pub fn unsubscribe_id(&mut self, peer_id: &PeerId) -> Option<()> {
+        let res = self.subscriptions.remove(peer_id).map(|_| ());
+        self.update_empty();
+        res
     }
 
-    pub fn forward(&mut self, payload: Bytes, sender: &PeerId) -> usize {
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    /// Returns for how long the channel has had no subscribers at `now`.
+    pub fn idle_for(&self, now: Instant) -> Option<Duration> {
+        self.empty_since
+            .map(|since| now.saturating_duration_since(since))
+    }
+
+    fn update_empty(&mut self) {
+        if self.subscriptions.is_empty() {
+            self.empty_since.get_or_insert_with(Instant::now);
+        } else {
+            self.empty_since = None;
+        }
+    }
+
+    pub fn is_subscribed(&self, peer_id: &PeerId) -> bool {
+        self.subscriptions.contains_key(peer_id)
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &PeerId> {
+        self.subscriptions.keys()
+    }
+
+    /// Publishes `payload` to all subscribers but `sender`.
+    ///
+    /// Expired messages are dropped.
+    pub fn forward(&mut self, payload: Frame, sender: &PeerId) -> usize {
+        self.publish(payload, sender, false)
+    }
+
+    /// Like `forward()`, but the message replaces the retained message, as
+    /// if the channel retained messages. A message without content clears
+    /// the retained message.
+    pub fn forward_retained(&mut self, payload: Frame, sender: &PeerId) -> usize {
+        self.publish(payload, sender, true)
+    }
+
+    fn publish(&mut self, payload: Frame, sender: &PeerId, retain: bool) -> usize {
+        if payload.is_expired() {
+            metrics::DROPPED.inc();
+            return 0;
+        }
+        let payload = payload.with_seq(self.messages);
+        self.record(&payload, retain);
+        self.messages += 1;
+        self.bytes += payload.len() as u64;
+        metrics::MESSAGES_IN.inc();
+        metrics::BYTES_IN.add(payload.len() as u64);
+        let count = self.notify(payload, sender);
+        self.deliveries += count as u64;
+        count
+    }
+
+    /// Sends `payload` to all subscribers but `sender`, without counting it
+    /// as a published message.
+    pub fn notify(&mut self, payload: Frame, sender: &PeerId) -> usize {
         let mut count = 0usize;
         self.subscriptions.retain(|peer_id, peer| {
             if peer_id != sender {
                 // If this errors, probably the peer's channel was closed when the peer
                 // disconnected.
-                peer.send(payload.clone()).inspect(|_| count += 1).is_ok()
+                peer.send(payload.clone())
+                    .inspect(|_| count += 1)
+                    .inspect_err(|_| metrics::DROPPED.inc())
+                    .is_ok()
             } else {
                 true
             }
         });
+        self.update_empty();
+        metrics::MESSAGES_OUT.add(count as u64);
+        metrics::BYTES_OUT.add((count * payload.len()) as u64);
         count
     }
 
-    pub async fn forward_async(&mut self, payload: Bytes, sender: &PeerId) -> usize {
+    pub async fn forward_async(&mut self, payload: Frame, sender: &PeerId) -> usize {
+        if payload.is_expired() {
+            metrics::DROPPED.inc();
+            return 0;
+        }
+        let payload = payload.with_seq(self.messages);
+        self.record(&payload, false);
         let mut count = 0usize;
         let mut dropped = Vec::new();
         for (peer_id, peer) in &self.subscriptions {
@@ -97,6 +419,7 @@ impl Channel {
                         count += 1;
                     }
                     Err(_) => {
+                        metrics::DROPPED.inc();
                         dropped.push(peer_id.clone());
                     }
                 }
@@ -105,6 +428,14 @@ impl Channel {
         for peer_id in dropped {
             self.subscriptions.remove(&peer_id);
         }
+        self.update_empty();
+        self.messages += 1;
+        self.bytes += payload.len() as u64;
+        self.deliveries += count as u64;
+        metrics::MESSAGES_IN.inc();
+        metrics::BYTES_IN.add(payload.len() as u64);
+        metrics::MESSAGES_OUT.add(count as u64);
+        metrics::BYTES_OUT.add((count * payload.len()) as u64);
         count
     }
 }
@@ -6,4 +6,12 @@ pub enum TxError {
     InvalidChannel,
     #[error("no subscriber")]
     NoSubscriber,
+    #[error("not subscribed to channel")]
+    NotSubscribed,
+    #[error("not the channel owner")]
+    NotOwner,
+    #[error("not permitted")]
+    NotPermitted,
+    #[error("server is shutting down")]
+    ShuttingDown,
 }
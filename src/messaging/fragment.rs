@@ -0,0 +1,530 @@
+//! Streamed messages.
+//!
+//! A large payload can be sent as a stream of channel messages, each carrying
+//! a `Fragment` header. The server forwards fragments as they arrive, like any
+//! other channel message, so neither the server nor the receiver needs to hold
+//! the whole payload in memory.
+//!
+//! A stream is identified by its sender and stream id. Fragments are numbered
+//! from 0, and the last fragment of a stream has `last` set (it may be empty).
+//!
+//! A stream that ends without its last fragment, because a fragment went
+//! missing, its sender left or it was evicted for being idle, fails with an
+//! error rather than looking complete to its reader.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use bincode::{Decode, Encode};
+use monoio::buf::{IoBufMut, IoVecBufMut, RawBuf};
+use monoio::io::AsyncReadRent;
+use monoio::BufResult;
+use serde::{Deserialize, Serialize};
+
+use super::channel::ChannelId;
+use super::headers::Headers;
+use super::msg::{ChannelMsg, StatusMsg};
+use super::peer::PeerId;
+
+/// Default payload size of a fragment.
+pub const DEFAULT_FRAGMENT_SIZE: usize = 64 * 1024;
+
+/// Number of fragments buffered per stream. Streams whose reader falls
+/// further behind are dropped.
+const STREAM_BUFFER: usize = 64;
+
+/// Default time a stream may go without fragments before it is dropped.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default number of streams reassembled at the same time.
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(
+    PartialEq, Eq, Hash, Clone, Copy, Debug, Default, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct Fragment {
+    /// Stream id, unique per sender.
+    pub stream: u64,
+    /// Position of the fragment within the stream.
+    pub seq: u64,
+    /// Set on the last fragment of the stream.
+    pub last: bool,
+}
+
+impl Fragment {
+    /// Returns the first fragment of a new stream.
+    pub fn start() -> Self {
+        Self {
+            stream: NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed),
+            seq: 0,
+            last: false,
+        }
+    }
+
+    /// Returns the fragment following this one.
+    pub fn next(self) -> Self {
+        Self {
+            seq: self.seq + 1,
+            ..self
+        }
+    }
+
+    pub fn into_last(self) -> Self {
+        Self { last: true, ..self }
+    }
+}
+
+/// Receiving end of a streamed message.
+///
+/// The content is read with `AsyncReadRent`, or chunk by chunk with
+/// `next_chunk()`.
+pub struct ContentStream {
+    sender: PeerId,
+    channel: ChannelId,
+    headers: Headers,
+    rx: flume::Receiver<io::Result<Chunk>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    /// Set once the last fragment or an error was received.
+    ended: bool,
+}
+
+/// Content of a fragment, and whether it is the last one.
+type Chunk = (Vec<u8>, bool);
+
+impl ContentStream {
+    pub fn sender(&self) -> &PeerId {
+        &self.sender
+    }
+
+    pub fn channel(&self) -> ChannelId {
+        self.channel
+    }
+
+    /// Headers of the first fragment.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Returns the next chunk of content, or `None` at the end of the stream.
+    pub async fn next_chunk(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.pos < self.chunk.len() {
+            let chunk = self.chunk.split_off(self.pos);
+            self.chunk.clear();
+            self.pos = 0;
+            return Some(Ok(chunk));
+        }
+        loop {
+            match self.recv().await? {
+                Ok(chunk) if chunk.is_empty() => continue,
+                res => return Some(res),
+            }
+        }
+    }
+
+    /// Waits for content to read, returning `false` at the end of the
+    /// stream.
+    async fn fill(&mut self) -> io::Result<bool> {
+        while self.pos == self.chunk.len() {
+            match self.recv().await {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Copies up to `len` bytes of content to `dst`, returning the number of
+    /// bytes copied.
+    ///
+    /// # Safety
+    ///
+    /// `dst` has to be valid for writing `len` bytes.
+    unsafe fn copy_to(&mut self, dst: *mut u8, len: usize) -> usize {
+        let n = len.min(self.chunk.len() - self.pos);
+        dst.copy_from_nonoverlapping(self.chunk[self.pos..].as_ptr(), n);
+        self.pos += n;
+        n
+    }
+
+    /// Receives the next fragment's content, or `None` at the end of the
+    /// stream.
+    async fn recv(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.ended {
+            return None;
+        }
+        match self.rx.recv_async().await {
+            Ok(Ok((chunk, last))) => {
+                self.ended = last;
+                Some(Ok(chunk))
+            }
+            Ok(Err(e)) => {
+                self.ended = true;
+                Some(Err(e))
+            }
+            // the stream was dropped before its last fragment
+            Err(_) => {
+                self.ended = true;
+                Some(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ended without its last fragment",
+                )))
+            }
+        }
+    }
+}
+
+/// Reads return 0 at the end of the stream, and fail if the stream broke off.
+impl AsyncReadRent for ContentStream {
+    async fn read<T: IoBufMut>(&mut self, mut buf: T) -> BufResult<usize, T> {
+        match self.fill().await {
+            Ok(true) => {}
+            Ok(false) => return (Ok(0), buf),
+            Err(e) => return (Err(e), buf),
+        }
+        let n = unsafe { self.copy_to(buf.write_ptr(), buf.bytes_total()) };
+        unsafe { buf.set_init(n) };
+        (Ok(n), buf)
+    }
+
+    async fn readv<T: IoVecBufMut>(&mut self, mut buf: T) -> BufResult<usize, T> {
+        // only the first buffer is filled
+        let n = match unsafe { RawBuf::new_from_iovec_mut(&mut buf) } {
+            Some(raw) => match self.read(raw).await {
+                (Ok(n), _) => n,
+                (Err(e), _) => return (Err(e), buf),
+            },
+            None => 0,
+        };
+        unsafe { buf.set_init(n) };
+        (Ok(n), buf)
+    }
+}
+
+struct OpenStream {
+    channel: ChannelId,
+    next_seq: u64,
+    last_seen: Instant,
+    tx: flume::Sender<io::Result<Chunk>>,
+}
+
+/// Turns received fragments back into streams.
+///
+/// `push` doesn't wait for readers, it buffers up to `STREAM_BUFFER`
+/// fragments per stream. A stream whose reader falls further behind is
+/// dropped, its reader gets an error once it caught up.
+///
+/// Streams that get no fragments for the idle timeout are dropped, as is the
+/// least recently active stream when a new one would exceed the stream limit.
+/// Pass status messages to `status` as well, so that the streams of a peer
+/// that left are dropped right away.
+pub struct Reassembler {
+    streams: HashMap<(PeerId, u64), OpenStream>,
+    idle_timeout: Duration,
+    max_streams: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self {
+            streams: HashMap::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_streams: DEFAULT_MAX_STREAMS,
+        }
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the time a stream may go without fragments before it is dropped.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Sets the number of streams reassembled at the same time.
+    pub fn set_max_streams(&mut self, max: usize) {
+        self.max_streams = max.max(1);
+    }
+
+    /// Number of streams that have not seen their last fragment.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Drops the streams sent by `sender`, e.g. once it left the channel.
+    ///
+    /// Their readers get an error.
+    pub fn remove_sender(&mut self, sender: &PeerId) {
+        self.streams
+            .retain(|(stream_sender, _), _| stream_sender != sender);
+    }
+
+    /// Handles a received status message.
+    ///
+    /// Drops the streams of a peer leaving a channel, or all streams when the
+    /// connection is lost.
+    pub fn status(&mut self, status: &StatusMsg) {
+        match status {
+            StatusMsg::ChannelLeft(channel, peer) => self
+                .streams
+                .retain(|(sender, _), stream| sender != peer || stream.channel != *channel),
+            StatusMsg::Disconnected => self.streams.clear(),
+            _ => {}
+        }
+    }
+
+    /// Drops the streams that got no fragments for the idle timeout at `now`.
+    ///
+    /// Their readers get an error. This is done by `push` too.
+    pub fn evict_idle(&mut self, now: Instant) {
+        let timeout = self.idle_timeout;
+        self.streams
+            .retain(|_, stream| now.saturating_duration_since(stream.last_seen) < timeout);
+    }
+
+    /// Handles a received channel message.
+    ///
+    /// Returns a new `ContentStream` if `msg` is the first fragment of a
+    /// stream. Messages without a `Fragment` header are ignored.
+    pub fn push(&mut self, msg: &ChannelMsg) -> Option<ContentStream> {
+        let fragment = msg.headers().fragment?;
+        let key = (msg.sender().clone(), fragment.stream);
+        self.evict_idle(Instant::now());
+
+        let mut new_stream = None;
+        if fragment.seq == 0 {
+            if !self.streams.contains_key(&key) && self.streams.len() >= self.max_streams {
+                self.evict_oldest();
+            }
+            let (tx, rx) = flume::bounded(STREAM_BUFFER);
+            self.streams.insert(
+                key.clone(),
+                OpenStream {
+                    channel: msg.channel(),
+                    next_seq: 0,
+                    last_seen: Instant::now(),
+                    tx,
+                },
+            );
+            new_stream = Some(ContentStream {
+                sender: msg.sender().clone(),
+                channel: msg.channel(),
+                headers: msg.headers().clone(),
+                rx,
+                chunk: Vec::new(),
+                pos: 0,
+                ended: false,
+            });
+        }
+
+        let stream = match self.streams.get_mut(&key) {
+            Some(stream) => stream,
+            // stream was aborted or started before we joined
+            None => return new_stream,
+        };
+
+        stream.last_seen = Instant::now();
+        let chunk = if fragment.seq == stream.next_seq {
+            stream.next_seq += 1;
+            Ok((msg.content().clone(), fragment.last))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "missing fragment {} of stream {}",
+                    stream.next_seq, fragment.stream
+                ),
+            ))
+        };
+        let failed = chunk.is_err();
+
+        // the reader is gone or too far behind, so drop the stream
+        let sent = stream.tx.try_send(chunk).is_ok();
+        if fragment.last || failed || !sent {
+            self.streams.remove(&key);
+        }
+
+        new_stream
+    }
+
+    /// Drops the stream that went the longest without fragments.
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .streams
+            .iter()
+            .min_by_key(|(_, stream)| stream.last_seen)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.streams.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment_msg(sender: &str, fragment: Fragment, content: &[u8]) -> ChannelMsg {
+        ChannelMsg::new(PeerId::new(sender), ChannelId::default(), content.to_vec())
+            .with_headers(Headers::new().with_fragment(fragment))
+    }
+
+    async fn read_all(stream: &mut ContentStream) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        loop {
+            let (res, buf) = stream.read(Vec::with_capacity(3)).await;
+            match res? {
+                0 => return Ok(content),
+                _ => content.extend_from_slice(&buf),
+            }
+        }
+    }
+
+    #[monoio::test]
+    async fn reassemble() {
+        let mut reassembler = Reassembler::new();
+        let first = Fragment::start();
+        let other = Fragment::start();
+
+        let mut stream = reassembler
+            .push(&fragment_msg("a", first, b"hello "))
+            .unwrap();
+        let mut other_stream = reassembler
+            .push(&fragment_msg("a", other, b"other"))
+            .unwrap();
+        // interleaved with the other stream, and with unfragmented messages
+        let plain = ChannelMsg::new(PeerId::new("a"), ChannelId::default(), b"plain".to_vec());
+        assert!(reassembler.push(&plain).is_none());
+        assert!(reassembler
+            .push(&fragment_msg("a", first.next(), b"world"))
+            .is_none());
+        assert_eq!(reassembler.len(), 2);
+        // the last fragment may be empty
+        reassembler.push(&fragment_msg("a", first.next().next().into_last(), b""));
+        reassembler.push(&fragment_msg("a", other.next().into_last(), b" stream"));
+        assert!(reassembler.is_empty());
+
+        assert_eq!(stream.sender(), &PeerId::new("a"));
+        assert_eq!(stream.headers().fragment, Some(first));
+        assert_eq!(read_all(&mut stream).await.unwrap(), b"hello world");
+        assert_eq!(other_stream.next_chunk().await.unwrap().unwrap(), b"other");
+        assert_eq!(
+            other_stream.next_chunk().await.unwrap().unwrap(),
+            b" stream"
+        );
+        assert!(other_stream.next_chunk().await.is_none());
+    }
+
+    #[monoio::test]
+    async fn missing_fragment() {
+        let mut reassembler = Reassembler::new();
+        let fragment = Fragment::start();
+
+        let mut stream = reassembler
+            .push(&fragment_msg("a", fragment, b"hello"))
+            .unwrap();
+        reassembler.push(&fragment_msg("a", fragment.next().next(), b"world"));
+        assert!(reassembler.is_empty());
+
+        assert_eq!(stream.next_chunk().await.unwrap().unwrap(), b"hello");
+        let err = stream.next_chunk().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(stream.next_chunk().await.is_none());
+
+        // fragments of a stream started before joining are ignored
+        assert!(reassembler
+            .push(&fragment_msg("a", Fragment::start().next(), b"late"))
+            .is_none());
+        assert!(reassembler.is_empty());
+    }
+
+    #[monoio::test]
+    async fn sender_left() {
+        let mut reassembler = Reassembler::new();
+        let mut stream = reassembler
+            .push(&fragment_msg("a", Fragment::start(), b"hello"))
+            .unwrap();
+        let mut other = reassembler
+            .push(&fragment_msg("b", Fragment::start(), b"hello"))
+            .unwrap();
+
+        // other channels and senders are left alone
+        reassembler.status(&StatusMsg::ChannelLeft(
+            ChannelId::default(),
+            PeerId::new("c"),
+        ));
+        assert_eq!(reassembler.len(), 2);
+
+        reassembler.status(&StatusMsg::ChannelLeft(
+            ChannelId::default(),
+            PeerId::new("a"),
+        ));
+        assert_eq!(reassembler.len(), 1);
+        let err = read_all(&mut stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        reassembler.status(&StatusMsg::Disconnected);
+        assert!(reassembler.is_empty());
+        assert!(read_all(&mut other).await.is_err());
+    }
+
+    #[monoio::test]
+    async fn slow_reader() {
+        let mut reassembler = Reassembler::new();
+        let mut fragment = Fragment::start();
+        let mut stream = reassembler
+            .push(&fragment_msg("a", fragment, b"0"))
+            .unwrap();
+        for _ in 1..STREAM_BUFFER {
+            fragment = fragment.next();
+            reassembler.push(&fragment_msg("a", fragment, b"1"));
+        }
+        assert_eq!(reassembler.len(), 1);
+
+        // a fragment not fitting the buffer drops the stream
+        reassembler.push(&fragment_msg("a", fragment.next(), b"2"));
+        assert!(reassembler.is_empty());
+        for _ in 0..STREAM_BUFFER {
+            assert!(stream.next_chunk().await.unwrap().is_ok());
+        }
+        let err = stream.next_chunk().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[monoio::test]
+    async fn eviction() {
+        let mut reassembler = Reassembler::new();
+        reassembler.set_max_streams(2);
+        reassembler.set_idle_timeout(Duration::from_secs(60));
+
+        let mut oldest = reassembler
+            .push(&fragment_msg("a", Fragment::start(), b"1"))
+            .unwrap();
+        let newer = Fragment::start();
+        reassembler.push(&fragment_msg("a", newer, b"2"));
+        // the least recently active stream makes room for a new one
+        reassembler.push(&fragment_msg("b", Fragment::start(), b"3"));
+        assert_eq!(reassembler.len(), 2);
+        assert!(read_all(&mut oldest).await.is_err());
+
+        let now = Instant::now();
+        reassembler.evict_idle(now);
+        assert_eq!(reassembler.len(), 2);
+        reassembler.evict_idle(now + Duration::from_secs(60));
+        assert!(reassembler.is_empty());
+    }
+}
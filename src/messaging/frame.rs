@@ -0,0 +1,90 @@
+use bytes::Bytes;
+
+use super::headers::Priority;
+use super::util::unix_time_ms;
+
+/// Size from which `Frame::detach()` keeps frames as they are, a quarter of
+/// the buffers connections are read into.
+const DETACH_MAX: usize = 16 * 1024;
+
+/// A framed message, as queued for delivery to peers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub data: Bytes,
+    /// Expiry time in milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+    pub priority: Priority,
+    /// Position of the message in its channel's publishing order, set when
+    /// the message gets published.
+    pub seq: Option<u64>,
+}
+
+impl Frame {
+    pub fn new(data: Bytes) -> Self {
+        Self {
+            data,
+            expires: None,
+            priority: Priority::Normal,
+            seq: None,
+        }
+    }
+
+    /// Creates a frame for a server generated status message.
+    ///
+    /// These get delivered with high priority.
+    pub fn status(data: Bytes) -> Self {
+        Self::new(data).with_priority(Priority::High)
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_expiry(mut self, expires: Option<u64>) -> Self {
+        self.expires = expires;
+        self
+    }
+
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// Returns the frame with a copy of its data in a buffer of its own.
+    ///
+    /// Frames read from connections are slices of a shared read buffer, which
+    /// stays allocated as long as any of them is. Frames kept around for
+    /// longer than it takes to deliver them are copied first.
+    ///
+    /// Frames of at least `DETACH_MAX` bytes aren't copied, they make up a
+    /// good part of any buffer they keep allocated.
+    pub fn detach(&self) -> Self {
+        if self.data.len() >= DETACH_MAX {
+            return self.clone();
+        }
+        Self {
+            data: Bytes::copy_from_slice(&self.data),
+            ..self.clone()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= unix_time_ms())
+    }
+}
+
+impl From<Bytes> for Frame {
+    fn from(data: Bytes) -> Self {
+        Frame::new(data)
+    }
+}
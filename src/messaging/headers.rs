@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{BorrowDecode, Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use super::fragment::Fragment;
+use super::util::unix_time_ms;
+
+/// Delivery priority of a message.
+///
+/// Peers' outgoing queues deliver higher priority messages first.
+#[derive(
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Serialize,
+    Deserialize,
+    Encode,
+    Decode,
+)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Channel message headers.
+///
+/// On the wire, the headers are encoded as a length prefixed byte string
+/// containing the fields in order. New fields must be appended at the end.
+/// Decoding stops at the end of the byte string, leaving missing fields at
+/// their default, and ignores trailing fields it doesn't know about.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Headers {
+    pub content_type: Option<String>,
+    pub correlation_id: Option<String>,
+    /// Publishing time in milliseconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+    /// Message key, used by compacted channels.
+    pub key: Option<String>,
+    /// User defined headers.
+    pub extra: BTreeMap<String, String>,
+    /// Expiry time in milliseconds since the Unix epoch.
+    ///
+    /// Expired messages are dropped instead of being delivered.
+    pub expires: Option<u64>,
+    /// Delivery time in milliseconds since the Unix epoch.
+    ///
+    /// The server holds back messages until they are due.
+    pub deliver_at: Option<u64>,
+    pub priority: Priority,
+    /// Set on the fragments of a streamed message.
+    pub fragment: Option<Fragment>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Gets a user defined header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.extra.get(name).map(String::as_str)
+    }
+
+    /// Sets a user defined header, returning the previous value.
+    pub fn insert(&mut self, name: String, value: String) -> Option<String> {
+        self.extra.insert(name, value)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.extra.remove(name)
+    }
+
+    pub fn with_content_type(mut self, content_type: String) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: String) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    /// Sets the timestamp to the current time.
+    pub fn with_timestamp(mut self) -> Self {
+        self.timestamp = Some(unix_time_ms());
+        self
+    }
+
+    pub fn with_key(mut self, key: String) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Lets the message expire `ttl` from now.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expires = Some(unix_time_ms().saturating_add(ttl.as_millis() as u64));
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= unix_time_ms())
+    }
+
+    /// Delays delivery of the message by `delay`.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.deliver_at = Some(unix_time_ms().saturating_add(delay.as_millis() as u64));
+        self
+    }
+
+    /// Sets the delivery time in milliseconds since the Unix epoch.
+    pub fn with_deliver_at(mut self, deliver_at: u64) -> Self {
+        self.deliver_at = Some(deliver_at);
+        self
+    }
+
+    /// Returns `true` if the message should not be delivered yet.
+    pub fn is_deferred(&self) -> bool {
+        self.deliver_at
+            .is_some_and(|deliver_at| deliver_at > unix_time_ms())
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_fragment(mut self, fragment: Fragment) -> Self {
+        self.fragment = Some(fragment);
+        self
+    }
+
+    pub fn with(mut self, name: String, value: String) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    fn encode_fields(&self) -> Result<Vec<u8>, EncodeError> {
+        bincode::encode_to_vec(
+            (
+                &self.content_type,
+                &self.correlation_id,
+                &self.timestamp,
+                &self.key,
+                &self.extra,
+                &self.expires,
+                &self.deliver_at,
+                &self.priority,
+                &self.fragment,
+            ),
+            bincode::config::standard(),
+        )
+    }
+
+    fn decode_fields(mut fields: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Headers {
+            content_type: next_field(&mut fields)?,
+            correlation_id: next_field(&mut fields)?,
+            timestamp: next_field(&mut fields)?,
+            key: next_field(&mut fields)?,
+            extra: next_field(&mut fields)?,
+            expires: next_field(&mut fields)?,
+            deliver_at: next_field(&mut fields)?,
+            priority: next_field(&mut fields)?,
+            fragment: next_field(&mut fields)?,
+        })
+    }
+}
+
+/// Decodes the next header field, or its default if there are no more fields.
+fn next_field<T: Decode<()> + Default>(fields: &mut &[u8]) -> Result<T, DecodeError> {
+    if fields.is_empty() {
+        return Ok(T::default());
+    }
+    let (value, len) = bincode::decode_from_slice(fields, bincode::config::standard())?;
+    *fields = &fields[len..];
+    Ok(value)
+}
+
+impl Encode for Headers {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        Encode::encode(&self.encode_fields()?, encoder)
+    }
+}
+
+impl<Context> Decode<Context> for Headers {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let fields = Vec::<u8>::decode(decoder)?;
+        Headers::decode_fields(&fields)
+    }
+}
+
+impl<'de, Context> BorrowDecode<'de, Context> for Headers {
+    fn borrow_decode<D: bincode::de::BorrowDecoder<'de, Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, DecodeError> {
+        let fields = <&[u8]>::borrow_decode(decoder)?;
+        Headers::decode_fields(fields)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(fields: Vec<u8>) -> Headers {
+        let encoded = bincode::encode_to_vec(fields, bincode::config::standard()).unwrap();
+        let (headers, _) =
+            bincode::decode_from_slice::<Headers, _>(&encoded, bincode::config::standard())
+                .unwrap();
+        headers
+    }
+
+    #[test]
+    fn fields() {
+        let headers = Headers::new()
+            .with_content_type("text/plain".to_string())
+            .with_key("k".to_string())
+            .with("trace".to_string(), "1".to_string())
+            .with_priority(Priority::High);
+        assert_eq!(decode(headers.encode_fields().unwrap()), headers);
+        assert_eq!(decode(Vec::new()), Headers::default());
+    }
+
+    #[test]
+    fn older_fields() {
+        // headers written before the later fields were added
+        let fields = bincode::encode_to_vec(
+            (Some("text/plain"), None::<String>, Some(7u64), Some("k")),
+            bincode::config::standard(),
+        )
+        .unwrap();
+        let headers = decode(fields);
+        assert_eq!(headers.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(headers.timestamp, Some(7));
+        assert_eq!(headers.key.as_deref(), Some("k"));
+        assert!(headers.extra.is_empty());
+        assert_eq!(headers.priority, Priority::Normal);
+        assert_eq!(headers.fragment, None);
+    }
+
+    #[test]
+    fn newer_fields() {
+        // headers written by a version with fields this one doesn't know
+        let headers = Headers::new().with_key("k".to_string()).with_deliver_at(9);
+        let mut fields = headers.encode_fields().unwrap();
+        fields.extend(
+            bincode::encode_to_vec(("unknown", 42u32), bincode::config::standard()).unwrap(),
+        );
+        assert_eq!(decode(fields), headers);
+    }
+}
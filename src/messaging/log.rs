@@ -0,0 +1,556 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use bincode::{Decode, Encode};
+use bytes::Bytes;
+
+use super::channel::ChannelOptions;
+use super::frame::Frame;
+use super::headers::Priority;
+use super::peer::PeerId;
+use super::util::unix_time_ms;
+
+#[derive(Debug)]
+struct LogEntry {
+    seq: u64,
+    key: String,
+    /// `None` marks a deleted key.
+    frame: Option<Frame>,
+}
+
+/// Message log of a compacted channel.
+///
+/// New messages are appended. `compact()` drops all entries that have been
+/// superseded by a later message with the same key, so replaying the log
+/// yields the latest message for every key.
+#[derive(Debug, Default)]
+pub struct ChannelLog {
+    entries: VecDeque<LogEntry>,
+    next_seq: u64,
+    /// Number of entries that `compact()` would drop.
+    superseded: usize,
+    /// Sequence number of the latest entry per key, and whether it's a delete
+    /// marker.
+    latest: HashMap<String, (u64, bool)>,
+}
+
+impl ChannelLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a message. A `frame` of `None` deletes `key`.
+    pub fn append(&mut self, key: String, frame: Option<Frame>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let deleted = frame.is_none();
+        // a superseded delete marker was already counted when it was appended
+        if let Some((_, false)) = self.latest.insert(key.clone(), (seq, deleted)) {
+            self.superseded += 1;
+        }
+        if deleted {
+            // the delete marker itself goes away on the next compaction
+            self.superseded += 1;
+        }
+
+        self.entries.push_back(LogEntry { seq, key, frame });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns `true` if at least half of the entries are superseded.
+    pub fn needs_compaction(&self) -> bool {
+        self.superseded > 0 && self.superseded * 2 >= self.entries.len()
+    }
+
+    /// Drops superseded and expired entries and delete markers.
+    pub fn compact(&mut self) {
+        let latest = &mut self.latest;
+        self.entries.retain(|entry| {
+            if latest.get(&entry.key).map(|(seq, _)| *seq) != Some(entry.seq) {
+                return false;
+            }
+            if entry.frame.as_ref().is_none_or(Frame::is_expired) {
+                latest.remove(&entry.key);
+                return false;
+            }
+            true
+        });
+        self.superseded = 0;
+    }
+
+    /// Iterates over the logged messages in publishing order, skipping
+    /// superseded and expired ones.
+    pub fn replay(&self) -> impl Iterator<Item = &Frame> + '_ {
+        self.entries
+            .iter()
+            .filter(move |entry| {
+                self.latest.get(&entry.key).map(|(seq, _)| *seq) == Some(entry.seq)
+            })
+            .filter_map(|entry| entry.frame.as_ref())
+            .filter(|frame| !frame.is_expired())
+    }
+}
+
+/// A change of the persisted channel logs, as appended to the store file.
+#[derive(Debug, Encode, Decode)]
+enum Record {
+    /// A compacted channel was created or its settings changed.
+    Channel {
+        name: String,
+        owner: Option<PeerId>,
+        options: ChannelOptions,
+    },
+    /// A message was appended to a channel's log. A `frame` of `None`
+    /// deletes `key`.
+    Append {
+        channel: String,
+        key: String,
+        frame: Option<StoredFrame>,
+    },
+    /// A channel and its log were removed, or it's no longer compacted.
+    Remove { channel: String },
+}
+
+/// On-disk representation of a logged `Frame`.
+#[derive(Clone, Debug, Encode, Decode)]
+struct StoredFrame {
+    #[bincode(with_serde)]
+    data: Bytes,
+    expires: Option<u64>,
+    priority: Priority,
+}
+
+impl StoredFrame {
+    fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= unix_time_ms())
+    }
+}
+
+/// A channel log loaded from the store.
+#[derive(Debug)]
+pub struct StoredLog {
+    pub channel: String,
+    pub owner: Option<PeerId>,
+    pub options: ChannelOptions,
+    /// The latest message per key, in publishing order. The frames are
+    /// encoded for the channel id they were published to, which doesn't
+    /// survive restarts.
+    pub entries: Vec<(String, Frame)>,
+}
+
+/// Latest state of a persisted channel, as kept by the store thread.
+#[derive(Debug)]
+struct StoredChannel {
+    owner: Option<PeerId>,
+    options: ChannelOptions,
+    /// Latest message per key, with its position in the log.
+    entries: HashMap<String, (u64, StoredFrame)>,
+}
+
+/// The persisted channel logs, by channel name.
+#[derive(Debug, Default)]
+struct StoreState {
+    channels: HashMap<String, StoredChannel>,
+    next_seq: u64,
+}
+
+impl StoreState {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Channel {
+                name,
+                owner,
+                options,
+            } => {
+                let channel = self.channels.entry(name).or_insert_with(|| StoredChannel {
+                    owner: None,
+                    options: ChannelOptions::default(),
+                    entries: HashMap::new(),
+                });
+                channel.owner = owner;
+                channel.options = options;
+            }
+            Record::Append {
+                channel,
+                key,
+                frame,
+            } => {
+                let channel = match self.channels.get_mut(&channel) {
+                    Some(channel) => channel,
+                    None => return,
+                };
+                match frame {
+                    Some(frame) => {
+                        channel.entries.insert(key, (self.next_seq, frame));
+                        self.next_seq += 1;
+                    }
+                    None => {
+                        channel.entries.remove(&key);
+                    }
+                }
+            }
+            Record::Remove { channel } => {
+                self.channels.remove(&channel);
+            }
+        }
+    }
+
+    /// Number of records a compacted store file holds.
+    fn len(&self) -> usize {
+        self.channels
+            .values()
+            .map(|channel| 1 + channel.entries.len())
+            .sum()
+    }
+
+    /// Returns the records that recreate the current state, skipping
+    /// expired messages.
+    fn records(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for (name, channel) in &self.channels {
+            records.push(Record::Channel {
+                name: name.clone(),
+                owner: channel.owner.clone(),
+                options: channel.options.clone(),
+            });
+            let mut entries: Vec<_> = channel
+                .entries
+                .iter()
+                .filter(|(_, (_, frame))| !frame.is_expired())
+                .collect();
+            entries.sort_by_key(|(_, (seq, _))| *seq);
+            records.extend(entries.into_iter().map(|(key, (_, frame))| Record::Append {
+                channel: name.clone(),
+                key: key.clone(),
+                frame: Some(frame.clone()),
+            }));
+        }
+        records
+    }
+}
+
+/// Persists the logs of compacted channels, so that they survive restarts.
+///
+/// Changes are appended to the store file by a thread of its own, so that
+/// the event loop doesn't wait for the file system. Once the file holds
+/// mostly superseded records, that thread compacts it by writing the latest
+/// state to a new file.
+#[derive(Debug)]
+pub struct LogStore {
+    tx: mpsc::Sender<Record>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+/// Minimum number of records in the store file before it gets compacted.
+const STORE_COMPACT_MIN: usize = 1024;
+
+impl LogStore {
+    /// Opens the store at `path`, creating it if needed, and returns the
+    /// channel logs stored there.
+    pub fn open(path: PathBuf) -> io::Result<(Self, Vec<StoredLog>)> {
+        let mut state = StoreState::default();
+        if path.exists() {
+            let data = fs::read(&path)?;
+            let mut rest = &data[..];
+            while !rest.is_empty() {
+                match bincode::decode_from_slice(rest, bincode::config::standard()) {
+                    Ok((record, len)) => {
+                        state.apply(record);
+                        rest = &rest[len..];
+                    }
+                    // a crash can leave a partly written record behind
+                    Err(e) => {
+                        tracing::warn!("ignoring truncated channel log store: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+
+        // start from a compacted file
+        let mut file = StoreFile::create(path, state)?;
+
+        let logs = file
+            .state
+            .channels
+            .iter()
+            .map(|(name, channel)| {
+                let mut entries: Vec<_> = channel
+                    .entries
+                    .iter()
+                    .filter(|(_, (_, frame))| !frame.is_expired())
+                    .collect();
+                entries.sort_by_key(|(_, (seq, _))| *seq);
+                StoredLog {
+                    channel: name.clone(),
+                    owner: channel.owner.clone(),
+                    options: channel.options.clone(),
+                    entries: entries
+                        .into_iter()
+                        .map(|(key, (_, frame))| {
+                            let data = Frame::new(frame.data.clone())
+                                .with_expiry(frame.expires)
+                                .with_priority(frame.priority);
+                            (key.clone(), data)
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::channel::<Record>();
+        let thread = thread::Builder::new()
+            .name("channel-log-store".to_string())
+            .spawn(move || {
+                let mut res = Ok(());
+                while let Ok(record) = rx.recv() {
+                    res = file.write(std::iter::once(record).chain(rx.try_iter()));
+                    if let Err(e) = &res {
+                        tracing::warn!("error persisting channel logs: {e}");
+                    }
+                }
+                res
+            })?;
+
+        Ok((Self { tx, thread }, logs))
+    }
+
+    /// Returns a handle for recording changes of channel logs.
+    pub fn writer(&self) -> LogWriter {
+        LogWriter(self.tx.clone())
+    }
+
+    /// Waits until the recorded changes are written, returning the result of
+    /// the last write.
+    ///
+    /// All `LogWriter`s have to be dropped first.
+    pub fn close(self) -> io::Result<()> {
+        drop(self.tx);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("log store panicked")))
+    }
+}
+
+/// The store file, and the state it holds.
+struct StoreFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    state: StoreState,
+    /// Number of records in the file.
+    written: usize,
+}
+
+impl StoreFile {
+    /// Writes a compacted store file for `state`.
+    fn create(path: PathBuf, state: StoreState) -> io::Result<Self> {
+        let (file, written) = write_compacted(&path, &state)?;
+        Ok(Self {
+            path,
+            file,
+            state,
+            written,
+        })
+    }
+
+    /// Appends `records`, compacting the file once it holds mostly
+    /// superseded records.
+    fn write(&mut self, records: impl Iterator<Item = Record>) -> io::Result<()> {
+        for record in records {
+            write_record(&mut self.file, &record)?;
+            self.state.apply(record);
+            self.written += 1;
+        }
+        self.file.flush()?;
+
+        if self.written >= STORE_COMPACT_MIN && self.written >= self.state.len() * 2 {
+            let (file, written) = write_compacted(&self.path, &self.state)?;
+            self.file = file;
+            self.written = written;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the store file at `path` by the records of `state`.
+///
+/// Returns the file opened for appending, and the number of records.
+fn write_compacted(path: &Path, state: &StoreState) -> io::Result<(BufWriter<File>, usize)> {
+    // write to a temporary file first so a crash can't lose the store
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    let records = state.records();
+    for record in &records {
+        write_record(&mut file, record)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    let file = BufWriter::new(OpenOptions::new().append(true).open(path)?);
+    Ok((file, records.len()))
+}
+
+fn write_record(file: &mut impl Write, record: &Record) -> io::Result<()> {
+    bincode::encode_into_std_write(record, file, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(())
+}
+
+/// Records changes of channel logs in a `LogStore`.
+///
+/// Changes recorded after the store was closed are dropped.
+#[derive(Clone, Debug)]
+pub struct LogWriter(mpsc::Sender<Record>);
+
+impl LogWriter {
+    /// Records the settings of a compacted channel.
+    pub fn channel(&self, name: &str, owner: Option<&PeerId>, options: &ChannelOptions) {
+        let _ = self.0.send(Record::Channel {
+            name: name.to_string(),
+            owner: owner.cloned(),
+            options: options.clone(),
+        });
+    }
+
+    /// Records a message appended to the log of channel `channel`, see
+    /// `ChannelLog::append()`.
+    pub fn append(&self, channel: &str, key: &str, frame: Option<&Frame>) {
+        let _ = self.0.send(Record::Append {
+            channel: channel.to_string(),
+            key: key.to_string(),
+            frame: frame.map(|frame| StoredFrame {
+                data: frame.data.clone(),
+                expires: frame.expires,
+                priority: frame.priority,
+            }),
+        });
+    }
+
+    /// Records the removal of a channel's log.
+    pub fn remove(&self, channel: &str) {
+        let _ = self.0.send(Record::Remove {
+            channel: channel.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+
+    fn frame(data: &'static str) -> Option<Frame> {
+        Some(Frame::new(Bytes::from(data)))
+    }
+
+    fn replayed(log: &ChannelLog) -> Vec<Bytes> {
+        log.replay().map(|frame| frame.data.clone()).collect()
+    }
+
+    #[test]
+    fn compaction() {
+        let mut log = ChannelLog::new();
+        log.append("a".to_string(), frame("a1"));
+        log.append("b".to_string(), frame("b1"));
+        log.append("a".to_string(), frame("a2"));
+        assert!(!log.needs_compaction());
+        assert_eq!(replayed(&log), vec!["b1", "a2"]);
+
+        log.append("a".to_string(), frame("a3"));
+        assert!(log.needs_compaction());
+        log.compact();
+        assert_eq!(log.len(), 2);
+        assert_eq!(replayed(&log), vec!["b1", "a3"]);
+    }
+
+    #[test]
+    fn delete() {
+        let mut log = ChannelLog::new();
+        for key in ["a", "b", "c", "d"] {
+            log.append(key.to_string(), frame("1"));
+        }
+        log.append("a".to_string(), None);
+        assert_eq!(replayed(&log).len(), 3);
+        log.append("a".to_string(), frame("2"));
+
+        // the old entry of "a" and the delete marker are superseded
+        assert!(!log.needs_compaction());
+        assert_eq!(replayed(&log).len(), 4);
+
+        log.append("b".to_string(), None);
+        assert!(log.needs_compaction());
+        log.compact();
+        assert_eq!(log.len(), 3);
+        assert_eq!(replayed(&log), vec!["1", "1", "2"]);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut log = ChannelLog::new();
+        log.append(
+            "a".to_string(),
+            frame("a1").map(|frame| frame.with_expiry(Some(1))),
+        );
+        log.append("b".to_string(), frame("b1"));
+        assert_eq!(replayed(&log), vec!["b1"]);
+
+        // expired entries are dropped by compaction, even if not superseded
+        log.compact();
+        assert_eq!(log.len(), 1);
+        assert_eq!(replayed(&log), vec!["b1"]);
+    }
+
+    #[test]
+    fn store() {
+        let path = std::env::temp_dir().join(format!("rsq-logs-{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (store, logs) = LogStore::open(path.clone()).unwrap();
+        assert!(logs.is_empty());
+        let writer = store.writer();
+        let options = ChannelOptions {
+            compact: true,
+            ..Default::default()
+        };
+        writer.channel("news", Some(&PeerId::new("owner")), &options);
+        writer.append("news", "a", frame("a1").as_ref());
+        writer.append("news", "b", frame("b1").as_ref());
+        writer.append("news", "a", frame("a2").as_ref());
+        writer.append("news", "b", None);
+        writer.channel("gone", None, &options);
+        writer.append("gone", "a", frame("a1").as_ref());
+        writer.remove("gone");
+        // messages of channels the store doesn't know are ignored
+        writer.append("unknown", "a", frame("a1").as_ref());
+        drop(writer);
+        store.close().unwrap();
+
+        let (store, logs) = LogStore::open(path.clone()).unwrap();
+        store.close().unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].channel, "news");
+        assert_eq!(logs[0].owner, Some(PeerId::new("owner")));
+        assert_eq!(logs[0].options, options);
+        let entries: Vec<_> = logs[0]
+            .entries
+            .iter()
+            .map(|(key, frame)| (key.as_str(), frame.data.clone()))
+            .collect();
+        assert_eq!(entries, vec![("a", Bytes::from("a2"))]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,32 +1,39 @@
+pub mod acl;
 pub mod channel;
 pub mod errors;
+pub mod fragment;
+pub mod frame;
+pub mod headers;
+pub mod log;
 pub mod msg;
+pub mod pattern;
 pub mod peer;
+pub mod queue;
 pub mod router;
-mod util;
+pub mod schedule;
+pub(crate) mod util;
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
-
     use super::channel::*;
+    use super::frame::Frame;
+    use super::headers::Headers;
     use super::msg::*;
     use super::peer::*;
+    use super::queue::peer_queue;
     use super::router::Router;
-    use tokio::sync::mpsc::unbounded_channel;
-    use tokio::sync::mpsc::UnboundedReceiver;
-    use tokio::sync::mpsc::UnboundedSender;
+    use crate::wire;
 
     struct TestPeer {
         id: PeerId,
         num_received: usize,
-        tx: UnboundedSender<Arc<Msg>>,
-        rx: UnboundedReceiver<Arc<Msg>>,
+        tx: PeerTx,
+        rx: PeerRx,
     }
 
     impl TestPeer {
         pub fn new(id: &str) -> TestPeer {
-            let (tx, rx) = unbounded_channel();
+            let (tx, rx) = peer_queue();
             TestPeer {
                 id: PeerId::new(id),
                 num_received: 0,
@@ -36,11 +43,8 @@ mod test {
         }
 
         pub fn poll(&mut self) {
-            loop {
-                match self.rx.try_recv() {
-                    Ok(_) => self.num_received += 1,
-                    _ => return,
-                }
+            while self.rx.try_recv().is_some() {
+                self.num_received += 1;
             }
         }
     }
@@ -49,11 +53,43 @@ mod test {
         fn get_id(&self) -> &PeerId {
             &self.id
         }
-        fn get_sink(&self) -> &UnboundedSender<Arc<Msg>> {
+        fn get_sink(&self) -> &PeerTx {
             &self.tx
         }
     }
 
+    /// Returns the frame of a channel message, with optional headers such as
+    /// a key (see `key()`).
+    fn test_frame(sender: &PeerId, channel: ChannelId, content: &[u8], headers: Headers) -> Frame {
+        let msg = ChannelMsg::new(sender.clone(), channel, content.to_vec()).with_headers(headers);
+        Frame::new(wire::frame(&Msg::ChannelMsg(msg)))
+    }
+
+    /// Headers of a message with `key`.
+    fn key(key: &str) -> Headers {
+        Headers::new().with_key(key.to_string())
+    }
+
+    /// Takes the content of the channel messages queued for `peer`.
+    fn contents(peer: &mut TestPeer) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| peer.rx.try_recv())
+            .filter_map(|frame| match Msg::decode_frame(&frame.data) {
+                Ok(Msg::ChannelMsg(msg)) => Some(msg.content().clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Takes the status messages queued for `peer`, dropping other messages.
+    fn statuses(peer: &mut TestPeer) -> Vec<StatusMsg> {
+        std::iter::from_fn(|| peer.rx.try_recv())
+            .filter_map(|frame| match Msg::decode_frame(&frame.data) {
+                Ok(Msg::StatusMsg(status)) => Some(status),
+                _ => None,
+            })
+            .collect()
+    }
+
     #[test]
     fn basic() {
         let peerid = "test_peer";
@@ -63,48 +99,776 @@ mod test {
 
     #[test]
     fn peer_send() {
-        let channel = Channel::new(ChannelId("test_channel".to_string()));
+        let channel = Channel::new("test_channel".to_string(), ChannelId::default());
         let mut peer = TestPeer::new("test_peer");
-        let msg = Arc::new(Msg::new_channel_msg(
-            peer.get_id().clone(),
-            channel.get_id().clone(),
-            bytes::Bytes::from("test_data"),
-        ));
+        let frame = test_frame(
+            peer.get_id(),
+            channel.get_id(),
+            b"test_data",
+            Headers::new(),
+        );
         assert_eq!(peer.num_received, 0);
-        peer.get_sink().send(msg).unwrap();
+        peer.get_sink().send(frame).unwrap();
         peer.poll();
         assert_eq!(peer.num_received, 1);
     }
 
     #[test]
     fn channel_send() {
-        let mut channel = Channel::new(ChannelId("test_channel".to_string()));
+        let mut channel = Channel::new("test_channel".to_string(), ChannelId::default());
         let mut peer = TestPeer::new("test_peer");
         let mut peer2 = TestPeer::new("test_peer2");
-        let msg = Msg::new_channel_msg(
-            peer.get_id().clone(),
-            channel.get_id().clone(),
-            bytes::Bytes::from("test_data"),
+        let frame = test_frame(
+            peer.get_id(),
+            channel.get_id(),
+            b"test_data",
+            Headers::new(),
         );
         channel.subscribe(&peer);
         channel.subscribe(&peer2);
 
         assert_eq!(peer.num_received, 0);
         assert_eq!(peer2.num_received, 0);
-        channel.forward(Arc::new(msg), peer.get_id());
+        channel.forward(frame, peer.get_id());
         peer.poll();
         peer2.poll();
         assert_eq!(peer.num_received, 0);
         assert_eq!(peer2.num_received, 1);
     }
 
+    #[test]
+    fn channel_retain() {
+        let mut channel = Channel::new("test_channel".to_string(), ChannelId::default());
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        let mut peer2 = TestPeer::new("test_peer3");
+
+        // without the option, nothing is retained
+        channel.forward(
+            test_frame(sender.get_id(), channel.get_id(), b"a", Headers::new()),
+            sender.get_id(),
+        );
+        assert!(channel.retained().is_none());
+
+        channel.set_options(ChannelOptions {
+            retain: true,
+            ..Default::default()
+        });
+        for content in [b"b", b"c"] {
+            let frame = test_frame(sender.get_id(), channel.get_id(), content, Headers::new());
+            channel.forward(frame, sender.get_id());
+        }
+
+        // new subscribers get the last message right away
+        channel.subscribe(&peer);
+        assert_eq!(contents(&mut peer), vec![b"c".to_vec()]);
+        channel.subscribe(&peer);
+        assert!(contents(&mut peer).is_empty());
+
+        // a message without content clears it
+        let frame = test_frame(sender.get_id(), channel.get_id(), b"", Headers::new());
+        channel.forward(frame, sender.get_id());
+        assert_eq!(contents(&mut peer), vec![b"".to_vec()]);
+        assert!(channel.retained().is_none());
+        channel.subscribe(&peer2);
+        assert!(contents(&mut peer2).is_empty());
+    }
+
+    #[test]
+    fn channel_retain_message() {
+        let mut channel = Channel::new("test_channel".to_string(), ChannelId::default());
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+
+        // single messages are retained without turning on the option
+        let frame = test_frame(sender.get_id(), channel.get_id(), b"a", Headers::new());
+        channel.forward_retained(frame, sender.get_id());
+        let frame = test_frame(sender.get_id(), channel.get_id(), b"b", Headers::new());
+        channel.forward(frame, sender.get_id());
+        assert!(!channel.options().retain);
+        channel.subscribe(&peer);
+        assert_eq!(contents(&mut peer), vec![b"a".to_vec()]);
+
+        // changing other options keeps it
+        channel.set_options(ChannelOptions {
+            description: Some("test".to_string()),
+            ..Default::default()
+        });
+        assert!(channel.retained().is_some());
+
+        // an empty retained message clears it
+        let frame = test_frame(sender.get_id(), channel.get_id(), b"", Headers::new());
+        channel.forward_retained(frame, sender.get_id());
+        assert!(channel.retained().is_none());
+    }
+
+    #[test]
+    fn router_compact() {
+        use std::time::Instant;
+
+        let mut router = Router::new();
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        let mut peer2 = TestPeer::new("test_peer3");
+        let channel = router.channel_create("test_channel".to_string(), sender.get_id());
+        let options = ChannelOptions {
+            compact: true,
+            ..Default::default()
+        };
+        router.channel_configure(channel, options, &sender).unwrap();
+
+        let frames = [
+            test_frame(sender.get_id(), channel, b"a1", key("a")),
+            test_frame(sender.get_id(), channel, b"b1", key("b")),
+            test_frame(sender.get_id(), channel, b"a2", key("a")),
+            // messages without key aren't logged
+            test_frame(sender.get_id(), channel, b"unkeyed", Headers::new()),
+            test_frame(sender.get_id(), channel, b"c1", key("c")),
+            // empty messages delete their key
+            test_frame(sender.get_id(), channel, b"", key("b")),
+        ];
+        for frame in frames {
+            router.forward(frame, channel, sender.get_id()).unwrap();
+        }
+
+        // the latest message of every key, in publishing order
+        router.attach(channel, &peer).unwrap();
+        assert_eq!(contents(&mut peer), vec![b"a2".to_vec(), b"c1".to_vec()]);
+
+        // compacting doesn't change what is replayed
+        router.gc(Instant::now());
+        router.attach(channel, &peer2).unwrap();
+        assert_eq!(contents(&mut peer2), vec![b"a2".to_vec(), b"c1".to_vec()]);
+    }
+
+    #[test]
+    fn channel_expiry() {
+        use super::util::unix_time_ms;
+        use std::time::Duration;
+
+        let mut channel = Channel::new("test_channel".to_string(), ChannelId::default());
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        channel.subscribe(&peer);
+
+        assert!(!Headers::new()
+            .with_ttl(Duration::from_secs(60))
+            .is_expired());
+        assert!(Headers::new().with_ttl(Duration::ZERO).is_expired());
+
+        // expired messages are dropped without being counted
+        let frame = test_frame(
+            sender.get_id(),
+            channel.get_id(),
+            b"test_data",
+            Headers::new(),
+        )
+        .with_expiry(Some(1));
+        assert_eq!(channel.forward(frame, sender.get_id()), 0);
+        assert!(contents(&mut peer).is_empty());
+        assert_eq!(channel.info().messages, 0);
+
+        // retained and logged messages expire while kept
+        channel.set_options(ChannelOptions {
+            retain: true,
+            compact: true,
+            ..Default::default()
+        });
+        // with plenty of time to forward the message before it expires
+        let expires = Some(unix_time_ms() + 500);
+        let frame =
+            test_frame(sender.get_id(), channel.get_id(), b"a1", key("a")).with_expiry(expires);
+        assert_eq!(channel.forward(frame, sender.get_id()), 1);
+        assert_eq!(contents(&mut peer), vec![b"a1".to_vec()]);
+        let mut peer2 = TestPeer::new("test_peer3");
+        channel.subscribe(&peer2);
+        assert_eq!(contents(&mut peer2), vec![b"a1".to_vec()]);
+
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(channel.retained().is_none());
+        let mut peer3 = TestPeer::new("test_peer4");
+        channel.subscribe(&peer3);
+        assert!(contents(&mut peer3).is_empty());
+    }
+
     #[test]
     fn router_basic() {
-        let router = Router::new();
-        let peer = Box::new(TestPeer::new("test_peer"));
-        let peer2 = Box::new(TestPeer::new("test_peer2"));
-        let mut channel = Channel::new(ChannelId("test_channel".to_string()));
-        channel.subscribe(peer.as_ref());
-        channel.subscribe(peer2.as_ref());
+        let mut router = Router::new();
+        let mut peer = TestPeer::new("test_peer");
+        let mut peer2 = TestPeer::new("test_peer2");
+        let channel = router.channel_get_or_add("test_channel".to_string());
+        router.attach(channel, &peer).unwrap();
+        router.attach(channel, &peer2).unwrap();
+
+        // drop the join notifications
+        peer.poll();
+        peer2.poll();
+        peer.num_received = 0;
+        peer2.num_received = 0;
+
+        let frame = test_frame(peer.get_id(), channel, b"test_data", Headers::new());
+        assert_eq!(router.forward(frame, channel, peer.get_id()).unwrap(), 1);
+        peer.poll();
+        peer2.poll();
+        assert_eq!(peer.num_received, 0);
+        assert_eq!(peer2.num_received, 1);
+    }
+
+    #[test]
+    fn router_presence() {
+        use super::errors::TxError;
+
+        let mut router = Router::new();
+        let mut peer = TestPeer::new("test_peer");
+        let mut peer2 = TestPeer::new("test_peer2");
+        let outsider = TestPeer::new("test_peer3");
+        let channel = router.channel_get_or_add("test_channel".to_string());
+        router.attach(channel, &peer).unwrap();
+        router.attach(channel, &peer2).unwrap();
+
+        // peers aren't told about their own joining
+        assert_eq!(
+            statuses(&mut peer),
+            vec![StatusMsg::ChannelJoined(channel, peer2.get_id().clone())]
+        );
+        assert!(statuses(&mut peer2).is_empty());
+
+        // subscribing again doesn't notify anyone
+        router.attach(channel, &peer2).unwrap();
+        assert!(statuses(&mut peer).is_empty());
+
+        let members = router.members(channel, &peer2).unwrap();
+        assert_eq!(members.len(), 2);
+        assert!(members.contains(peer.get_id()) && members.contains(peer2.get_id()));
+        // only subscribers may list the members
+        assert!(matches!(
+            router.members(channel, &outsider),
+            Err(TxError::NotSubscribed)
+        ));
+
+        router.detach(channel, &peer2).unwrap();
+        assert_eq!(
+            statuses(&mut peer),
+            vec![StatusMsg::ChannelLeft(channel, peer2.get_id().clone())]
+        );
+
+        // disconnecting leaves all channels
+        let channel = router.channel_get_or_add("test_channel2".to_string());
+        router.attach(channel, &peer).unwrap();
+        router.peer_add(&peer2);
+        router.attach(channel, &peer2).unwrap();
+        router.peer_remove(peer2.get_id());
+        assert_eq!(
+            statuses(&mut peer),
+            vec![
+                StatusMsg::ChannelJoined(channel, peer2.get_id().clone()),
+                StatusMsg::ChannelLeft(channel, peer2.get_id().clone())
+            ]
+        );
+        assert_eq!(
+            router.members(channel, &peer).unwrap(),
+            vec![peer.get_id().clone()]
+        );
+
+        // removing a peer again, or one never added, is ignored
+        router.peer_remove(peer2.get_id());
+        router.peer_remove(outsider.get_id());
+        assert!(statuses(&mut peer).is_empty());
+
+        // quiet peers come and go unannounced
+        router.peer_add_quiet(&outsider);
+        router.attach(channel, &outsider).unwrap();
+        router.peer_remove(outsider.get_id());
+        assert!(statuses(&mut peer).is_empty());
+    }
+
+    #[test]
+    fn router_listing() {
+        let mut router = Router::new();
+        let sender = TestPeer::new("sender");
+        let peer = TestPeer::new("reader");
+        let peer2 = TestPeer::new("reader2");
+        for name in ["news.weather", "chat", "news.sports"] {
+            router.channel_get_or_add(name.to_string());
+        }
+
+        let names = |list: Vec<(String, ChannelId)>| -> Vec<String> {
+            list.into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(
+            names(router.channel_list(None)),
+            vec!["chat", "news.sports", "news.weather"]
+        );
+        assert_eq!(
+            names(router.channel_list(Some("news."))),
+            vec!["news.sports", "news.weather"]
+        );
+        assert!(router.channel_list(Some("sports")).is_empty());
+
+        let (_, channel) = router.channel_list(Some("chat"))[0];
+        router.attach(channel, &peer).unwrap();
+        router.attach(channel, &peer2).unwrap();
+        let frame = test_frame(sender.get_id(), channel, b"test_data", Headers::new());
+        let len = frame.len() as u64;
+        for _ in 0..2 {
+            router
+                .forward(frame.clone(), channel, sender.get_id())
+                .unwrap();
+        }
+        // a subscriber's own messages aren't delivered back to it
+        router.forward(frame, channel, peer.get_id()).unwrap();
+
+        // presence notifications aren't counted
+        let info = router.channel_info(channel).unwrap();
+        assert_eq!(info.id, channel);
+        assert_eq!(info.name, "chat");
+        assert_eq!(info.owner, None);
+        assert_eq!(info.subscribers, 2);
+        assert_eq!(info.messages, 3);
+        assert_eq!(info.bytes, 3 * len);
+        assert_eq!(info.deliveries, 5);
+
+        assert!(router.channel_info(ChannelId::default()).is_err());
+    }
+
+    #[test]
+    fn router_owner() {
+        use super::errors::TxError;
+
+        let mut router = Router::new();
+        let owner = TestPeer::new("owner");
+        let other = TestPeer::new("other");
+
+        // channels created by publishing or subscribing have no owner
+        let channel = router.channel_get_or_add("news".to_string());
+        assert_eq!(router.channel_info(channel).unwrap().owner, None);
+        assert!(matches!(
+            router.channel_configure(channel, ChannelOptions::default(), &owner),
+            Err(TxError::NotOwner)
+        ));
+
+        // creating one explicitly claims it, once
+        assert_eq!(
+            router.channel_create("news".to_string(), owner.get_id()),
+            channel
+        );
+        assert_eq!(
+            router.channel_create("news".to_string(), other.get_id()),
+            channel
+        );
+        let info = router.channel_info(channel).unwrap();
+        assert_eq!(info.owner.as_ref(), Some(owner.get_id()));
+        router
+            .channel_configure(channel, ChannelOptions::default(), &owner)
+            .unwrap();
+        assert!(matches!(
+            router.channel_configure(channel, ChannelOptions::default(), &other),
+            Err(TxError::NotOwner)
+        ));
+    }
+
+    #[test]
+    fn router_lifecycle() {
+        use super::errors::TxError;
+        use std::time::{Duration, Instant};
+
+        let mut router = Router::new();
+        let owner = TestPeer::new("owner");
+        let mut peer = TestPeer::new("reader");
+
+        // without linger time, channels go away with their last subscriber
+        let channel = router.channel_get_or_add("news".to_string());
+        router.attach(channel, &peer).unwrap();
+        router.detach(channel, &peer).unwrap();
+        assert!(router.channel_info(channel).is_err());
+        assert!(router.channel_list(None).is_empty());
+
+        // unless they are persistent
+        let channel = router.channel_create("news".to_string(), owner.get_id());
+        let options = ChannelOptions {
+            persistent: true,
+            ..Default::default()
+        };
+        router.channel_configure(channel, options, &owner).unwrap();
+        router.attach(channel, &peer).unwrap();
+        router.detach(channel, &peer).unwrap();
+        assert_eq!(router.gc(Instant::now()), 0);
+        assert!(router.channel_info(channel).is_ok());
+
+        // only the owner may delete a channel, subscribers are told
+        router.attach(channel, &peer).unwrap();
+        assert!(matches!(
+            router.channel_delete(channel, &peer),
+            Err(TxError::NotOwner)
+        ));
+        router.channel_delete(channel, &owner).unwrap();
+        assert!(router.channel_info(channel).is_err());
+        assert_eq!(
+            statuses(&mut peer),
+            vec![StatusMsg::ChannelDeleted(channel)]
+        );
+
+        // with linger time, empty channels are removed by gc()
+        let linger = Duration::from_secs(10);
+        router.set_linger(linger);
+        let channel = router.channel_get_or_add("chat".to_string());
+        router.attach(channel, &peer).unwrap();
+        router.detach(channel, &peer).unwrap();
+        assert_eq!(router.gc(Instant::now()), 0);
+        assert!(router.channel_info(channel).is_ok());
+
+        // subscribing again resets the linger time
+        router.attach(channel, &peer).unwrap();
+        assert_eq!(router.gc(Instant::now() + linger), 0);
+        router.detach(channel, &peer).unwrap();
+        let detached = Instant::now();
+        assert_eq!(router.gc(detached + linger / 2), 0);
+        assert_eq!(router.gc(detached + linger), 1);
+        assert!(router.channel_info(channel).is_err());
+
+        // the name can be used again, for a new channel
+        let recreated = router.channel_get_or_add("chat".to_string());
+        assert_ne!(recreated, channel);
+    }
+
+    #[test]
+    fn router_schedule() {
+        use super::util::unix_time_ms;
+
+        let mut router = Router::new();
+        let sender = TestPeer::new("sender");
+        let mut peer = TestPeer::new("reader");
+        let channel = router.channel_get_or_add("news".to_string());
+        router.attach(channel, &peer).unwrap();
+
+        let later = test_frame(sender.get_id(), channel, b"later", Headers::new());
+        let now = test_frame(sender.get_id(), channel, b"now", Headers::new());
+        router
+            .schedule(later, channel, sender.get_id(), unix_time_ms() + 60_000)
+            .unwrap();
+        router
+            .schedule(now, channel, sender.get_id(), unix_time_ms())
+            .unwrap();
+        // held back until delivered
+        assert!(contents(&mut peer).is_empty());
+
+        assert_eq!(router.deliver_due(), 1);
+        assert_eq!(contents(&mut peer), vec![b"now".to_vec()]);
+        assert_eq!(router.deliver_due(), 0);
+        assert_eq!(router.channel_info(channel).unwrap().messages, 1);
+    }
+
+    #[test]
+    fn router_resume() {
+        let mut router = Router::new();
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        let channel = router.channel_create("test_channel".to_string(), sender.get_id());
+        let options = ChannelOptions {
+            compact: true,
+            ..Default::default()
+        };
+        router.channel_configure(channel, options, &sender).unwrap();
+
+        for name in ["a", "b", "c"] {
+            let frame = test_frame(sender.get_id(), channel, b"test_data", key(name));
+            router.forward(frame, channel, sender.get_id()).unwrap();
+        }
+
+        // messages 1 and 2 haven't been seen
+        router.attach_from(channel, &peer, Some(1)).unwrap();
+        peer.poll();
+        assert_eq!(peer.num_received, 2);
+    }
+
+    #[test]
+    fn router_log_store() {
+        let path = std::env::temp_dir().join(format!("rsq-router-logs-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut router = Router::new();
+        router.set_log_store(path.clone()).unwrap();
+        let sender = TestPeer::new("test_peer");
+        let channel = router.channel_create("test_channel".to_string(), sender.get_id());
+        let options = ChannelOptions {
+            persistent: true,
+            compact: true,
+            ..Default::default()
+        };
+        router
+            .channel_configure(channel, options.clone(), &sender)
+            .unwrap();
+        for (name, content) in [("a", b"a1"), ("b", b"b1"), ("a", b"a2")] {
+            let frame = test_frame(sender.get_id(), channel, content, key(name));
+            router.forward(frame, channel, sender.get_id()).unwrap();
+        }
+        router.persist().unwrap();
+
+        // the channel comes back with its options, owner and log
+        let mut restored = Router::new();
+        restored.set_log_store(path.clone()).unwrap();
+        let (_, channel) = restored.channel_list(None).pop().unwrap();
+        let info = restored.channel_info(channel).unwrap();
+        assert_eq!(info.options, options);
+        assert_eq!(info.owner.as_ref(), Some(sender.get_id()));
+        let mut peer = TestPeer::new("test_peer2");
+        restored.attach(channel, &peer).unwrap();
+        let channels: Vec<_> = std::iter::from_fn(|| peer.rx.try_recv())
+            .filter_map(|frame| match Msg::decode_frame(&frame.data) {
+                Ok(Msg::ChannelMsg(msg)) => Some((msg.channel(), msg.content().clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            channels,
+            vec![(channel, b"b1".to_vec()), (channel, b"a2".to_vec())]
+        );
+        restored.persist().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn router_retain_compact() {
+        let mut router = Router::new();
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        let channel = router.channel_create("test_channel".to_string(), sender.get_id());
+        let options = ChannelOptions {
+            retain: true,
+            compact: true,
+            ..Default::default()
+        };
+        router.channel_configure(channel, options, &sender).unwrap();
+
+        for name in ["a", "b"] {
+            let frame = test_frame(sender.get_id(), channel, b"test_data", key(name));
+            router.forward(frame, channel, sender.get_id()).unwrap();
+        }
+
+        // the retained message is the last logged one, and is sent once
+        router.attach(channel, &peer).unwrap();
+        peer.poll();
+        assert_eq!(peer.num_received, 2);
+    }
+
+    #[test]
+    fn router_history() {
+        let mut router = Router::new();
+        router.set_history_len(2);
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        let mut resumed = TestPeer::new("test_peer3");
+        let channel = router.channel_get_or_add("test_channel".to_string());
+
+        for _ in 0..3 {
+            let frame = test_frame(sender.get_id(), channel, b"test_data", Headers::new());
+            router.forward(frame, channel, sender.get_id()).unwrap();
+        }
+
+        // the history is only replayed when resuming
+        router.attach(channel, &peer).unwrap();
+        peer.poll();
+        assert_eq!(peer.num_received, 0);
+
+        router.attach_from(channel, &resumed, Some(0)).unwrap();
+        let seqs: Vec<_> = std::iter::from_fn(|| resumed.rx.try_recv())
+            .map(|frame| frame.seq)
+            .collect();
+        assert_eq!(seqs, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn router_pattern() {
+        let mut router = Router::new();
+        let sender = TestPeer::new("test_peer");
+        let mut peer = TestPeer::new("test_peer2");
+        router.peer_add(&peer);
+
+        let before = router.channel_get_or_add("news.sports".to_string());
+        assert_eq!(
+            router.attach_pattern("news.*".to_string(), &peer),
+            vec![before]
+        );
+        let after = router.channel_get_or_add("news.weather".to_string());
+        let other = router.channel_get_or_add("sports".to_string());
+
+        for channel in [before, after, other] {
+            let frame = test_frame(sender.get_id(), channel, b"test_data", Headers::new());
+            router.forward(frame, channel, sender.get_id()).unwrap();
+        }
+        peer.poll();
+        assert_eq!(peer.num_received, 2);
+
+        router.detach_pattern("news.*", &peer);
+        router.channel_get_or_add("news.tech".to_string());
+        assert_eq!(router.channel_info(after).unwrap().subscribers, 1);
+        let tech = router.channel_get_or_add("news.tech".to_string());
+        assert_eq!(router.channel_info(tech).unwrap().subscribers, 0);
+    }
+
+    #[test]
+    fn router_pattern_presence() {
+        let mut router = Router::new();
+        let mut peer = TestPeer::new("test_peer");
+        let mut peer2 = TestPeer::new("test_peer2");
+        router.peer_add(&peer);
+        router.peer_add(&peer2);
+        router.attach_pattern("news.*".to_string(), &peer);
+        router.attach_pattern("news.*".to_string(), &peer2);
+
+        // the peer subscribed second is announced to the first
+        let channel = router.channel_get_or_add("news.sports".to_string());
+        let mut joined = statuses(&mut peer);
+        joined.extend(statuses(&mut peer2));
+        assert_eq!(joined.len(), 1);
+        assert!(matches!(
+            &joined[0],
+            StatusMsg::ChannelJoined(id, _) if *id == channel
+        ));
+    }
+
+    #[test]
+    fn router_acl() {
+        use super::acl::{Acl, AclRule};
+        use super::errors::TxError;
+
+        let mut router = Router::new();
+        let sender = TestPeer::new("sender");
+        let mut peer = TestPeer::new("reader");
+        router.peer_add(&peer);
+        router.set_acl(Acl::new(vec![AclRule {
+            peers: "reader".to_string(),
+            publish: Vec::new(),
+            subscribe: vec!["public.*".to_string()],
+        }]));
+
+        let public = router.channel_get_or_add("public.news".to_string());
+        let private = router.channel_get_or_add("private".to_string());
+        assert!(router.attach(private, &peer).is_err());
+        assert_eq!(router.attach_pattern("*".to_string(), &peer), vec![public]);
+
+        // pattern subscriptions only pick up permitted channels
+        let later = router.channel_get_or_add("private.later".to_string());
+        assert_eq!(router.channel_info(later).unwrap().subscribers, 0);
+
+        let frame = test_frame(peer.get_id(), public, b"test_data", Headers::new());
+        assert!(matches!(
+            router.forward(frame, public, peer.get_id()),
+            Err(TxError::NotPermitted)
+        ));
+        let frame = test_frame(sender.get_id(), public, b"test_data", Headers::new());
+        assert_eq!(router.forward(frame, public, sender.get_id()).unwrap(), 1);
+        peer.poll();
+        assert_eq!(peer.num_received, 1);
+    }
+
+    #[test]
+    fn router_acl_reload() {
+        use super::acl::{Acl, AclRule};
+
+        let mut router = Router::new();
+        let mut peer = TestPeer::new("reader");
+        router.peer_add(&peer);
+        let channel = router.channel_get_or_add("private".to_string());
+        router.attach(channel, &peer).unwrap();
+        peer.poll();
+
+        router.set_acl(Acl::new(vec![AclRule {
+            peers: "reader".to_string(),
+            publish: Vec::new(),
+            subscribe: vec!["public.*".to_string()],
+        }]));
+        // the channel has no subscribers left, and no linger time
+        assert!(router.channel_info(channel).is_err());
+        let frame = peer.rx.try_recv().unwrap();
+        assert_eq!(
+            Msg::decode_frame(&frame.data).unwrap(),
+            Msg::new_status(StatusMsg::ChannelLeft(channel, peer.get_id().clone()))
+        );
+    }
+
+    #[test]
+    fn router_channel_defaults() {
+        let mut router = Router::new();
+        let options = ChannelOptions {
+            retain: true,
+            ..Default::default()
+        };
+        router.set_channel_defaults(vec![("config.*".to_string(), options.clone())]);
+
+        let config = router.channel_get_or_add("config.app".to_string());
+        let other = router.channel_get_or_add("app".to_string());
+        assert_eq!(router.channel_info(config).unwrap().options, options);
+        assert_eq!(
+            router.channel_info(other).unwrap().options,
+            ChannelOptions::default()
+        );
+    }
+
+    #[test]
+    fn router_schedule_removed_channel() {
+        let mut router = Router::new();
+        let sender = TestPeer::new("sender");
+        let mut peer = TestPeer::new("reader");
+        router.peer_add(&peer);
+        router.attach_pattern("news".to_string(), &peer);
+
+        let channel = router.channel_get_or_add("news".to_string());
+        let frame = test_frame(sender.get_id(), channel, b"test_data", Headers::new());
+        router.schedule(frame, channel, sender.get_id(), 0).unwrap();
+        router.detach(channel, &peer).unwrap();
+        assert!(router.channel_info(channel).is_err());
+
+        assert_eq!(router.deliver_due(), 1);
+        let (_, channel) = router.channel_list(Some("news"))[0];
+        assert_eq!(router.channel_info(channel).unwrap().messages, 1);
+        peer.poll();
+        assert_eq!(peer.num_received, 1);
+    }
+
+    #[test]
+    fn router_close() {
+        use super::errors::TxError;
+
+        let mut router = Router::new();
+        let sender = TestPeer::new("sender");
+        let channel = router.channel_get_or_add("news".to_string());
+        let frame = test_frame(sender.get_id(), channel, b"test_data", Headers::new());
+        router
+            .schedule(frame.clone(), channel, sender.get_id(), 0)
+            .unwrap();
+
+        router.close();
+        assert!(matches!(
+            router.forward(frame.clone(), channel, sender.get_id()),
+            Err(TxError::ShuttingDown)
+        ));
+        assert!(matches!(
+            router.schedule(frame, channel, sender.get_id(), 0),
+            Err(TxError::ShuttingDown)
+        ));
+        assert_eq!(router.deliver_due(), 0);
+    }
+
+    #[test]
+    fn router_notify_peers() {
+        let mut router = Router::new();
+        let mut peer1 = TestPeer::new("peer1");
+        let mut peer2 = TestPeer::new("peer2");
+        router.peer_add(&peer1);
+        router.peer_add(&peer2);
+
+        router.notify_peers(StatusMsg::ShuttingDown);
+        assert_eq!(router.queued(), 2);
+
+        let frame = peer1.rx.try_recv().unwrap();
+        assert_eq!(
+            Msg::decode_frame(&frame.data).unwrap(),
+            Msg::new_status(StatusMsg::ShuttingDown)
+        );
+        assert_eq!(router.queued(), 1);
+        peer2.poll();
+        assert_eq!(router.queued(), 0);
     }
 }
@@ -1,7 +1,11 @@
-use super::channel::ChannelId;
+use super::channel::{ChannelId, ChannelInfo, ChannelOptions};
+use super::headers::Headers;
 use super::peer::PeerId;
 use super::util::hash;
+use crate::compression::Compression;
+use crate::wire;
 
+use bincode::error::DecodeError;
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 
@@ -18,15 +22,15 @@ impl Decode<bool> for Msg {
     ) -> Result<Self, bincode::error::DecodeError> {
         let variant_index = <u32 as ::bincode::Decode<bool>>::decode(decoder)?;
         match variant_index {
-            0u32 => core::result::Result::Ok(Self::ChannelMsg {
-                0: ::bincode::Decode::<bool>::decode(decoder)?,
-            }),
-            1u32 => core::result::Result::Ok(Self::ControlMsg {
-                0: ::bincode::Decode::<bool>::decode(decoder)?,
-            }),
-            2u32 => core::result::Result::Ok(Self::StatusMsg {
-                0: ::bincode::Decode::<bool>::decode(decoder)?,
-            }),
+            0u32 => core::result::Result::Ok(Self::ChannelMsg(::bincode::Decode::<bool>::decode(
+                decoder,
+            )?)),
+            1u32 => core::result::Result::Ok(Self::ControlMsg(::bincode::Decode::<bool>::decode(
+                decoder,
+            )?)),
+            2u32 => core::result::Result::Ok(Self::StatusMsg(::bincode::Decode::<bool>::decode(
+                decoder,
+            )?)),
             variant => {
                 core::result::Result::Err(::bincode::error::DecodeError::UnexpectedVariant {
                     found: variant,
@@ -42,6 +46,7 @@ impl Decode<bool> for Msg {
 pub struct ChannelMsg {
     sender: PeerId,
     channel: ChannelId,
+    headers: Headers,
     content: Vec<u8>,
 }
 
@@ -53,6 +58,7 @@ impl Decode<bool> for ChannelMsg {
             let res = ChannelMsg {
                 sender: ::bincode::Decode::<bool>::decode(decoder)?,
                 channel: ::bincode::Decode::<bool>::decode(decoder)?,
+                headers: ::bincode::Decode::<bool>::decode(decoder)?,
                 content: vec![],
             };
             Ok(res)
@@ -60,6 +66,7 @@ impl Decode<bool> for ChannelMsg {
             let res = ChannelMsg {
                 sender: ::bincode::Decode::<bool>::decode(decoder)?,
                 channel: ::bincode::Decode::<bool>::decode(decoder)?,
+                headers: ::bincode::Decode::<bool>::decode(decoder)?,
                 content: ::bincode::Decode::<bool>::decode(decoder)?,
             };
             Ok(res)
@@ -71,13 +78,25 @@ impl Decode<bool> for ChannelMsg {
 pub struct ChannelMsgHdr {
     sender: PeerId,
     channel: ChannelId,
+    headers: Headers,
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize, Encode, Decode)]
 pub enum ControlMsg {
     ChannelJoin(String),
+    /// Looks up a channel by name, creating it if needed. The sender
+    /// becomes the channel owner, if it has none yet.
     ChannelCreate(String),
     ChannelLeave(ChannelId),
+    ChannelMembers(ChannelId),
+    ChannelList(Option<String>),
+    ChannelInfo(ChannelId),
+    ChannelConfigure(ChannelId, ChannelOptions),
+    ChannelDelete(ChannelId),
+    Hello(Hello),
+    /// Sends the token required by the listener. Until then, only `Hello`
+    /// is accepted. A wrong token closes the connection.
+    Auth(String),
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize, Encode, Decode)]
@@ -86,6 +105,31 @@ pub enum StatusMsg {
     Connected,
     Disconnected,
     ChannelId(String, ChannelId),
+    ChannelJoined(ChannelId, PeerId),
+    ChannelLeft(ChannelId, PeerId),
+    ChannelMembers(ChannelId, Vec<PeerId>),
+    ChannelList(Vec<(String, ChannelId)>),
+    ChannelInfo(ChannelInfo),
+    ChannelDeleted(ChannelId),
+    Error(String),
+    Welcome(Welcome),
+    /// The server is shutting down. Queued messages are still delivered
+    /// before it closes the connection.
+    ShuttingDown,
+}
+
+/// Sent by clients after connecting, announcing what they support.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize, Encode, Decode)]
+pub struct Hello {
+    /// Compression algorithms the client can decode, in order of preference.
+    pub compression: Vec<Compression>,
+}
+
+/// Server reply to `Hello`, with the settings used for the connection.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize, Encode, Decode)]
+pub struct Welcome {
+    /// Compression algorithm the server uses for frames sent to the client.
+    pub compression: Option<Compression>,
 }
 
 impl ChannelMsg {
@@ -93,16 +137,46 @@ impl ChannelMsg {
         Self {
             sender,
             channel,
+            headers: Headers::default(),
             content,
         }
     }
 
+    pub fn with_headers(mut self, headers: Headers) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sets a user defined header.
+    pub fn with_header(mut self, name: String, value: String) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Sets the message key, used by compacted channels.
+    pub fn with_key(mut self, key: String) -> Self {
+        self.headers.key = Some(key);
+        self
+    }
+
     pub fn sender(&self) -> &PeerId {
         &self.sender
     }
     pub fn channel(&self) -> ChannelId {
         self.channel
     }
+    pub fn set_channel(&mut self, channel: ChannelId) {
+        self.channel = channel
+    }
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+    pub fn key(&self) -> Option<&str> {
+        self.headers.key.as_deref()
+    }
     pub fn content(&self) -> &Vec<u8> {
         &self.content
     }
@@ -113,6 +187,14 @@ impl Msg {
         Self::ChannelMsg(ChannelMsg::new(sender, channel, content))
     }
 
+    pub fn hello(hello: Hello) -> Self {
+        Self::ControlMsg(ControlMsg::Hello(hello))
+    }
+
+    pub fn auth(token: String) -> Self {
+        Self::ControlMsg(ControlMsg::Auth(token))
+    }
+
     pub fn channel_join(channel_name: String) -> Self {
         Self::ControlMsg(ControlMsg::ChannelJoin(channel_name))
     }
@@ -121,6 +203,52 @@ impl Msg {
         Self::ControlMsg(ControlMsg::ChannelLeave(channel_id))
     }
 
+    pub fn channel_members(channel_id: ChannelId) -> Self {
+        Self::ControlMsg(ControlMsg::ChannelMembers(channel_id))
+    }
+
+    pub fn channel_list(prefix: Option<String>) -> Self {
+        Self::ControlMsg(ControlMsg::ChannelList(prefix))
+    }
+
+    pub fn channel_info(channel_id: ChannelId) -> Self {
+        Self::ControlMsg(ControlMsg::ChannelInfo(channel_id))
+    }
+
+    pub fn channel_configure(channel_id: ChannelId, options: ChannelOptions) -> Self {
+        Self::ControlMsg(ControlMsg::ChannelConfigure(channel_id, options))
+    }
+
+    pub fn channel_delete(channel_id: ChannelId) -> Self {
+        Self::ControlMsg(ControlMsg::ChannelDelete(channel_id))
+    }
+
+    /// Decodes a framed message.
+    pub fn decode_frame(frame: &[u8]) -> Result<Self, DecodeError> {
+        let (msg, _) = wire::decode_body(frame_body(frame)?, false)?;
+        Ok(msg)
+    }
+
+    /// Decodes a framed message, skipping the content of channel messages.
+    ///
+    /// Returns the message and the content length of a channel message (0 for
+    /// other messages).
+    pub fn decode_header(frame: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let msg_slice = frame_body(frame)?;
+        let (msg, hdr_len) = wire::decode_body(msg_slice, true)?;
+
+        let content_len = match msg {
+            Msg::ChannelMsg(_) => {
+                let (len, _) =
+                    bincode::decode_from_slice::<u64, _>(&msg_slice[hdr_len..], wire::CONFIG)?;
+                len as usize
+            }
+            _ => 0,
+        };
+
+        Ok((msg, content_len))
+    }
+
     pub fn get_id(&self) -> MsgId {
         MsgId(hash(self))
     }
@@ -133,6 +261,15 @@ impl Msg {
 #[derive(PartialEq, Clone, Debug)]
 pub struct MsgId(u64);
 
+/// Returns the body of an uncompressed frame.
+fn frame_body(frame: &[u8]) -> Result<&[u8], DecodeError> {
+    frame
+        .get(wire::PREFIX_LEN..)
+        .ok_or(DecodeError::UnexpectedEnd {
+            additional: wire::PREFIX_LEN,
+        })
+}
+
 mod test {
     //    use super::*;
 }
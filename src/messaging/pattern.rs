@@ -0,0 +1,104 @@
+//! Glob style channel name patterns, as used by Redis' `PSUBSCRIBE`.
+//!
+//! `*` matches any sequence of characters, `?` any single character, and
+//! `[abc]` or `[a-z]` one of the listed characters (`[^...]` negates the
+//! set). `\` escapes the next character.
+
+/// Returns `true` if channel `name` matches `pattern`.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    glob(pattern.as_bytes(), name.as_bytes())
+}
+
+fn glob(mut pattern: &[u8], mut name: &[u8]) -> bool {
+    while let Some(&c) = pattern.first() {
+        match c {
+            b'*' => {
+                while pattern.first() == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.is_empty() {
+                    return true;
+                }
+                return (0..=name.len()).any(|i| glob(pattern, &name[i..]));
+            }
+            b'?' => {
+                if name.is_empty() {
+                    return false;
+                }
+                pattern = &pattern[1..];
+                name = &name[1..];
+            }
+            b'[' => {
+                let (&ch, rest) = match name.split_first() {
+                    Some(split) => split,
+                    None => return false,
+                };
+                let negate = pattern.get(1) == Some(&b'^');
+                let mut i = if negate { 2 } else { 1 };
+                let mut matched = false;
+                // an unterminated set ends with the pattern
+                while let Some(&member) = pattern.get(i) {
+                    if member == b']' {
+                        break;
+                    }
+                    match (pattern.get(i + 1), pattern.get(i + 2)) {
+                        (Some(&escaped), _) if member == b'\\' => {
+                            matched |= escaped == ch;
+                            i += 2;
+                        }
+                        (Some(b'-'), Some(&end)) if end != b']' => {
+                            let (low, high) = (member.min(end), member.max(end));
+                            matched |= (low..=high).contains(&ch);
+                            i += 3;
+                        }
+                        _ => {
+                            matched |= member == ch;
+                            i += 1;
+                        }
+                    }
+                }
+                if matched == negate {
+                    return false;
+                }
+                pattern = pattern.get(i + 1..).unwrap_or_default();
+                name = rest;
+            }
+            _ => {
+                let (literal, len) = match (c, pattern.get(1)) {
+                    (b'\\', Some(&escaped)) => (escaped, 2),
+                    _ => (c, 1),
+                };
+                if name.first() != Some(&literal) {
+                    return false;
+                }
+                pattern = &pattern[len..];
+                name = &name[1..];
+            }
+        }
+    }
+    name.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+
+    #[test]
+    fn glob() {
+        assert!(matches("news.*", "news.sports"));
+        assert!(matches("news.*", "news."));
+        assert!(!matches("news.*", "news"));
+        assert!(matches("*", ""));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("*.log.*", "app.log.1"));
+        assert!(matches("[abc", "b"));
+    }
+}
@@ -1,9 +1,7 @@
 use bincode::{Decode, Encode};
-use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
-pub type PeerTx = flume::Sender<Bytes>;
-pub type PeerRx = flume::Receiver<Bytes>;
+pub use super::queue::{PeerRx, PeerTx};
 
 #[derive(PartialEq, Eq, Clone, Debug, Hash, Serialize, Deserialize, Encode, Decode)]
 pub struct PeerId {
@@ -14,6 +12,10 @@ impl PeerId {
     pub fn new(id: &str) -> PeerId {
         PeerId { id: id.to_string() }
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
 }
 
 pub trait Peer {
@@ -0,0 +1,184 @@
+use flume::{RecvError, SendError};
+
+use super::frame::Frame;
+use super::headers::Priority;
+
+const LANES: usize = Priority::High as usize + 1;
+
+/// Number of times a waiting lane may be passed over by higher priority
+/// frames before it gets served anyway.
+const STARVATION_LIMIT: usize = 16;
+
+/// Creates an unbounded per-peer delivery queue.
+///
+/// Frames are delivered by priority, FIFO within a priority.
+pub fn peer_queue() -> (PeerTx, PeerRx) {
+    let (txs, rxs): (Vec<_>, Vec<_>) = (0..LANES).map(|_| flume::unbounded()).unzip();
+    let (ready_tx, ready_rx) = flume::unbounded();
+
+    (
+        PeerTx {
+            lanes: txs,
+            ready: ready_tx,
+        },
+        PeerRx {
+            lanes: rxs,
+            ready: ready_rx,
+            skipped: [0; LANES],
+        },
+    )
+}
+
+/// Sending half of a peer's delivery queue.
+#[derive(Clone, Debug)]
+pub struct PeerTx {
+    lanes: Vec<flume::Sender<Frame>>,
+    /// One token per queued frame, so the receiver has a single thing to
+    /// wait on.
+    ready: flume::Sender<()>,
+}
+
+impl PeerTx {
+    pub fn send(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        self.lanes[frame.priority as usize].send(frame)?;
+        let _ = self.ready.send(());
+        Ok(())
+    }
+
+    /// Same as `send()`, as the queue is unbounded.
+    pub async fn send_async(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        self.send(frame)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ready.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.ready.is_disconnected()
+    }
+}
+
+/// Receiving half of a peer's delivery queue.
+#[derive(Debug)]
+pub struct PeerRx {
+    lanes: Vec<flume::Receiver<Frame>>,
+    ready: flume::Receiver<()>,
+    /// How often each lane has been passed over while non-empty.
+    skipped: [usize; LANES],
+}
+
+impl PeerRx {
+    pub async fn recv_async(&mut self) -> Result<Frame, RecvError> {
+        loop {
+            self.ready.recv_async().await?;
+            if let Some(frame) = self.pick() {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Returns the next frame if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<Frame> {
+        self.ready.try_recv().ok()?;
+        self.pick()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ready.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    fn pick(&mut self) -> Option<Frame> {
+        // serve starving lanes first, lowest priority first
+        for lane in 0..LANES {
+            if self.skipped[lane] >= STARVATION_LIMIT {
+                if let Ok(frame) = self.lanes[lane].try_recv() {
+                    self.skipped[lane] = 0;
+                    return Some(frame);
+                }
+            }
+        }
+
+        for lane in (0..LANES).rev() {
+            if let Ok(frame) = self.lanes[lane].try_recv() {
+                self.skipped[lane] = 0;
+                for lower in 0..lane {
+                    if !self.lanes[lower].is_empty() {
+                        self.skipped[lower] += 1;
+                    }
+                }
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+
+    fn frame(id: u8, priority: Priority) -> Frame {
+        Frame::new(Bytes::from(vec![id])).with_priority(priority)
+    }
+
+    fn received(rx: &mut PeerRx) -> Vec<u8> {
+        std::iter::from_fn(|| rx.try_recv())
+            .map(|frame| frame.data[0])
+            .collect()
+    }
+
+    #[test]
+    fn priority() {
+        let (tx, mut rx) = peer_queue();
+        tx.send(frame(1, Priority::Low)).unwrap();
+        tx.send(frame(2, Priority::Normal)).unwrap();
+        tx.send(frame(3, Priority::High)).unwrap();
+        tx.send(frame(4, Priority::Normal)).unwrap();
+        assert_eq!(tx.len(), 4);
+
+        // by priority, in order within a priority
+        assert_eq!(received(&mut rx), vec![3, 2, 4, 1]);
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn starvation() {
+        let (tx, mut rx) = peer_queue();
+        tx.send(frame(0, Priority::Low)).unwrap();
+        for id in 1..=40 {
+            tx.send(frame(id, Priority::High)).unwrap();
+        }
+
+        // the low priority frame is served after being passed over
+        // STARVATION_LIMIT times
+        let received = received(&mut rx);
+        assert_eq!(received.len(), 41);
+        assert_eq!(
+            received.iter().position(|id| *id == 0),
+            Some(STARVATION_LIMIT)
+        );
+    }
+
+    #[monoio::test]
+    async fn recv_async() {
+        let (tx, mut rx) = peer_queue();
+        tx.send(frame(1, Priority::Normal)).unwrap();
+        tx.send(frame(2, Priority::High)).unwrap();
+        assert_eq!(rx.recv_async().await.unwrap().data[0], 2);
+        assert_eq!(rx.recv_async().await.unwrap().data[0], 1);
+
+        drop(tx);
+        assert!(rx.recv_async().await.is_err());
+    }
+}
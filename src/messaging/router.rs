@@ -1,9 +1,18 @@
-use super::channel::{Channel, ChannelId};
+use super::acl::Acl;
+use super::channel::{Channel, ChannelId, ChannelInfo, ChannelOptions};
+use super::frame::Frame;
+use super::log::LogStore;
+use super::msg::{Msg, StatusMsg};
+use super::pattern;
 use super::peer::{Peer, PeerId, PeerTx};
-use std::collections::HashMap;
+use super::schedule::{Scheduled, Scheduler};
+use crate::monoio_bincode::Framed;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use anyhow::Error;
-use bytes::Bytes;
 use slotmap::SlotMap;
 
 use super::errors::TxError;
@@ -11,8 +20,37 @@ use super::errors::TxError;
 #[derive(Debug, Default)]
 pub struct Router {
     peers: HashMap<PeerId, PeerTx>,
+    /// Peers whose subscriptions aren't announced, see `peer_add_quiet()`.
+    quiet: HashSet<PeerId>,
     channels: SlotMap<ChannelId, Channel>,
     channel_names: HashMap<String, ChannelId>,
+    /// How long an empty, non-persistent channel is kept around.
+    linger: Duration,
+    /// Number of messages channels keep for resuming subscribers.
+    history_len: usize,
+    scheduler: Scheduler,
+    /// Persists the logs of compacted channels, see `set_log_store()`.
+    log_store: Option<LogStore>,
+    /// Channel name patterns subscribed to by peers, see `attach_pattern()`.
+    patterns: HashMap<PeerId, Vec<String>>,
+    acl: Acl,
+    /// Options of newly created channels, by channel name pattern.
+    defaults: Vec<(String, ChannelOptions)>,
+    /// Set once the server shuts down, see `close()`.
+    closed: bool,
+}
+
+/// A peer as registered with the router.
+struct PeerRef<'a>(&'a PeerId, &'a PeerTx);
+
+impl Peer for PeerRef<'_> {
+    fn get_id(&self) -> &PeerId {
+        self.0
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        self.1
+    }
 }
 
 impl Router {
@@ -20,15 +58,133 @@ impl Router {
         Router::default()
     }
 
+    /// Sets how long channels are kept after their last subscriber left.
+    ///
+    /// With a zero linger time (the default), channels are removed as soon as
+    /// they become empty.
+    pub fn set_linger(&mut self, linger: Duration) {
+        self.linger = linger;
+    }
+
+    /// Sets the number of messages every channel keeps, so that subscribers
+    /// can resume after a disconnect (see `attach_from()`).
+    ///
+    /// With zero (the default), channels keep no history.
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+        for (_, channel) in self.channels.iter_mut() {
+            channel.set_history_len(len);
+        }
+    }
+
+    /// Restricts the channels peers may publish and subscribe to.
+    ///
+    /// Subscriptions the new ACL doesn't permit are removed, the peers are
+    /// notified with `StatusMsg::ChannelLeft`.
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = acl;
+
+        let (acl, peers, quiet) = (&self.acl, &self.peers, &self.quiet);
+        let mut left = Vec::new();
+        for (channel_id, channel) in self.channels.iter_mut() {
+            let denied: Vec<PeerId> = channel
+                .members()
+                .filter(|peer_id| !acl.may_subscribe(peer_id, channel.get_name()))
+                .cloned()
+                .collect();
+            for peer_id in denied {
+                channel.unsubscribe_id(&peer_id);
+                let status = StatusMsg::ChannelLeft(channel_id, peer_id.clone());
+                if let Some(sink) = peers.get(&peer_id) {
+                    let _ = sink.send(Frame::status(Msg::new_status(status.clone()).framed()));
+                }
+                Self::presence(quiet, channel, status);
+                left.push(channel_id);
+            }
+        }
+
+        left.dedup();
+        for channel_id in left {
+            self.release(channel_id);
+        }
+    }
+
+    /// Sets the options of newly created channels. The options of the first
+    /// pattern matching a channel's name are used.
+    pub fn set_channel_defaults(&mut self, defaults: Vec<(String, ChannelOptions)>) {
+        self.defaults = defaults;
+    }
+
+    /// Returns whether the ACL permits `peer_id` to publish to the channel
+    /// named `name`.
+    pub fn may_publish(&self, peer_id: &PeerId, name: &str) -> bool {
+        self.acl.may_publish(peer_id, name)
+    }
+
+    /// Stops accepting messages, as the server is shutting down.
+    ///
+    /// Publishing and scheduling fail with `TxError::ShuttingDown`. Due
+    /// messages stay scheduled, so that they get persisted.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
     pub fn peer_add(&mut self, peer: &dyn Peer) {
         self.peers
             .insert(peer.get_id().clone(), peer.get_sink().clone());
     }
 
+    /// Adds a peer whose joining and leaving channels isn't announced to the
+    /// other subscribers, e.g. the short-lived subscriber of an HTTP long
+    /// poll.
+    pub fn peer_add_quiet(&mut self, peer: &dyn Peer) {
+        self.peer_add(peer);
+        self.quiet.insert(peer.get_id().clone());
+    }
+
+    /// Removes a peer and its subscriptions. Unknown peers are ignored.
     pub fn peer_remove(&mut self, peer_id: &PeerId) {
-        self.peers.remove(peer_id).unwrap();
+        if self.peers.remove(peer_id).is_none() {
+            return;
+        }
+        self.patterns.remove(peer_id);
+
+        let mut left = Vec::new();
+        for (channel_id, channel) in self.channels.iter_mut() {
+            if channel.unsubscribe_id(peer_id).is_some() {
+                Self::presence(
+                    &self.quiet,
+                    channel,
+                    StatusMsg::ChannelLeft(channel_id, peer_id.clone()),
+                );
+                left.push(channel_id);
+            }
+        }
+
+        for channel_id in left {
+            self.release(channel_id);
+        }
     }
 
+    /// Looks up a channel by name, creating it explicitly if needed.
+    ///
+    /// The channel is owned by `owner`, unless it already has an owner.
+    pub fn channel_create(&mut self, name: String, owner: &PeerId) -> ChannelId {
+        let channel_id = self.channel_get_or_add(name);
+        if let Some(channel) = self.channels.get_mut(channel_id) {
+            if channel.owner().is_none() {
+                channel.set_owner(owner.clone());
+            }
+        }
+        channel_id
+    }
+
+    /// Looks up a channel by name, creating it if needed.
+    ///
+    /// Channels created this way, e.g. by publishing or subscribing to them,
+    /// have no owner until one is set with `channel_create()`. Peers with a
+    /// matching pattern subscription are subscribed to a new channel, if the
+    /// ACL permits.
     pub fn channel_get_or_add(&mut self, name: String) -> ChannelId {
         if let Some(key) = self.channel_names.get(&name) {
             return *key;
@@ -36,11 +192,35 @@ impl Router {
 
         tracing::info!("creating channel {}", name);
 
+        let history_len = self.history_len;
+        let log_writer = self.log_store.as_ref().map(LogStore::writer);
+        let options = self
+            .defaults
+            .iter()
+            .find(|(pattern, _)| pattern::matches(pattern, &name))
+            .map(|(_, options)| options.clone());
         let key = self.channels.insert_with_key(|key| {
-            let channel = Channel::new(name.clone(), key);
+            let mut channel = Channel::new(name.clone(), key);
+            channel.set_history_len(history_len);
+            channel.set_log_writer(log_writer);
+            if let Some(options) = options {
+                channel.set_options(options);
+            }
             channel
         });
+
+        // pattern subscribers join like any other subscriber, the ACL is
+        // checked by attach()
+        let subscribers: Vec<_> = self
+            .patterns
+            .iter()
+            .filter(|(_, patterns)| patterns.iter().any(|p| pattern::matches(p, &name)))
+            .filter_map(|(peer_id, _)| Some((peer_id.clone(), self.peers.get(peer_id)?.clone())))
+            .collect();
         self.channel_names.insert(name, key);
+        for (peer_id, sink) in &subscribers {
+            let _ = self.attach(key, &PeerRef(peer_id, sink));
+        }
         key
     }
 
@@ -48,6 +228,131 @@ impl Router {
         self.channels.get_mut(channel_id)
     }
 
+    /// Lists channel names and ids, sorted by name.
+    ///
+    /// If `prefix` is given, only channels whose name starts with it are listed.
+    pub fn channel_list(&self, prefix: Option<&str>) -> Vec<(String, ChannelId)> {
+        let mut list: Vec<_> = self
+            .channel_names
+            .iter()
+            .filter(|(name, _)| prefix.is_none_or(|prefix| name.starts_with(prefix)))
+            .map(|(name, id)| (name.clone(), *id))
+            .collect();
+        list.sort();
+        list
+    }
+
+    pub fn channel_name(&self, channel_id: ChannelId) -> Option<&str> {
+        self.channels
+            .get(channel_id)
+            .map(|channel| channel.get_name().as_str())
+    }
+
+    pub fn channel_info(&self, channel_id: ChannelId) -> Result<ChannelInfo, TxError> {
+        self.channels
+            .get(channel_id)
+            .map(Channel::info)
+            .ok_or(TxError::InvalidChannel)
+    }
+
+    /// Changes a channel's options. Only the channel owner may do this.
+    pub fn channel_configure(
+        &mut self,
+        channel_id: ChannelId,
+        options: ChannelOptions,
+        peer: &dyn Peer,
+    ) -> Result<(), TxError> {
+        let channel = self
+            .channel_get(channel_id)
+            .ok_or(TxError::InvalidChannel)?;
+
+        if !channel.is_owner(peer.get_id()) {
+            return Err(TxError::NotOwner);
+        }
+
+        channel.set_options(options);
+
+        Ok(())
+    }
+
+    /// Deletes a channel. Only the channel owner may do this.
+    ///
+    /// Remaining subscribers get notified with `StatusMsg::ChannelDeleted`.
+    pub fn channel_delete(
+        &mut self,
+        channel_id: ChannelId,
+        peer: &dyn Peer,
+    ) -> Result<(), TxError> {
+        let channel = self
+            .channel_get(channel_id)
+            .ok_or(TxError::InvalidChannel)?;
+
+        if !channel.is_owner(peer.get_id()) {
+            return Err(TxError::NotOwner);
+        }
+
+        channel.notify(
+            Frame::status(Msg::new_status(StatusMsg::ChannelDeleted(channel_id)).framed()),
+            peer.get_id(),
+        );
+        self.channel_remove(channel_id);
+
+        Ok(())
+    }
+
+    /// Removes channels that have been empty for longer than the linger time
+    /// at `now`, and compacts channel logs.
+    ///
+    /// Returns the number of removed channels.
+    pub fn gc(&mut self, now: Instant) -> usize {
+        for (_, channel) in self.channels.iter_mut() {
+            channel.compact();
+        }
+
+        let linger = self.linger;
+        let expired: Vec<_> = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| !channel.options().persistent)
+            .filter(|(_, channel)| channel.idle_for(now).is_some_and(|idle| idle >= linger))
+            .map(|(channel_id, _)| channel_id)
+            .collect();
+
+        for channel_id in &expired {
+            self.channel_remove(*channel_id);
+        }
+
+        expired.len()
+    }
+
+    /// Removes a channel right away if it is empty, not persistent and
+    /// there's no linger time. Otherwise, `gc()` will pick it up later.
+    fn release(&mut self, channel_id: ChannelId) {
+        if !self.linger.is_zero() {
+            return;
+        }
+
+        if let Some(channel) = self.channels.get(channel_id) {
+            if channel.is_empty() && !channel.options().persistent {
+                self.channel_remove(channel_id);
+            }
+        }
+    }
+
+    fn channel_remove(&mut self, channel_id: ChannelId) -> Option<Channel> {
+        let channel = self.channels.remove(channel_id)?;
+        tracing::info!("dropping channel {}", channel.get_name());
+        if let Some(store) = self
+            .log_store
+            .as_ref()
+            .filter(|_| channel.options().compact)
+        {
+            store.writer().remove(channel.get_name());
+        }
+        self.channel_names.remove(channel.get_name());
+        Some(channel)
+    }
+
     // pub fn channel_get_or_add(&mut self, channel_id: &ChannelId) -> &mut Channel {
     //     self.channels.entry(channel_id.clone()).or_insert_with(|| {
     //         tracing::info!("creating channel {}", channel_id.0);
@@ -57,53 +362,344 @@ impl Router {
 
     pub fn forward(
         &mut self,
-        payload: Bytes,
+        payload: Frame,
+        channel_id: ChannelId,
+        sender: &PeerId,
+    ) -> Result<usize, TxError> {
+        self.publish(payload, channel_id, sender, false)
+    }
+
+    /// Forwards a message that replaces the channel's retained message, see
+    /// `Channel::forward_retained()`.
+    pub fn forward_retained(
+        &mut self,
+        payload: Frame,
         channel_id: ChannelId,
         sender: &PeerId,
     ) -> Result<usize, TxError> {
+        self.publish(payload, channel_id, sender, true)
+    }
+
+    fn publish(
+        &mut self,
+        payload: Frame,
+        channel_id: ChannelId,
+        sender: &PeerId,
+        retain: bool,
+    ) -> Result<usize, TxError> {
+        if self.closed {
+            return Err(TxError::ShuttingDown);
+        }
         let channel = self
-            .channel_get(channel_id)
+            .channels
+            .get_mut(channel_id)
             .ok_or(TxError::InvalidChannel)?;
+        if !self.acl.may_publish(sender, channel.get_name()) {
+            return Err(TxError::NotPermitted);
+        }
+
+        Ok(match retain {
+            true => channel.forward_retained(payload, sender),
+            false => channel.forward(payload, sender),
+        })
+    }
+
+    /// Holds back a message until `due` (in milliseconds since the Unix
+    /// epoch), then forwards it.
+    pub fn schedule(
+        &mut self,
+        payload: Frame,
+        channel_id: ChannelId,
+        sender: &PeerId,
+        due: u64,
+    ) -> Result<(), TxError> {
+        if self.closed {
+            return Err(TxError::ShuttingDown);
+        }
+        let channel = self
+            .channels
+            .get(channel_id)
+            .ok_or(TxError::InvalidChannel)?;
+        if !self.acl.may_publish(sender, channel.get_name()) {
+            return Err(TxError::NotPermitted);
+        }
+
+        self.scheduler.push(
+            due,
+            Some(channel_id),
+            channel.get_name().clone(),
+            sender.clone(),
+            payload.detach(),
+        );
+
+        Ok(())
+    }
+
+    /// Persists scheduled messages to `path`, loading any stored there.
+    pub fn set_schedule_store(&mut self, path: PathBuf) -> io::Result<()> {
+        self.scheduler.set_store(path)
+    }
+
+    /// Forwards scheduled messages that are due. Channels removed in the
+    /// meantime are created again.
+    ///
+    /// Returns the number of forwarded messages.
+    pub fn deliver_due(&mut self) -> usize {
+        if self.closed {
+            return 0;
+        }
+        let due = self.scheduler.take_due();
+        let count = due.len();
+
+        for scheduled in due {
+            let Scheduled {
+                channel,
+                channel_name,
+                sender,
+                frame,
+                ..
+            } = scheduled;
+
+            // Channels might have been removed or recreated since scheduling
+            // (or the server restarted), look them up by name.
+            let channel_id = self.channel_get_or_add(channel_name);
+
+            let frame = if channel == Some(channel_id) {
+                frame
+            } else {
+                match Self::rebind(&frame, channel_id) {
+                    Some(frame) => frame,
+                    None => continue,
+                }
+            };
+
+            let _ = self.forward(frame, channel_id, &sender);
+        }
+
+        if let Err(e) = self.scheduler.flush() {
+            tracing::warn!("error persisting scheduled messages: {e}");
+        }
+
+        count
+    }
+
+    /// Persists the logs of compacted channels to `path`, and restores the
+    /// channels and logs stored there.
+    ///
+    /// Restored channels are created with their options and owner, their
+    /// logged messages count as published.
+    pub fn set_log_store(&mut self, path: PathBuf) -> io::Result<()> {
+        let (store, logs) = LogStore::open(path)?;
+        tracing::info!("loaded {} channel logs", logs.len());
+
+        for log in logs {
+            let channel_id = self.channel_get_or_add(log.channel);
+            let channel = &mut self.channels[channel_id];
+            if let Some(owner) = log.owner {
+                channel.set_owner(owner);
+            }
+            channel.set_options(log.options);
+            for (key, frame) in log.entries {
+                // the channel id changed since the message was published
+                if let Some(frame) = Self::rebind(&frame, channel_id) {
+                    channel.restore(key, frame);
+                }
+            }
+        }
+
+        for (_, channel) in self.channels.iter_mut() {
+            channel.set_log_writer(Some(store.writer()));
+        }
+        self.log_store = Some(store);
+        Ok(())
+    }
 
-        Ok(channel.forward(payload, sender))
+    /// Writes scheduled messages and channel logs to their stores, if set,
+    /// and waits until they are written.
+    pub fn persist(&mut self) -> io::Result<()> {
+        let scheduled = self.scheduler.sync();
+        let logs = match self.log_store.take() {
+            Some(store) => {
+                for (_, channel) in self.channels.iter_mut() {
+                    channel.set_log_writer(None);
+                }
+                store.close()
+            }
+            None => Ok(()),
+        };
+        scheduled.and(logs)
+    }
+
+    /// Sends a status message to every peer.
+    pub fn notify_peers(&self, status: StatusMsg) {
+        let frame = Frame::status(Msg::new_status(status).framed());
+        for sink in self.peers.values() {
+            let _ = sink.send(frame.clone());
+        }
+    }
+
+    /// Returns the number of frames waiting in the queues of peers.
+    pub fn queued(&self) -> usize {
+        self.peers.values().map(PeerTx::len).sum()
+    }
+
+    /// Returns the number of frames waiting in the longest peer queue.
+    pub fn max_queued(&self) -> usize {
+        self.peers.values().map(PeerTx::len).max().unwrap_or(0)
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Re-encodes a channel message frame for a different channel id.
+    fn rebind(frame: &Frame, channel_id: ChannelId) -> Option<Frame> {
+        match Msg::decode_frame(&frame.data) {
+            Ok(Msg::ChannelMsg(mut msg)) => {
+                msg.set_channel(channel_id);
+                Some(
+                    Frame::new(Msg::ChannelMsg(msg).framed())
+                        .with_expiry(frame.expires)
+                        .with_priority(frame.priority),
+                )
+            }
+            _ => None,
+        }
     }
 
     pub async fn forward_async(
         &mut self,
-        payload: Bytes,
+        payload: Frame,
         channel_id: ChannelId,
         sender: &PeerId,
     ) -> Result<usize, TxError> {
+        if self.closed {
+            return Err(TxError::ShuttingDown);
+        }
         let channel = self
-            .channel_get(channel_id)
+            .channels
+            .get_mut(channel_id)
             .ok_or(TxError::InvalidChannel)?;
+        if !self.acl.may_publish(sender, channel.get_name()) {
+            return Err(TxError::NotPermitted);
+        }
 
         Ok(channel.forward_async(payload, sender).await)
     }
 
     pub fn attach(&mut self, channel_id: ChannelId, peer: &dyn Peer) -> Result<(), Error> {
+        self.attach_from(channel_id, peer, None)
+    }
+
+    /// Subscribes `peer` to a channel, resuming at message `from`, see
+    /// `Channel::subscribe_from()`.
+    pub fn attach_from(
+        &mut self,
+        channel_id: ChannelId,
+        peer: &dyn Peer,
+        from: Option<u64>,
+    ) -> Result<(), Error> {
         let channel = self
-            .channel_get(channel_id)
+            .channels
+            .get_mut(channel_id)
             .ok_or(TxError::InvalidChannel)?;
+        if !self.acl.may_subscribe(peer.get_id(), channel.get_name()) {
+            return Err(TxError::NotPermitted.into());
+        }
 
-        channel.subscribe(peer);
+        if channel.subscribe_from(peer, from) {
+            Self::presence(
+                &self.quiet,
+                channel,
+                StatusMsg::ChannelJoined(channel_id, peer.get_id().clone()),
+            );
+        }
 
         Ok(())
     }
 
+    /// Subscribes `peer` to all channels whose name matches `pattern` (see
+    /// `pattern::matches()`), including channels created later.
+    ///
+    /// Returns the channels subscribed to right away. Channels the ACL
+    /// doesn't permit subscribing to are skipped.
+    pub fn attach_pattern(&mut self, pattern: String, peer: &dyn Peer) -> Vec<ChannelId> {
+        let matching: Vec<_> = self
+            .channel_names
+            .iter()
+            .filter(|(name, _)| pattern::matches(&pattern, name))
+            .map(|(_, channel_id)| *channel_id)
+            .collect();
+        let matching: Vec<_> = matching
+            .into_iter()
+            .filter(|channel_id| self.attach(*channel_id, peer).is_ok())
+            .collect();
+
+        let patterns = self.patterns.entry(peer.get_id().clone()).or_default();
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+
+        matching
+    }
+
+    /// Removes a pattern subscription of `peer`.
+    ///
+    /// Channels subscribed to through the pattern stay subscribed, they
+    /// have to be detached separately.
+    pub fn detach_pattern(&mut self, pattern: &str, peer: &dyn Peer) {
+        if let Some(patterns) = self.patterns.get_mut(peer.get_id()) {
+            patterns.retain(|p| p != pattern);
+            if patterns.is_empty() {
+                self.patterns.remove(peer.get_id());
+            }
+        }
+    }
+
     pub fn detach(&mut self, channel_id: ChannelId, peer: &dyn Peer) -> Result<(), Error> {
-        let channel = self.channels.get_mut(channel_id);
-        let mut remove = Option::default();
-        if let Some(channel) = channel {
+        if let Some(channel) = self.channels.get_mut(channel_id) {
             if channel.unsubscribe(peer).is_some() {
-                tracing::info!("dropping channel {}", channel.get_name());
-                remove = Some(channel_id);
-                self.channel_names.remove(channel.get_name());
+                Self::presence(
+                    &self.quiet,
+                    channel,
+                    StatusMsg::ChannelLeft(channel_id, peer.get_id().clone()),
+                );
+                self.release(channel_id);
             }
         }
-        if let Some(channel_id) = remove {
-            self.channels.remove(channel_id);
-        }
         Ok(())
     }
+
+    /// Lists the peers subscribed to a channel.
+    ///
+    /// Only subscribers of a channel may list its members.
+    pub fn members(&self, channel_id: ChannelId, peer: &dyn Peer) -> Result<Vec<PeerId>, TxError> {
+        let channel = self
+            .channels
+            .get(channel_id)
+            .ok_or(TxError::InvalidChannel)?;
+
+        if !channel.is_subscribed(peer.get_id()) {
+            return Err(TxError::NotSubscribed);
+        }
+
+        Ok(channel.members().cloned().collect())
+    }
+
+    /// Notifies a channel's subscribers about a membership change.
+    ///
+    /// The peer the event is about is not notified itself. Nothing is sent
+    /// for quiet peers.
+    fn presence(quiet: &HashSet<PeerId>, channel: &mut Channel, status: StatusMsg) {
+        let peer_id = match &status {
+            StatusMsg::ChannelJoined(_, peer_id) | StatusMsg::ChannelLeft(_, peer_id) => {
+                peer_id.clone()
+            }
+            _ => return,
+        };
+        if quiet.contains(&peer_id) {
+            return;
+        }
+        channel.notify(Frame::status(Msg::new_status(status).framed()), &peer_id);
+    }
 }
@@ -0,0 +1,302 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use bincode::{Decode, Encode};
+use bytes::Bytes;
+
+use super::channel::ChannelId;
+use super::frame::Frame;
+use super::headers::Priority;
+use super::peer::PeerId;
+use super::util::unix_time_ms;
+
+/// A message waiting for its delivery time.
+#[derive(Debug)]
+pub struct Scheduled {
+    /// Delivery time in milliseconds since the Unix epoch.
+    pub due: u64,
+    seq: u64,
+    /// Channel id the frame was encoded for. `None` if the message was
+    /// loaded from disk, as channel ids don't survive restarts.
+    pub channel: Option<ChannelId>,
+    pub channel_name: String,
+    pub sender: PeerId,
+    pub frame: Frame,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// On-disk representation of a `Scheduled`.
+#[derive(Encode, Decode)]
+struct StoredEntry {
+    due: u64,
+    channel_name: String,
+    sender: PeerId,
+    frame: Vec<u8>,
+    expires: Option<u64>,
+    priority: Priority,
+}
+
+/// Holds messages until they are due.
+///
+/// If a store path is set, the pending messages are written there by
+/// `flush()` if they changed, and loaded back on startup.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    next_seq: u64,
+    store: Option<PathBuf>,
+    writer: Option<StoreWriter>,
+    dirty: bool,
+}
+
+/// Writes snapshots of the scheduled messages to the store on a thread of
+/// its own, so that the event loop doesn't wait for the file system.
+///
+/// Snapshots queued while a write is in progress are coalesced, only the
+/// latest one gets written.
+#[derive(Debug)]
+struct StoreWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl StoreWriter {
+    fn spawn(path: PathBuf) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::Builder::new()
+            .name("schedule-store".to_string())
+            .spawn(move || {
+                let mut res = Ok(());
+                while let Ok(mut data) = rx.recv() {
+                    while let Ok(newer) = rx.try_recv() {
+                        data = newer;
+                    }
+                    res = write_store(&path, &data);
+                    if let Err(e) = &res {
+                        tracing::warn!("error persisting scheduled messages: {e}");
+                    }
+                }
+                res
+            })?;
+        Ok(Self { tx, thread })
+    }
+
+    /// Waits until the queued snapshots are written, returning the result of
+    /// the last write.
+    fn close(self) -> io::Result<()> {
+        drop(self.tx);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("store writer panicked")))
+    }
+}
+
+fn write_store(path: &Path, data: &[u8]) -> io::Result<()> {
+    // write to a temporary file first so a crash can't leave a truncated store
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the file used to persist scheduled messages and loads messages
+    /// from it, if it exists.
+    pub fn set_store(&mut self, path: PathBuf) -> io::Result<()> {
+        if path.exists() {
+            let data = fs::read(&path)?;
+            let (entries, _): (Vec<StoredEntry>, _) =
+                bincode::decode_from_slice(&data, bincode::config::standard())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            tracing::info!("loaded {} scheduled messages", entries.len());
+
+            for entry in entries {
+                let frame = Frame::new(Bytes::from(entry.frame))
+                    .with_expiry(entry.expires)
+                    .with_priority(entry.priority);
+                self.push(entry.due, None, entry.channel_name, entry.sender, frame);
+            }
+        }
+        self.store = Some(path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(
+        &mut self,
+        due: u64,
+        channel: Option<ChannelId>,
+        channel_name: String,
+        sender: PeerId,
+        frame: Frame,
+    ) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            due,
+            seq,
+            channel,
+            channel_name,
+            sender,
+            frame,
+        }));
+        self.dirty = true;
+    }
+
+    /// Removes and returns all messages that are due.
+    pub fn take_due(&mut self) -> Vec<Scheduled> {
+        let now = unix_time_ms();
+        let mut due = Vec::new();
+        while self.queue.peek().is_some_and(|next| next.0.due <= now) {
+            due.push(self.queue.pop().unwrap().0);
+        }
+        if !due.is_empty() {
+            self.dirty = true;
+        }
+        due
+    }
+
+    /// Hands pending messages to the store writer, if they changed since
+    /// the last call. The store is written in the background.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let path = match &self.store {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+
+        let entries: Vec<_> = self
+            .queue
+            .iter()
+            .map(|Reverse(scheduled)| StoredEntry {
+                due: scheduled.due,
+                channel_name: scheduled.channel_name.clone(),
+                sender: scheduled.sender.clone(),
+                frame: scheduled.frame.data.to_vec(),
+                expires: scheduled.frame.expires,
+                priority: scheduled.frame.priority,
+            })
+            .collect();
+
+        let data = bincode::encode_to_vec(&entries, bincode::config::standard())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self.writer.insert(StoreWriter::spawn(path.clone())?),
+        };
+        if let Err(mpsc::SendError(data)) = writer.tx.send(data) {
+            // the writer thread is gone, write in place
+            self.writer = None;
+            write_store(path, &data)?;
+        }
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Flushes pending messages and waits until they are written.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match self.writer.take() {
+            Some(writer) => writer.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let now = unix_time_ms();
+        let mut scheduler = Scheduler::new();
+        for (due, name) in [
+            (now + 60_000, "later"),
+            (now - 1, "a"),
+            (now - 5, "first"),
+            (now - 1, "b"),
+        ] {
+            let frame = Frame::new(Bytes::new());
+            scheduler.push(due, None, name.to_string(), PeerId::new("sender"), frame);
+        }
+
+        // by delivery time, then in scheduling order
+        let due: Vec<_> = scheduler
+            .take_due()
+            .into_iter()
+            .map(|scheduled| scheduled.channel_name)
+            .collect();
+        assert_eq!(due, vec!["first", "a", "b"]);
+        assert_eq!(scheduler.len(), 1);
+        assert!(scheduler.take_due().is_empty());
+    }
+
+    #[test]
+    fn store() {
+        let path = std::env::temp_dir().join(format!("rsq-schedule-{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut scheduler = Scheduler::new();
+        scheduler.set_store(path.clone()).unwrap();
+        for due in [20, 10] {
+            let frame = Frame::new(Bytes::from(vec![due as u8])).with_expiry(Some(u64::MAX));
+            scheduler.push(due, None, "news".to_string(), PeerId::new("sender"), frame);
+        }
+        scheduler.push(
+            u64::MAX,
+            None,
+            "later".to_string(),
+            PeerId::new("sender"),
+            Frame::new(Bytes::new()),
+        );
+        assert_eq!(scheduler.take_due().len(), 2);
+        scheduler.sync().unwrap();
+
+        let mut loaded = Scheduler::new();
+        loaded.set_store(path.clone()).unwrap();
+        assert_eq!(loaded.len(), 1);
+        let Reverse(scheduled) = loaded.queue.pop().unwrap();
+        assert_eq!(scheduled.channel_name, "later");
+        assert_eq!(scheduled.channel, None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
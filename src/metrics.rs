@@ -0,0 +1,290 @@
+//! Server metrics, exported in the Prometheus text format.
+//!
+//! Counters are process wide statics, updated where messages pass through.
+//! Gauges are read from the server state when metrics are scraped, by
+//! `listener()`.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use monoio::io::Splitable;
+use monoio::net::{TcpListener, TcpStream};
+
+use crate::http::{self, RequestReader};
+use crate::msg_stream::FrameWriter;
+use crate::server::{shutdown_requested, Endpoint, Shared, OPEN_CONNECTIONS};
+
+/// Content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Messages published to channels.
+pub static MESSAGES_IN: Counter = Counter::new();
+
+/// Bytes of messages published to channels, including framing.
+pub static BYTES_IN: Counter = Counter::new();
+
+/// Messages queued for delivery to peers.
+pub static MESSAGES_OUT: Counter = Counter::new();
+
+/// Bytes of messages queued for delivery to peers.
+pub static BYTES_OUT: Counter = Counter::new();
+
+/// Messages that expired or couldn't be delivered.
+pub static DROPPED: Counter = Counter::new();
+
+/// Frames, packets and commands received from clients that couldn't be
+/// decoded.
+pub static DECODE_ERRORS: Counter = Counter::new();
+
+/// A monotonically increasing count.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Writes metrics in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    out: String,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.out, "{name} {value}");
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.out, "{name} {value}");
+    }
+
+    /// Writes a gauge with one sample per value of `label`.
+    pub fn labeled_gauge<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        samples: impl IntoIterator<Item = (&'a str, u64)>,
+    ) {
+        self.header(name, help, "gauge");
+        for (label_value, value) in samples {
+            let _ = writeln!(
+                self.out,
+                "{name}{{{label}=\"{}\"}} {value}",
+                escape(label_value)
+            );
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Accepts connections of Prometheus scrapers.
+pub async fn listener(state: Rc<RefCell<Shared>>, listener: TcpListener, endpoint: Rc<Endpoint>) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("metrics accept error: {e}");
+                continue;
+            }
+        };
+        if !endpoint.config.allows(addr.ip()) {
+            tracing::info!("rejecting connection from {}", addr);
+            continue;
+        }
+
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+        monoio::spawn(async move {
+            endpoint.opened();
+            if let Err(e) = process_metrics(state, &endpoint, stream).await {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+            endpoint.closed();
+        });
+    }
+}
+
+/// Answers `GET /metrics` requests.
+async fn process_metrics(
+    state: Rc<RefCell<Shared>>,
+    endpoint: &Endpoint,
+    stream: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let (stream_in, stream_out) = stream.into_split();
+    let mut requests = RequestReader::new(stream_in);
+    let mut writer = FrameWriter::new(stream_out);
+
+    while let Some(request) = requests.next().await {
+        let request = request?;
+        let path = request.path.split('?').next().unwrap_or_default();
+        let response = if path != "/metrics" {
+            http::text_response(404, "not found\n")
+        } else if request.method != "GET" {
+            http::text_response(405, "method not allowed\n")
+        } else {
+            match http::authorize(endpoint.config.token.as_deref(), &request) {
+                Ok(()) => {
+                    let body = render_metrics(&state.borrow());
+                    http::response(200, CONTENT_TYPE, body.as_bytes())
+                }
+                Err(response) => response,
+            }
+        };
+
+        writer.push(response);
+        writer.flush().await?;
+
+        if !request.keep_alive() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the server metrics in the Prometheus text format.
+fn render_metrics(state: &Shared) -> String {
+    let router = &state.router;
+    let channels: Vec<_> = router
+        .channel_list(None)
+        .into_iter()
+        .filter_map(|(_, channel_id)| router.channel_info(channel_id).ok())
+        .collect();
+
+    let mut encoder = Encoder::new();
+    encoder.gauge(
+        "rsq_connections",
+        "Open client connections.",
+        OPEN_CONNECTIONS.load(Ordering::Relaxed),
+    );
+    encoder.gauge(
+        "rsq_peers",
+        "Peers registered with the router, including webhooks.",
+        router.peer_count() as u64,
+    );
+    encoder.gauge("rsq_channels", "Channels.", channels.len() as u64);
+    encoder.labeled_gauge(
+        "rsq_channel_subscribers",
+        "Subscribers of a channel.",
+        "channel",
+        channels
+            .iter()
+            .map(|info| (info.name.as_str(), info.subscribers)),
+    );
+    encoder.counter(
+        "rsq_messages_in_total",
+        "Messages published to channels.",
+        MESSAGES_IN.get(),
+    );
+    encoder.counter(
+        "rsq_bytes_in_total",
+        "Bytes of messages published to channels.",
+        BYTES_IN.get(),
+    );
+    encoder.counter(
+        "rsq_messages_out_total",
+        "Messages queued for delivery to peers.",
+        MESSAGES_OUT.get(),
+    );
+    encoder.counter(
+        "rsq_bytes_out_total",
+        "Bytes of messages queued for delivery to peers.",
+        BYTES_OUT.get(),
+    );
+    encoder.gauge(
+        "rsq_queued_messages",
+        "Messages waiting in peer queues.",
+        router.queued() as u64,
+    );
+    encoder.gauge(
+        "rsq_max_queued_messages",
+        "Messages waiting in the longest peer queue.",
+        router.max_queued() as u64,
+    );
+    encoder.counter(
+        "rsq_dropped_messages_total",
+        "Messages that expired or couldn't be delivered.",
+        DROPPED.get(),
+    );
+    encoder.counter(
+        "rsq_decode_errors_total",
+        "Frames, packets and commands from clients that couldn't be decoded.",
+        DECODE_ERRORS.get(),
+    );
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let mut encoder = Encoder::new();
+        encoder.counter("rsq_messages_in_total", "Messages published.", 3);
+        encoder.labeled_gauge(
+            "rsq_channel_subscribers",
+            "Subscribers of a channel.",
+            "channel",
+            vec![("chat", 2), ("say \"hi\"\\", 0)],
+        );
+
+        assert_eq!(
+            encoder.finish(),
+            "# HELP rsq_messages_in_total Messages published.\n\
+             # TYPE rsq_messages_in_total counter\n\
+             rsq_messages_in_total 3\n\
+             # HELP rsq_channel_subscribers Subscribers of a channel.\n\
+             # TYPE rsq_channel_subscribers gauge\n\
+             rsq_channel_subscribers{channel=\"chat\"} 2\n\
+             rsq_channel_subscribers{channel=\"say \\\"hi\\\"\\\\\"} 0\n"
+        );
+    }
+}
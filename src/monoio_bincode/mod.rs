@@ -1,18 +1,41 @@
 use bincode::{Decode, Encode};
-use bytes::{BufMut, Bytes, BytesMut};
-use monoio_codec::{length_delimited::LengthDelimitedCodec, Decoded, Decoder, Encoder};
-use std::{io, marker::PhantomData, sync::Arc};
+use bytes::{Bytes, BytesMut};
+use monoio_codec::{Decoded, Decoder, Encoder};
+use std::{cell::Cell, io, marker::PhantomData, rc::Rc, sync::Arc};
+
+use crate::compression::{self, Compression};
+use crate::wire;
+
+pub use crate::wire::MAX_FRAME_LEN;
+
+/// Compression setting shared between a codec and whoever negotiates it.
+pub type CompressionHandle = Rc<Cell<Option<Compression>>>;
 
 pub struct BincodeCodec<T> {
-    inner: LengthDelimitedCodec,
+    compression: CompressionHandle,
+    threshold: usize,
     _phantom: PhantomData<T>,
 }
 
+impl<T> Default for BincodeCodec<T> {
+    fn default() -> Self {
+        Self::with_compression(CompressionHandle::default(), compression::DEFAULT_THRESHOLD)
+    }
+}
+
 impl<T> BincodeCodec<T> {
     pub fn new() -> Self {
-        let inner = LengthDelimitedCodec::default();
+        Self::default()
+    }
+
+    /// Creates a codec that compresses frames of at least `threshold` bytes
+    /// with the algorithm currently set in `compression`.
+    ///
+    /// Compressed frames are always decoded.
+    pub fn with_compression(compression: CompressionHandle, threshold: usize) -> Self {
         Self {
-            inner,
+            compression,
+            threshold,
             _phantom: PhantomData,
         }
     }
@@ -27,44 +50,34 @@ impl<T: Decode<bool>> Decoder for BincodeCodec<T> {
         &mut self,
         src: &mut bytes::BytesMut,
     ) -> Result<monoio_codec::Decoded<Self::Item>, Self::Error> {
-        match self.inner.decode(src) {
-            Ok(Decoded::Some(bytes)) => {
-                let (data, size) = bincode::decode_from_slice_with_context(
-                    &bytes,
-                    bincode::config::standard(),
-                    false,
-                )
-                .unwrap();
-
-                //tracing::info!("size:{size}");
-
-                Ok(Decoded::Some(data))
-            }
-            Ok(Decoded::Insufficient) => Ok(Decoded::Insufficient),
-            Ok(Decoded::InsufficientAtLeast(n)) => Ok(Decoded::InsufficientAtLeast(n)),
-            Err(e) => Err(e),
+        let (len, compressed) = match wire::parse_prefix(src) {
+            Some(prefix) => prefix,
+            None => return Ok(Decoded::Insufficient),
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame too large",
+            ));
+        }
+        if src.len() < wire::PREFIX_LEN + len {
+            src.reserve(wire::PREFIX_LEN + len - src.len());
+            return Ok(Decoded::Insufficient);
         }
-    }
-}
 
-struct ByteMutBincodeWriter<'a> {
-    bytes: &'a mut BytesMut,
-}
+        let frame = src.split_to(wire::PREFIX_LEN + len);
+        let decompressed;
+        let body = if compressed {
+            decompressed = compression::decompress_body(&frame[wire::PREFIX_LEN..])?;
+            &decompressed[..]
+        } else {
+            &frame[wire::PREFIX_LEN..]
+        };
 
-impl bincode::enc::write::Writer for ByteMutBincodeWriter<'_> {
-    fn write(&mut self, bytes: &[u8]) -> Result<(), bincode::error::EncodeError> {
-        self.bytes.extend_from_slice(bytes);
-        Ok(())
-    }
-}
+        let (data, _size) = wire::decode_body(body, false)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-struct BincodeCountingWriter {
-    written: usize,
-}
-impl bincode::enc::write::Writer for BincodeCountingWriter {
-    fn write(&mut self, bytes: &[u8]) -> Result<(), bincode::error::EncodeError> {
-        self.written += bytes.len();
-        Ok(())
+        Ok(Decoded::Some(data))
     }
 }
 
@@ -72,16 +85,13 @@ impl<T: Encode> Encoder<Arc<T>> for BincodeCodec<T> {
     type Error = io::Error;
 
     fn encode(&mut self, data: Arc<T>, dst: &mut BytesMut) -> Result<(), io::Error> {
-        let mut counter = BincodeCountingWriter { written: 0 };
-        bincode::encode_into_writer(&data, &mut counter, bincode::config::standard())
-            .expect("encoding went well");
-
-        dst.reserve(counter.written + 4);
-        dst.put_u32(counter.written as u32);
+        if let Some(algorithm) = self.compression.get() {
+            let frame = compression::compress_frame(data.framed(), algorithm, self.threshold);
+            dst.extend_from_slice(&frame);
+            return Ok(());
+        }
 
-        let writer = ByteMutBincodeWriter { bytes: dst };
-        bincode::encode_into_writer(&data, writer, bincode::config::standard())
-            .expect("encoding went well");
+        wire::encode_frame(&data, dst).expect("encoding went well");
 
         Ok(())
     }
@@ -93,17 +103,48 @@ pub trait Framed {
 
 impl<T: Encode> Framed for T {
     fn framed(&self) -> Bytes {
-        let mut counter = BincodeCountingWriter { written: 0 };
-        bincode::encode_into_writer(self, &mut counter, bincode::config::standard())
-            .expect("encoding went well");
-
-        let mut dst = BytesMut::with_capacity(counter.written + 4);
-        dst.put_u32(counter.written as u32);
+        wire::frame(self)
+    }
+}
 
-        let writer = ByteMutBincodeWriter { bytes: &mut dst };
-        bincode::encode_into_writer(self, writer, bincode::config::standard())
-            .expect("encoding went well");
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messaging::channel::ChannelId;
+    use crate::messaging::msg::Msg;
+    use crate::messaging::peer::PeerId;
+
+    #[test]
+    fn negotiated_compression() {
+        let msg = Arc::new(Msg::new_channel_msg(
+            PeerId::new("peer"),
+            ChannelId::default(),
+            b"compressible ".repeat(100),
+        ));
+        let negotiated = CompressionHandle::default();
+        let mut encoder = BincodeCodec::<Msg>::with_compression(negotiated.clone(), 0);
+        let mut decoder = BincodeCodec::<Msg>::new();
+
+        // nothing negotiated yet
+        let mut buf = BytesMut::new();
+        encoder.encode(msg.clone(), &mut buf).unwrap();
+        assert!(!compression::is_compressed(&buf));
+        let plain_len = buf.len();
+        match decoder.decode(&mut buf).unwrap() {
+            Decoded::Some(decoded) => assert_eq!(decoded, msg),
+            _ => panic!("message not decoded"),
+        }
 
-        dst.freeze()
+        for algorithm in Compression::SUPPORTED {
+            negotiated.set(Some(*algorithm));
+            encoder.encode(msg.clone(), &mut buf).unwrap();
+            assert!(compression::is_compressed(&buf));
+            assert!(buf.len() < plain_len);
+            match decoder.decode(&mut buf).unwrap() {
+                Decoded::Some(decoded) => assert_eq!(decoded, msg),
+                _ => panic!("message not decoded"),
+            }
+            assert!(buf.is_empty());
+        }
     }
 }
@@ -0,0 +1,904 @@
+//! MQTT 3.1.1 and 5 packets, as far as the MQTT front-end needs them.
+//!
+//! Packets sent by clients are decoded, packets sent by the server are
+//! encoded. MQTT 5 properties sent by clients are skipped, and the server
+//! doesn't send any.
+//!
+//! The front-end (`listener()`) maps topics to channels of the same name.
+//! Topic filters with wildcards (`+`, `#`) aren't supported, subscribing to
+//! them fails.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use monoio::io::{AsyncReadRent, OwnedReadHalf, OwnedWriteHalf, Splitable};
+use monoio::net::{TcpListener, TcpStream};
+
+use crate::http;
+use crate::listener::ListenerConfig;
+use crate::messaging::channel::ChannelId;
+use crate::messaging::frame::Frame;
+use crate::messaging::msg::Msg;
+use crate::messaging::peer::{Peer, PeerId, PeerRx, PeerTx};
+use crate::messaging::queue::peer_queue;
+use crate::monoio_bincode::Framed;
+use crate::msg_stream::{FrameWriter, ReadBuffer};
+use crate::server::{
+    connection_peer_id, count_decode_error, pubsub_message, shutdown_requested, Endpoint, Shared,
+};
+use crate::wire;
+
+/// Protocol level of MQTT 3.1.1.
+pub const V311: u8 = 4;
+
+/// Protocol level of MQTT 5.
+pub const V5: u8 = 5;
+
+/// Largest packet accepted.
+pub const MAX_PACKET_LEN: usize = wire::MAX_FRAME_LEN;
+
+/// CONNACK return code for unsupported protocol levels.
+pub const UNACCEPTABLE_PROTOCOL: u8 = 0x01;
+
+/// SUBACK return code for rejected subscriptions (MQTT 3.1.1).
+pub const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+/// SUBACK reason code for filters with wildcards (MQTT 5).
+pub const WILDCARDS_UNSUPPORTED: u8 = 0xa2;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Packet {
+    Connect(Connect),
+    Publish(Publish),
+    PubAck(u16),
+    PubRec(u16),
+    PubRel(u16),
+    PubComp(u16),
+    Subscribe {
+        packet_id: u16,
+        /// Topic filters and their requested QoS.
+        filters: Vec<(String, u8)>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    PingReq,
+    Disconnect,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct Connect {
+    /// Protocol level, `V311` or `V5` for supported versions.
+    pub version: u8,
+    pub client_id: String,
+    pub clean_session: bool,
+    /// Keep alive interval in seconds, 0 if disabled.
+    pub keep_alive: u16,
+    /// Message to publish if the client disconnects without DISCONNECT.
+    pub will: Option<Publish>,
+    pub username: Option<String>,
+    pub password: Option<Bytes>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Publish {
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    /// Set for QoS 1 and 2.
+    pub packet_id: Option<u16>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    /// Parses a packet from the start of `buf`.
+    ///
+    /// `version` is the protocol level of the connection, it is ignored for
+    /// CONNECT packets, which carry their own.
+    ///
+    /// Returns the packet and its length, or `None` if `buf` doesn't hold a
+    /// complete packet yet.
+    pub fn parse(buf: &[u8], version: u8) -> io::Result<Option<(Packet, usize)>> {
+        let (len, header_len) = match remaining_length(buf)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if len > MAX_PACKET_LEN {
+            return Err(invalid_data("packet too large"));
+        }
+        if buf.len() < header_len + len {
+            return Ok(None);
+        }
+
+        let (kind, flags) = (buf[0] >> 4, buf[0] & 0x0f);
+        let mut body = Body(&buf[header_len..header_len + len]);
+
+        let packet = match kind {
+            CONNECT => Packet::Connect(Connect::parse(&mut body)?),
+            PUBLISH => Packet::Publish(Publish::parse(&mut body, flags, version)?),
+            PUBACK => Packet::PubAck(body.u16()?),
+            PUBREC => Packet::PubRec(body.u16()?),
+            PUBREL => Packet::PubRel(body.u16()?),
+            PUBCOMP => Packet::PubComp(body.u16()?),
+            SUBSCRIBE => {
+                let packet_id = body.u16()?;
+                body.properties(version)?;
+                let mut filters = Vec::new();
+                while !body.0.is_empty() {
+                    let filter = body.string()?;
+                    // MQTT 5 puts more subscription options in the upper bits
+                    filters.push((filter, body.u8()? & 0x03));
+                }
+                if filters.is_empty() {
+                    return Err(invalid_data("SUBSCRIBE without topic filters"));
+                }
+                Packet::Subscribe { packet_id, filters }
+            }
+            UNSUBSCRIBE => {
+                let packet_id = body.u16()?;
+                body.properties(version)?;
+                let mut filters = Vec::new();
+                while !body.0.is_empty() {
+                    filters.push(body.string()?);
+                }
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            PINGREQ => Packet::PingReq,
+            DISCONNECT => Packet::Disconnect,
+            _ => return Err(invalid_data(format!("unexpected packet type {kind}"))),
+        };
+
+        Ok(Some((packet, header_len + len)))
+    }
+}
+
+impl Connect {
+    fn parse(body: &mut Body) -> io::Result<Self> {
+        let protocol = body.string()?;
+        let version = body.u8()?;
+        if protocol != "MQTT" && protocol != "MQIsdp" {
+            return Err(invalid_data("not an MQTT connection"));
+        }
+        let flags = body.u8()?;
+        let keep_alive = body.u16()?;
+        let mut connect = Connect {
+            version,
+            client_id: String::new(),
+            clean_session: flags & 0x02 != 0,
+            keep_alive,
+            will: None,
+            username: None,
+            password: None,
+        };
+        if version != V311 && version != V5 {
+            // the rest might be laid out differently
+            return Ok(connect);
+        }
+
+        body.properties(version)?;
+        connect.client_id = body.string()?;
+        if flags & 0x04 != 0 {
+            body.properties(version)?;
+            connect.will = Some(Publish {
+                topic: body.string()?,
+                qos: (flags >> 3) & 0x03,
+                retain: flags & 0x20 != 0,
+                dup: false,
+                packet_id: None,
+                payload: body.binary()?,
+            });
+        }
+        if flags & 0x80 != 0 {
+            connect.username = Some(body.string()?);
+        }
+        if flags & 0x40 != 0 {
+            connect.password = Some(body.binary()?);
+        }
+
+        Ok(connect)
+    }
+}
+
+impl Publish {
+    fn parse(body: &mut Body, flags: u8, version: u8) -> io::Result<Self> {
+        let qos = (flags >> 1) & 0x03;
+        if qos > 2 {
+            return Err(invalid_data("invalid QoS"));
+        }
+        let topic = body.string()?;
+        let packet_id = if qos > 0 { Some(body.u16()?) } else { None };
+        body.properties(version)?;
+
+        Ok(Publish {
+            topic,
+            qos,
+            retain: flags & 0x01 != 0,
+            dup: flags & 0x08 != 0,
+            packet_id,
+            payload: Bytes::copy_from_slice(body.0),
+        })
+    }
+
+    /// Encodes the packet.
+    pub fn encode(&self, version: u8) -> Bytes {
+        let mut flags = self.qos << 1;
+        if self.retain {
+            flags |= 0x01;
+        }
+        if self.dup {
+            flags |= 0x08;
+        }
+
+        let mut body = BytesMut::with_capacity(self.topic.len() + self.payload.len() + 8);
+        put_string(&mut body, &self.topic);
+        if let Some(packet_id) = self.packet_id {
+            body.put_u16(packet_id);
+        }
+        if version == V5 {
+            body.put_u8(0);
+        }
+        body.extend_from_slice(&self.payload);
+
+        packet(PUBLISH << 4 | flags, &body)
+    }
+}
+
+/// Returns the CONNACK return code rejecting a client's user name or
+/// password.
+pub fn bad_credentials(version: u8) -> u8 {
+    match version {
+        V5 => 0x86,
+        _ => 0x04,
+    }
+}
+
+/// Returns a CONNACK packet.
+pub fn connack(version: u8, code: u8) -> Bytes {
+    let body: &[u8] = match version {
+        V5 => &[0, code, 0],
+        _ => &[0, code],
+    };
+    packet(CONNACK << 4, body)
+}
+
+/// Returns a PUBACK packet.
+pub fn puback(packet_id: u16) -> Bytes {
+    packet(PUBACK << 4, &packet_id.to_be_bytes())
+}
+
+/// Returns a PUBREC packet.
+pub fn pubrec(packet_id: u16) -> Bytes {
+    packet(PUBREC << 4, &packet_id.to_be_bytes())
+}
+
+/// Returns a PUBCOMP packet.
+pub fn pubcomp(packet_id: u16) -> Bytes {
+    packet(PUBCOMP << 4, &packet_id.to_be_bytes())
+}
+
+/// Returns a SUBACK packet with a return code per topic filter.
+pub fn suback(version: u8, packet_id: u16, codes: &[u8]) -> Bytes {
+    let mut body = BytesMut::with_capacity(3 + codes.len());
+    body.put_u16(packet_id);
+    if version == V5 {
+        body.put_u8(0);
+    }
+    body.extend_from_slice(codes);
+    packet(SUBACK << 4, &body)
+}
+
+/// Returns an UNSUBACK packet for `count` topic filters.
+pub fn unsuback(version: u8, packet_id: u16, count: usize) -> Bytes {
+    let mut body = BytesMut::with_capacity(3 + count);
+    body.put_u16(packet_id);
+    if version == V5 {
+        // properties, and success for every filter
+        body.put_u8(0);
+        body.put_bytes(0, count);
+    }
+    packet(UNSUBACK << 4, &body)
+}
+
+/// Returns a PINGRESP packet.
+pub fn pingresp() -> Bytes {
+    packet(PINGRESP << 4, &[])
+}
+
+/// Returns `true` if `filter` contains wildcards.
+pub fn has_wildcards(filter: &str) -> bool {
+    filter.contains(['+', '#'])
+}
+
+/// Reads the packets sent by a client.
+pub struct PacketReader<IO> {
+    reader: ReadBuffer<IO>,
+}
+
+impl<IO: AsyncReadRent> PacketReader<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            reader: ReadBuffer::new(io),
+        }
+    }
+
+    /// Returns the next packet.
+    pub async fn next(&mut self, version: u8) -> Option<io::Result<Packet>> {
+        loop {
+            match Packet::parse(self.reader.buffered(), version) {
+                Ok(Some((packet, len))) => {
+                    self.reader.split_to(len);
+                    return Some(Ok(packet));
+                }
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+
+            let buffered = self.reader.buffered().len();
+            if let Err(e) = self.reader.fill(buffered + 1).await {
+                return match e.kind() {
+                    io::ErrorKind::UnexpectedEof if buffered == 0 => None,
+                    _ => Some(Err(e)),
+                };
+            }
+        }
+    }
+}
+
+/// Parses the fixed header's remaining length.
+///
+/// Returns the remaining length and the length of the fixed header.
+fn remaining_length(buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let mut len = 0;
+    for i in 0..4 {
+        let byte = match buf.get(1 + i) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((len, 2 + i)));
+        }
+    }
+    Err(invalid_data("invalid remaining length"))
+}
+
+fn put_remaining_length(buf: &mut BytesMut, mut len: usize) {
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            buf.put_u8(byte);
+            return;
+        }
+        buf.put_u8(byte | 0x80);
+    }
+}
+
+fn put_string(buf: &mut BytesMut, s: &str) {
+    buf.put_u16(s.len() as u16);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn packet(first_byte: u8, body: &[u8]) -> Bytes {
+    let mut packet = BytesMut::with_capacity(5 + body.len());
+    packet.put_u8(first_byte);
+    put_remaining_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet.freeze()
+}
+
+/// Cursor over a packet's variable header and payload.
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("malformed packet"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(self.take(2)?.get_u16())
+    }
+
+    fn binary(&mut self) -> io::Result<Bytes> {
+        let len = self.u16()? as usize;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        let s = std::str::from_utf8(self.take(len)?).map_err(invalid_data)?;
+        Ok(s.to_string())
+    }
+
+    /// Reads a variable byte integer.
+    fn varint(&mut self) -> io::Result<usize> {
+        let mut value = 0;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("malformed variable byte integer"))
+    }
+
+    /// Skips MQTT 5 properties.
+    fn properties(&mut self, version: u8) -> io::Result<()> {
+        if version == V5 {
+            let len = self.varint()?;
+            self.take(len)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// `Peer` handle for MQTT clients.
+///
+/// Topics map to channels of the same name.
+struct MqttPeer {
+    tx: PeerTx,
+
+    peer_id: PeerId,
+
+    /// Subscribed channels, with their topic name and granted QoS.
+    subscriptions: Rc<RefCell<HashMap<ChannelId, (String, u8)>>>,
+}
+
+impl Peer for MqttPeer {
+    fn get_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        &self.tx
+    }
+}
+
+/// Accepts MQTT connections.
+pub async fn listener(state: Rc<RefCell<Shared>>, listener: TcpListener, endpoint: Rc<Endpoint>) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("MQTT accept error: {e}");
+                continue;
+            }
+        };
+        if !endpoint.config.allows(addr.ip()) {
+            tracing::info!("rejecting connection from {}", addr);
+            continue;
+        }
+        let _ = stream.set_nodelay(true);
+
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+        monoio::spawn(async move {
+            endpoint.opened();
+            if let Err(e) = process_mqtt(state, stream, addr, &endpoint.config).await {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+            endpoint.closed();
+        });
+    }
+}
+
+/// Time an MQTT client has to send CONNECT after connecting.
+const MQTT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Process an individual MQTT client
+async fn process_mqtt(
+    state: Rc<RefCell<Shared>>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: &ListenerConfig,
+) -> Result<(), Box<dyn Error>> {
+    let peer_addr = stream.peer_addr()?;
+    let (stream_in, stream_out) = stream.into_split();
+    let mut reader = PacketReader::new(stream_in);
+    let mut writer = FrameWriter::new(stream_out);
+
+    let connect = match monoio::time::timeout(MQTT_CONNECT_TIMEOUT, reader.next(V311)).await {
+        Ok(Some(Ok(Packet::Connect(connect)))) => connect,
+        Ok(Some(Err(e))) => return Err(e.into()),
+        Ok(_) => return Err("expected CONNECT".into()),
+        Err(_) => return Err("timed out waiting for CONNECT".into()),
+    };
+    let version = connect.version;
+    if version != V311 && version != V5 {
+        writer.push(connack(V311, UNACCEPTABLE_PROTOCOL));
+        writer.flush().await?;
+        return Err(format!("unsupported MQTT protocol level {version}").into());
+    }
+    // the listener's token is expected as password, the user name is ignored
+    if let Some(token) = &config.token {
+        let valid = match &connect.password {
+            Some(password) => http::secret_eq(password, token.as_bytes()),
+            None => false,
+        };
+        if !valid {
+            writer.push(connack(version, bad_credentials(version)));
+            writer.flush().await?;
+            return Err("invalid MQTT password".into());
+        }
+    }
+    writer.push(connack(version, 0));
+    writer.flush().await?;
+
+    let (tx, rx) = peer_queue();
+    let peer = MqttPeer {
+        tx,
+        peer_id: connection_peer_id(config.kind, peer_addr),
+        subscriptions: Rc::default(),
+    };
+
+    let peer_name = addr.to_string();
+    tracing::info!(
+        "new MQTT connection from {} (client id {:?})",
+        &peer_name,
+        connect.client_id
+    );
+
+    {
+        let mut state = state.borrow_mut();
+        state
+            .connections
+            .insert(peer.get_id().clone(), peer.tx.clone());
+        state.router.peer_add(&peer);
+    }
+
+    let peer_id = peer.get_id().clone();
+    let subscriptions = peer.subscriptions.clone();
+
+    // acknowledgements, sent alongside the peer's messages
+    let (control_tx, control_rx) = flume::unbounded();
+
+    let from_client_handle = monoio::spawn(mqtt_from_client(
+        state.clone(),
+        reader,
+        peer,
+        version,
+        connect.keep_alive,
+        control_tx,
+    ));
+    let to_client_handle = monoio::spawn(mqtt_to_client(
+        rx,
+        control_rx,
+        writer,
+        subscriptions,
+        version,
+    ));
+
+    let mut disconnected = false;
+    monoio::select!(
+        e = from_client_handle => {
+            match e {
+                Ok(clean) => disconnected = clean,
+                Err(e) => tracing::error!("{peer_name}: {e}"),
+            }
+        },
+        e = to_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        }
+    );
+
+    {
+        let mut state = state.borrow_mut();
+        state.connections.remove(&peer_id);
+        state.router.peer_remove(&peer_id);
+
+        // the will is published if the client went away without DISCONNECT
+        if let Some(will) = connect.will.filter(|_| !disconnected) {
+            mqtt_publish(&mut state, &peer_id, will);
+        }
+    }
+
+    tracing::info!("{peer_name} disconnected");
+
+    Ok(())
+}
+
+/// Handles the packets of an MQTT client.
+///
+/// Returns `true` if the client disconnected cleanly, with DISCONNECT.
+async fn mqtt_from_client(
+    state: Rc<RefCell<Shared>>,
+    mut reader: PacketReader<OwnedReadHalf<TcpStream>>,
+    peer: MqttPeer,
+    version: u8,
+    keep_alive: u16,
+    control: flume::Sender<Bytes>,
+) -> Result<bool, anyhow::Error> {
+    // clients have to send something within one and a half keep alive
+    // intervals
+    let timeout = Duration::from_millis(keep_alive as u64 * 1500);
+    // packet ids of QoS 2 messages forwarded, but not released with PUBREL
+    // yet
+    let mut unreleased = HashSet::new();
+
+    loop {
+        let packet = if keep_alive > 0 {
+            match monoio::time::timeout(timeout, reader.next(version)).await {
+                Ok(packet) => packet,
+                Err(_) => anyhow::bail!("keep alive timeout"),
+            }
+        } else {
+            reader.next(version).await
+        };
+
+        let packet = match packet {
+            Some(Ok(packet)) => packet,
+            Some(Err(e)) => {
+                count_decode_error(&e);
+                tracing::info!("mqtt_from_client error: {e}");
+                return Ok(false);
+            }
+            None => return Ok(false),
+        };
+
+        match packet {
+            Packet::Publish(publish) => match (publish.qos, publish.packet_id) {
+                (1, Some(packet_id)) => {
+                    mqtt_publish(&mut state.borrow_mut(), peer.get_id(), publish);
+                    let _ = control.send(puback(packet_id));
+                }
+                // QoS 2 messages are forwarded right away, retransmissions
+                // are only acknowledged until the client releases the
+                // packet id
+                (2, Some(packet_id)) => {
+                    if unreleased.insert(packet_id) {
+                        mqtt_publish(&mut state.borrow_mut(), peer.get_id(), publish);
+                    }
+                    let _ = control.send(pubrec(packet_id));
+                }
+                _ => mqtt_publish(&mut state.borrow_mut(), peer.get_id(), publish),
+            },
+            Packet::PubRel(packet_id) => {
+                unreleased.remove(&packet_id);
+                let _ = control.send(pubcomp(packet_id));
+            }
+            // messages are sent to clients at most with QoS 1, but not
+            // redelivered
+            Packet::PubAck(_) | Packet::PubRec(_) | Packet::PubComp(_) => {}
+            Packet::Subscribe { packet_id, filters } => {
+                let mut codes = Vec::with_capacity(filters.len());
+                let mut state = state.borrow_mut();
+                for (topic, qos) in filters {
+                    if has_wildcards(&topic) {
+                        codes.push(match version {
+                            V5 => WILDCARDS_UNSUPPORTED,
+                            _ => SUBSCRIBE_FAILURE,
+                        });
+                        continue;
+                    }
+                    let qos = qos.min(1);
+                    let channel_id = state.router.channel_get_or_add(topic.clone());
+                    match state.router.attach(channel_id, &peer) {
+                        Ok(()) => {
+                            peer.subscriptions
+                                .borrow_mut()
+                                .insert(channel_id, (topic, qos));
+                            codes.push(qos);
+                        }
+                        Err(_) => codes.push(SUBSCRIBE_FAILURE),
+                    }
+                }
+                let _ = control.send(suback(version, packet_id, &codes));
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                let mut state = state.borrow_mut();
+                for topic in &filters {
+                    let channel_id = peer
+                        .subscriptions
+                        .borrow()
+                        .iter()
+                        .find(|(_, (subscribed, _))| subscribed == topic)
+                        .map(|(channel_id, _)| *channel_id);
+                    if let Some(channel_id) = channel_id {
+                        state.router.detach(channel_id, &peer)?;
+                        peer.subscriptions.borrow_mut().remove(&channel_id);
+                    }
+                }
+                let _ = control.send(unsuback(version, packet_id, filters.len()));
+            }
+            Packet::PingReq => {
+                let _ = control.send(pingresp());
+            }
+            Packet::Disconnect => return Ok(true),
+            Packet::Connect(_) => anyhow::bail!("unexpected CONNECT"),
+        }
+    }
+}
+
+/// Publishes an MQTT message to the channel named like its topic.
+///
+/// Retained messages replace the channel's retained message, or clear it if
+/// they are empty. Other messages leave it alone, unless the channel retains
+/// all messages.
+fn mqtt_publish(state: &mut Shared, peer_id: &PeerId, publish: Publish) {
+    if !state.router.may_publish(peer_id, &publish.topic) {
+        tracing::debug!("{peer_id:?} may not publish to {}", publish.topic);
+        return;
+    }
+
+    let channel_id = state.router.channel_get_or_add(publish.topic);
+    let msg = Msg::new_channel_msg(peer_id.clone(), channel_id, publish.payload.to_vec());
+    let frame = Frame::new(msg.framed());
+    let _ = match publish.retain {
+        true => state.router.forward_retained(frame, channel_id, peer_id),
+        false => state.router.forward(frame, channel_id, peer_id),
+    };
+}
+
+async fn mqtt_to_client(
+    mut rx: PeerRx,
+    control: flume::Receiver<Bytes>,
+    mut writer: FrameWriter<OwnedWriteHalf<TcpStream>>,
+    subscriptions: Rc<RefCell<HashMap<ChannelId, (String, u8)>>>,
+    version: u8,
+) -> Result<(), anyhow::Error> {
+    let mut next_packet_id: u16 = 0;
+    loop {
+        monoio::select! {
+            frame = rx.recv_async() => {
+                let mut next = Some(frame?);
+                while let Some(frame) = next.take() {
+                    if let Some(msg) = pubsub_message(&frame) {
+                        if let Some((topic, qos)) = subscriptions.borrow().get(&msg.channel()) {
+                            let packet_id = (*qos > 0).then(|| {
+                                next_packet_id = next_packet_id.checked_add(1).unwrap_or(1);
+                                next_packet_id
+                            });
+                            let publish = Publish {
+                                topic: topic.clone(),
+                                qos: *qos,
+                                retain: false,
+                                dup: false,
+                                packet_id,
+                                payload: msg.content().clone().into(),
+                            };
+                            writer.push(publish.encode(version));
+                        }
+                    }
+                    if !writer.is_full() {
+                        next = rx.try_recv();
+                    }
+                }
+            }
+            control = control.recv_async() => {
+                match control {
+                    Ok(packet) => writer.push(packet),
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connect() {
+        let packet = [
+            0x10, 0x16, // CONNECT
+            0, 4, b'M', b'Q', b'T', b'T', V311, 0x02, 0, 60, // clean session
+            0, 10, b'r', b's', b'q', b'-', b'c', b'l', b'i', b'e', b'n', b't',
+        ];
+
+        assert!(Packet::parse(&packet[..10], V311).unwrap().is_none());
+
+        let (parsed, len) = Packet::parse(&packet, V311).unwrap().unwrap();
+        assert_eq!(len, packet.len());
+        assert_eq!(
+            parsed,
+            Packet::Connect(Connect {
+                version: V311,
+                client_id: "rsq-client".to_string(),
+                clean_session: true,
+                keep_alive: 60,
+                will: None,
+                username: None,
+                password: None,
+            })
+        );
+    }
+
+    #[test]
+    fn publish() {
+        for version in [V311, V5] {
+            let publish = Publish {
+                topic: "sensors/1".to_string(),
+                qos: 1,
+                retain: true,
+                dup: false,
+                packet_id: Some(7),
+                payload: Bytes::from_static(b"21.5"),
+            };
+            let packet = publish.encode(version);
+            assert_eq!(
+                Packet::parse(&packet, version).unwrap().unwrap(),
+                (Packet::Publish(publish), packet.len())
+            );
+        }
+    }
+
+    #[test]
+    fn subscribe() {
+        // MQTT 5, with an empty property section
+        let packet = [
+            0x82, 11, 0, 1, 0, // packet id, properties
+            0, 5, b'a', b'/', b'b', b'/', b'c', 0x01,
+        ];
+
+        let (parsed, _) = Packet::parse(&packet, V5).unwrap().unwrap();
+        assert_eq!(
+            parsed,
+            Packet::Subscribe {
+                packet_id: 1,
+                filters: vec![("a/b/c".to_string(), 1)],
+            }
+        );
+        assert_eq!(&suback(V5, 1, &[1])[..], &[0x90, 4, 0, 1, 0, 1]);
+        assert_eq!(&suback(V311, 1, &[1])[..], &[0x90, 3, 0, 1, 1]);
+    }
+
+    #[test]
+    fn remaining() {
+        for len in [0, 127, 128, 16383, 16384, 2_097_152] {
+            let mut buf = BytesMut::from(&[0x30][..]);
+            put_remaining_length(&mut buf, len);
+            assert_eq!(remaining_length(&buf).unwrap(), Some((len, buf.len())));
+        }
+        assert!(remaining_length(&[0x30, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
@@ -1,140 +1,437 @@
-use std::{convert::TryInto, io::ErrorKind};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::ops::{Deref, DerefMut};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use monoio::{
-    buf::{IoBuf, IoBufMut, SliceMut},
-    io::{AsyncBufRead, AsyncReadRent, AsyncReadRentExt, BufReader},
-    BufResult,
+    buf::{IoBufMut, IoVecBuf},
+    io::{AsyncReadRent, AsyncWriteRent},
 };
 
-use crate::messaging::msg::{ChannelMsgHdr, Msg};
+use crate::wire;
 
-pub struct FrameDecoder<IO> {
-    io: BufReader<IO>,
+/// Size of the reads `ReadBuffer` does.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Maximum number of frames `FrameWriter` writes with a single `writev`.
+pub const MAX_BATCH: usize = 64;
+
+/// Maximum number of read buffers kept in a thread's pool.
+const MAX_POOLED: usize = 64;
+
+thread_local! {
+    /// Read buffers given back by `ReadBuffer`s, see `PooledBuf`.
+    static POOL: RefCell<VecDeque<BytesMut>> = const { RefCell::new(VecDeque::new()) };
 }
 
-// trait PeekBufReader {
-//     async fn peek(&mut self, &mut [u8]) -> std::io::Result<()>;
-// }
-
-// impl<R: AsyncReadRent> PeekBufReader for BufReader<R> {
-//     async fn peek(&mut self, target: &mut [u8]) -> std::io::Result<()> {
-//         let mut nread = 0;
-//         while nread < target.len() {
-//             let buffered = self.fill_buf().await?;
-//             let available = buffered.len();
-//             let remaining = target.len() - nread;
-//             let to_copy = available.min(remaining);
-//             target[nread..].copy_from_slice(&buffered[..to_copy]);
-//             nread += available;
-//         }
-//         Ok(())
-//     }
-// }
+/// A read buffer, taken from the thread's pool and given back when dropped.
+///
+/// Parts split off a buffer keep its memory alive. A buffer given back with
+/// parts still around is only handed out again once they are all dropped,
+/// `BytesMut::try_reclaim()` tells. When the pool is full, the buffers given
+/// back first are dropped from it.
+struct PooledBuf(BytesMut);
 
-impl<IO> FrameDecoder<IO> {
+impl PooledBuf {
+    /// Takes a buffer with room for at least `len` bytes from the pool, or
+    /// allocates one.
+    fn take(len: usize) -> Self {
+        let pooled = POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            // only succeeds once no parts of the buffer are left
+            let reusable = pool.iter_mut().position(|buf| buf.try_reclaim(len))?;
+            pool.remove(reusable)
+        });
+        Self(pooled.unwrap_or_else(|| BytesMut::with_capacity(len.max(READ_BUFFER_SIZE))))
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.0);
+        buf.clear();
+        let _ = POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() == MAX_POOLED {
+                pool.pop_front();
+            }
+            pool.push_back(buf);
+        });
+    }
+}
+
+/// Buffered reader handing out parts of its buffer without copying.
+///
+/// Data is read in large chunks into a buffer taken from a per-thread pool,
+/// and parts are split off that buffer. Once all parts split off a buffer
+/// are dropped, its memory is reused for further reads, by this reader or,
+/// after it was dropped, by another one.
+pub struct ReadBuffer<IO> {
+    io: IO,
+    buf: PooledBuf,
+}
+
+impl<IO> ReadBuffer<IO> {
     pub fn new(io: IO) -> Self {
         Self {
-            io: BufReader::new(io),
+            io,
+            buf: PooledBuf::take(READ_BUFFER_SIZE),
         }
     }
 
-    pub async fn next(&mut self) -> Option<std::io::Result<BytesMut>>
+    /// Returns the underlying stream, dropping buffered data.
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+
+    /// Returns the data read but not yet split off.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Splits off the first `len` buffered bytes.
+    pub fn split_to(&mut self, len: usize) -> BytesMut {
+        self.buf.split_to(len)
+    }
+
+    /// Reads until at least `len` bytes are buffered.
+    pub async fn fill(&mut self, len: usize) -> io::Result<()>
     where
         IO: AsyncReadRent,
     {
-        let mut buf = BytesMut::with_capacity(1024);
-        let (size, nread) = {
-            let size: u32;
-            let nread: usize;
-            let buf_content = self.io.fill_buf().await.unwrap();
-            if buf_content.len() >= size_of::<u32>() {
-                size = u32::from_be_bytes(buf_content[0..4].try_into().unwrap());
-                nread = 0;
-            } else {
-                let (res, buf_back) = self.read_exact_n(buf, 0, 4).await;
-                buf = buf_back;
-                nread = match res {
-                    Ok(0) => return None,
-                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
-                    Err(e) => return Some(Err(e)),
-                    Ok(n) => n,
-                };
+        while self.buf.len() < len {
+            let room = (len - self.buf.len()).max(READ_BUFFER_SIZE);
+            if !self.buf.try_reclaim(room) {
+                // parts split off the buffer are still around, continue in
+                // another one
+                let mut buf = PooledBuf::take(self.buf.len() + room);
+                buf.extend_from_slice(&self.buf);
+                self.buf = buf;
+            }
 
-                size = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+            let buf = std::mem::take(&mut *self.buf);
+            let start = buf.len();
+            let end = buf.capacity();
+            let (res, slice) = self.io.read(buf.slice_mut(start..end)).await;
+            *self.buf = slice.into_inner();
+
+            match res {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed",
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
-            (size, nread)
-        };
-
-        //tracing::info!("2. got size={size}");
-        let whole_frame_size = size + 4;
-        let to_read = whole_frame_size as usize - nread;
-
-        buf.reserve(to_read);
-
-        // tracing::info!(
-        //     "buf len:{} whole_frame_size={whole_frame_size} to_read={to_read}",
-        //     buf.len()
-        // );
-        let (res, buf) = {
-            let slice = buf.slice_mut(nread..whole_frame_size as usize);
-            // tracing::info!("begin={} end={}", slice.begin(), slice.end());
-            let (res, slice) = self.io.read_exact(slice).await;
-            (res, slice.into_inner())
-        };
-
-        let nread = match res {
-            Ok(0) => return None,
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
-            Err(e) => return Some(Err(e)),
-            Ok(n) => n,
-        } + nread;
-        // tracing::info!("buf nread={nread} init={}", buf.bytes_init());
-
-        Some(Ok(buf))
-    }
-
-    async fn read_exact_n<T: IoBufMut + 'static>(
-        &mut self,
-        mut buf: T,
-        start: usize,
-        len: usize,
-    ) -> BufResult<usize, T>
+        }
+        Ok(())
+    }
+}
+
+/// Splits a stream into frames.
+pub struct FrameDecoder<IO> {
+    reader: ReadBuffer<IO>,
+    /// Largest frame body accepted.
+    max_len: usize,
+}
+
+impl<IO> FrameDecoder<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            reader: ReadBuffer::new(io),
+            max_len: wire::MAX_FRAME_LEN,
+        }
+    }
+
+    /// Rejects frames with bodies larger than `max_len`.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Returns the next frame, including its length prefix.
+    pub async fn next(&mut self) -> Option<io::Result<BytesMut>>
     where
         IO: AsyncReadRent,
     {
-        let mut read = start;
+        if let Err(e) = self.reader.fill(wire::PREFIX_LEN).await {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof if self.reader.buffered().is_empty() => None,
+                _ => Some(Err(e)),
+            };
+        }
+
+        // the compression flag is not part of the size
+        let (size, _compressed) = wire::parse_prefix(self.reader.buffered()).unwrap();
+        if size > self.max_len {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame too large",
+            )));
+        }
+        let len = wire::PREFIX_LEN + size;
+
+        if let Err(e) = self.reader.fill(len).await {
+            return Some(Err(e));
+        }
+
+        Some(Ok(self.reader.split_to(len)))
+    }
+}
+
+/// Frames queued for a single vectored write.
+#[derive(Default)]
+struct FrameBatch {
+    frames: VecDeque<Bytes>,
+    iovecs: Vec<libc::iovec>,
+}
 
-        while read < len {
-            let buf_slice = unsafe { SliceMut::new_unchecked(buf, read, len) };
+impl FrameBatch {
+    /// Points the iovecs at the queued frames.
+    fn prepare(&mut self) {
+        self.iovecs.clear();
+        self.iovecs
+            .extend(self.frames.iter().map(|frame| libc::iovec {
+                iov_base: frame.as_ptr() as *mut libc::c_void,
+                iov_len: frame.len(),
+            }));
+    }
 
-            let (result, buf_slice) = self.io.read(buf_slice).await;
+    /// Drops `n` written bytes from the front of the batch.
+    fn advance(&mut self, mut n: usize) {
+        while n > 0 {
+            let front = self.frames.front_mut().unwrap();
+            if n < front.len() {
+                front.advance(n);
+                return;
+            }
+            n -= front.len();
+            self.frames.pop_front();
+        }
+    }
+}
 
-            buf = buf_slice.into_inner();
+// The iovecs point into the frames' `Bytes`, which stay put while the batch
+// is moved into a write.
+unsafe impl IoVecBuf for FrameBatch {
+    fn read_iovec_ptr(&self) -> *const libc::iovec {
+        self.iovecs.as_ptr()
+    }
 
-            match result {
+    fn read_iovec_len(&self) -> usize {
+        self.iovecs.len()
+    }
+}
+
+/// Writes frames to a stream, several at a time, without copying them.
+pub struct FrameWriter<IO> {
+    io: IO,
+    batch: Option<FrameBatch>,
+}
+
+impl<IO> FrameWriter<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            io,
+            batch: Some(FrameBatch::default()),
+        }
+    }
+
+    /// Queues `frame` for the next `flush()`.
+    pub fn push(&mut self, frame: Bytes) {
+        if !frame.is_empty() {
+            self.batch.as_mut().unwrap().frames.push_back(frame);
+        }
+    }
+
+    /// Returns `true` if no more frames should be queued before flushing.
+    pub fn is_full(&self) -> bool {
+        self.batch.as_ref().unwrap().frames.len() >= MAX_BATCH
+    }
+
+    /// Writes all queued frames.
+    pub async fn flush(&mut self) -> io::Result<()>
+    where
+        IO: AsyncWriteRent,
+    {
+        let mut batch = self.batch.take().unwrap();
+        let mut res = Ok(());
+
+        while !batch.frames.is_empty() {
+            batch.prepare();
+            let (written, batch_back) = self.io.writev(batch).await;
+            batch = batch_back;
+
+            match written {
                 Ok(0) => {
-                    return (
-                        Err(std::io::Error::new(
-                            std::io::ErrorKind::UnexpectedEof,
-                            "failed to fill whole buffer",
-                        )),
-                        buf,
-                    )
+                    res = Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write frames",
+                    ));
+                    break;
+                }
+                Ok(n) => batch.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    res = Err(e);
+                    break;
                 }
+            }
+        }
 
-                Ok(n) => {
-                    read += n;
+        batch.frames.clear();
+        batch.iovecs.clear();
+        self.batch = Some(batch);
+        res
+    }
+}
 
-                    unsafe { buf.set_init(read) };
-                }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use monoio::buf::IoBuf;
+    use monoio::BufResult;
 
-                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+    /// Writer accepting at most `max` bytes per write, and failing the first
+    /// write with `Interrupted` if `interrupt` is set.
+    #[derive(Default)]
+    struct ShortWriter {
+        out: Vec<u8>,
+        max: usize,
+        interrupt: bool,
+        writes: usize,
+    }
 
-                Err(e) => return (Err(e), buf),
+    impl AsyncWriteRent for ShortWriter {
+        async fn write<T: IoBuf>(&mut self, buf: T) -> BufResult<usize, T> {
+            (Err(io::ErrorKind::Unsupported.into()), buf)
+        }
+
+        async fn writev<T: IoVecBuf>(&mut self, buf: T) -> BufResult<usize, T> {
+            self.writes += 1;
+            if std::mem::take(&mut self.interrupt) {
+                return (Err(io::ErrorKind::Interrupted.into()), buf);
+            }
+            let iovecs =
+                unsafe { std::slice::from_raw_parts(buf.read_iovec_ptr(), buf.read_iovec_len()) };
+            let mut n = 0;
+            for iovec in iovecs {
+                let data = unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                };
+                let len = data.len().min(self.max - n);
+                self.out.extend_from_slice(&data[..len]);
+                n += len;
             }
+            (Ok(n), buf)
+        }
+
+        async fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn shutdown(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frames(batch: &FrameBatch) -> Vec<&[u8]> {
+        batch.frames.iter().map(|frame| &frame[..]).collect()
+    }
+
+    #[test]
+    fn pool() {
+        let mut buf = PooledBuf::take(READ_BUFFER_SIZE);
+        let first = buf.as_ptr();
+        buf.extend_from_slice(b"frame");
+        let frame = buf.split_to(5).freeze();
+        drop(buf);
+
+        // a buffer with parts still around isn't handed out again
+        let buf = PooledBuf::take(READ_BUFFER_SIZE);
+        assert_ne!(buf.as_ptr(), first);
+        drop(buf);
+
+        drop(frame);
+        let buf = PooledBuf::take(READ_BUFFER_SIZE);
+        assert_eq!(buf.as_ptr(), first);
+        assert!(buf.capacity() >= READ_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn advance() {
+        let mut batch = FrameBatch::default();
+        for frame in [&b"abc"[..], b"de", b"fgh"] {
+            batch.frames.push_back(Bytes::from_static(frame));
         }
 
-        (Ok(read), buf)
+        // within the first frame
+        batch.advance(1);
+        assert_eq!(frames(&batch), vec![&b"bc"[..], b"de", b"fgh"]);
+        // up to the end of a frame
+        batch.advance(2);
+        assert_eq!(frames(&batch), vec![&b"de"[..], b"fgh"]);
+        // across frames
+        batch.advance(3);
+        assert_eq!(frames(&batch), vec![&b"gh"[..]]);
+        batch.advance(2);
+        assert!(batch.frames.is_empty());
+
+        batch.prepare();
+        assert!(batch.iovecs.is_empty());
+    }
+
+    #[monoio::test]
+    async fn partial_writes() {
+        let mut writer = FrameWriter::new(ShortWriter {
+            max: 4,
+            interrupt: true,
+            ..Default::default()
+        });
+        writer.push(Bytes::from_static(b"hello"));
+        writer.push(Bytes::new());
+        writer.push(Bytes::from_static(b" "));
+        writer.push(Bytes::from_static(b"world"));
+        writer.flush().await.unwrap();
+
+        // an interrupted write, then 11 bytes at most 4 at a time
+        assert_eq!(writer.io.out, b"hello world");
+        assert_eq!(writer.io.writes, 4);
+
+        // the batch is reused
+        writer.push(Bytes::from_static(b"!"));
+        writer.flush().await.unwrap();
+        assert_eq!(writer.io.out, b"hello world!");
+    }
+
+    #[monoio::test]
+    async fn write_zero() {
+        let mut writer = FrameWriter::new(ShortWriter::default());
+        writer.push(Bytes::from_static(b"hello"));
+        let err = writer.flush().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        // queued frames are dropped
+        writer.io.max = 8;
+        writer.flush().await.unwrap();
+        assert!(writer.io.out.is_empty());
     }
 }
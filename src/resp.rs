@@ -0,0 +1,807 @@
+//! The Redis serialization protocol (RESP2), as far as the Redis pub/sub
+//! compatible listener needs it.
+//!
+//! Clients send commands as arrays of bulk strings, or as inline commands
+//! (a line of space separated words).
+//!
+//! The listener (`listener()`) supports the pub/sub commands, channels are
+//! shared with the other front-ends.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::rc::Rc;
+
+use bytes::{Bytes, BytesMut};
+use monoio::io::{AsyncReadRent, OwnedReadHalf, OwnedWriteHalf, Splitable};
+use monoio::net::{TcpListener, TcpStream};
+
+use crate::http;
+use crate::listener::ListenerConfig;
+use crate::messaging::channel::ChannelId;
+use crate::messaging::errors::TxError;
+use crate::messaging::frame::Frame;
+use crate::messaging::msg::Msg;
+use crate::messaging::pattern;
+use crate::messaging::peer::{Peer, PeerId, PeerRx, PeerTx};
+use crate::messaging::queue::peer_queue;
+use crate::monoio_bincode::Framed;
+use crate::msg_stream::{FrameWriter, ReadBuffer};
+use crate::server::{
+    connection_peer_id, count_decode_error, pubsub_message, shutdown_requested, Endpoint, Shared,
+};
+use crate::wire;
+
+/// Largest command accepted.
+pub const MAX_COMMAND_LEN: usize = wire::MAX_FRAME_LEN;
+
+/// Largest number of arguments of a command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Longest inline command.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Returns a bulk string value.
+    pub fn bulk(data: impl Into<Bytes>) -> Self {
+        Value::Bulk(Some(data.into()))
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Value::Simple(s) => put_line(buf, b'+', s.as_bytes()),
+            Value::Error(s) => put_line(buf, b'-', s.as_bytes()),
+            Value::Integer(i) => put_line(buf, b':', i.to_string().as_bytes()),
+            Value::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+            Value::Bulk(Some(data)) => {
+                put_line(buf, b'$', data.len().to_string().as_bytes());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Array(values) => {
+                put_line(buf, b'*', values.len().to_string().as_bytes());
+                for value in values {
+                    value.encode(buf);
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        buf.freeze()
+    }
+}
+
+/// Parses a command from the start of `buf`.
+///
+/// Returns the command's arguments (including the command name) and its
+/// length, or `None` if `buf` doesn't hold a complete command yet.
+pub fn parse_command(buf: &[u8]) -> io::Result<Option<(Vec<Bytes>, usize)>> {
+    match Parser::default().parse(buf)? {
+        Parsed::Complete(args, len) => {
+            let args = args
+                .into_iter()
+                .map(|arg| Bytes::copy_from_slice(&buf[arg]))
+                .collect();
+            Ok(Some((args, len)))
+        }
+        Parsed::Incomplete(_) => Ok(None),
+    }
+}
+
+enum Parsed {
+    /// The ranges of the command's arguments, and its length.
+    Complete(Vec<Range<usize>>, usize),
+    /// The command is incomplete, and at least this many bytes are needed
+    /// before parsing can continue.
+    Incomplete(usize),
+}
+
+/// Parser of a single command, keeping its progress while the command is
+/// incomplete, so that the arguments already parsed aren't parsed again
+/// once more data arrives.
+#[derive(Default)]
+struct Parser {
+    /// Number of arguments, once the array header is parsed.
+    count: Option<usize>,
+    /// Position parsing continues at.
+    pos: usize,
+    /// Arguments parsed so far.
+    args: Vec<Range<usize>>,
+}
+
+impl Parser {
+    /// Continues parsing the command at the start of `buf`, which must start
+    /// with the data given to previous calls.
+    fn parse(&mut self, buf: &[u8]) -> io::Result<Parsed> {
+        match buf.first() {
+            None => return Ok(Parsed::Incomplete(1)),
+            Some(b'*') => {}
+            Some(_) => return self.parse_inline(buf),
+        }
+
+        let count = match self.count {
+            Some(count) => count,
+            None => {
+                let (count, pos) = match parse_line(buf, 0)? {
+                    Some(line) => line,
+                    None => return Ok(Parsed::Incomplete(buf.len() + 1)),
+                };
+                let count = parse_len(count, MAX_ARGS)?;
+                self.count = Some(count);
+                self.pos = pos;
+                self.args = Vec::with_capacity(count.min(64));
+                count
+            }
+        };
+
+        while self.args.len() < count {
+            let pos = self.pos;
+            if buf.len() <= pos {
+                return Ok(Parsed::Incomplete(pos + 1));
+            }
+            if buf[pos] != b'$' {
+                return Err(invalid_data("expected bulk string"));
+            }
+            let (len, start) = match parse_line(buf, pos)? {
+                Some(line) => line,
+                None => return Ok(Parsed::Incomplete(buf.len() + 1)),
+            };
+            let len = parse_len(len, MAX_COMMAND_LEN)?;
+            let end = start + len + 2;
+            if end > MAX_COMMAND_LEN {
+                return Err(invalid_data("command too large"));
+            }
+            if buf.len() < end {
+                return Ok(Parsed::Incomplete(end));
+            }
+            if &buf[start + len..end] != b"\r\n" {
+                return Err(invalid_data("bulk string not terminated"));
+            }
+            self.args.push(start..start + len);
+            self.pos = end;
+        }
+
+        let args = std::mem::take(&mut self.args);
+        let len = self.pos;
+        *self = Parser::default();
+        Ok(Parsed::Complete(args, len))
+    }
+
+    fn parse_inline(&mut self, buf: &[u8]) -> io::Result<Parsed> {
+        let end = match buf[self.pos..].iter().position(|b| *b == b'\n') {
+            Some(end) => self.pos + end,
+            None if buf.len() > MAX_INLINE_LEN => {
+                return Err(invalid_data("inline command too long"))
+            }
+            None => {
+                self.pos = buf.len();
+                return Ok(Parsed::Incomplete(buf.len() + 1));
+            }
+        };
+        let line_end = match buf[..end].last() {
+            Some(b'\r') => end - 1,
+            _ => end,
+        };
+
+        let mut args = Vec::new();
+        let mut word = 0;
+        for (i, b) in buf[..line_end].iter().enumerate() {
+            if *b == b' ' {
+                if i > word {
+                    args.push(word..i);
+                }
+                word = i + 1;
+            }
+        }
+        if line_end > word {
+            args.push(word..line_end);
+        }
+
+        *self = Parser::default();
+        Ok(Parsed::Complete(args, end + 1))
+    }
+}
+
+/// Parses the `\r\n` terminated line starting after the type byte at
+/// `pos`, returning its content and the position after it.
+fn parse_line(buf: &[u8], pos: usize) -> io::Result<Option<(&[u8], usize)>> {
+    match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+        Some(len) => Ok(Some((&buf[pos + 1..pos + len], pos + len + 2))),
+        None if buf.len() - pos > 32 => Err(invalid_data("length line too long")),
+        None => Ok(None),
+    }
+}
+
+fn parse_len(line: &[u8], max: usize) -> io::Result<usize> {
+    let len: usize = std::str::from_utf8(line)
+        .map_err(invalid_data)?
+        .parse()
+        .map_err(invalid_data)?;
+    if len > max {
+        return Err(invalid_data("command too large"));
+    }
+    Ok(len)
+}
+
+fn put_line(buf: &mut BytesMut, kind: u8, line: &[u8]) {
+    buf.reserve(line.len() + 3);
+    buf.extend_from_slice(&[kind]);
+    buf.extend_from_slice(line);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Reads the commands sent by a client.
+pub struct CommandReader<IO> {
+    reader: ReadBuffer<IO>,
+    parser: Parser,
+}
+
+impl<IO: AsyncReadRent> CommandReader<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            reader: ReadBuffer::new(io),
+            parser: Parser::default(),
+        }
+    }
+
+    /// Returns the arguments of the next command. Empty inline commands are
+    /// skipped.
+    ///
+    /// The arguments share a single buffer, split off the read buffer once
+    /// the command is complete.
+    pub async fn next(&mut self) -> Option<io::Result<Vec<Bytes>>> {
+        loop {
+            let needed = match self.parser.parse(self.reader.buffered()) {
+                Ok(Parsed::Complete(args, len)) => {
+                    let command = self.reader.split_to(len).freeze();
+                    if !args.is_empty() {
+                        return Some(Ok(args.into_iter().map(|arg| command.slice(arg)).collect()));
+                    }
+                    continue;
+                }
+                Ok(Parsed::Incomplete(needed)) => needed,
+                Err(e) => return Some(Err(e)),
+            };
+            if needed > MAX_COMMAND_LEN {
+                return Some(Err(invalid_data("command too large")));
+            }
+
+            let buffered = self.reader.buffered().len();
+            if let Err(e) = self.reader.fill(needed).await {
+                return match e.kind() {
+                    io::ErrorKind::UnexpectedEof if buffered == 0 => None,
+                    _ => Some(Err(e)),
+                };
+            }
+        }
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// `Peer` handle for Redis pub/sub clients.
+struct RespPeer {
+    tx: PeerTx,
+
+    peer_id: PeerId,
+
+    subscriptions: Rc<RefCell<RespSubscriptions>>,
+}
+
+/// Channels and patterns a Redis pub/sub client subscribed to.
+#[derive(Default)]
+struct RespSubscriptions {
+    channels: HashMap<ChannelId, String>,
+    patterns: Vec<String>,
+}
+
+impl Peer for RespPeer {
+    fn get_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        &self.tx
+    }
+}
+
+impl RespSubscriptions {
+    /// Number of subscriptions, as reported to the client.
+    fn count(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+
+    /// Returns `true` if messages of channel `name` are still wanted.
+    fn covers(&self, name: &str) -> bool {
+        self.channels.values().any(|channel| channel == name)
+            || self.patterns.iter().any(|p| pattern::matches(p, name))
+    }
+}
+
+/// Accepts Redis pub/sub connections.
+pub async fn listener(state: Rc<RefCell<Shared>>, listener: TcpListener, endpoint: Rc<Endpoint>) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("RESP accept error: {e}");
+                continue;
+            }
+        };
+        if !endpoint.config.allows(addr.ip()) {
+            tracing::info!("rejecting connection from {}", addr);
+            continue;
+        }
+        let _ = stream.set_nodelay(true);
+
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+        monoio::spawn(async move {
+            endpoint.opened();
+            if let Err(e) = process_resp(state, stream, addr, &endpoint.config).await {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+            endpoint.closed();
+        });
+    }
+}
+
+/// Process an individual Redis pub/sub client
+async fn process_resp(
+    state: Rc<RefCell<Shared>>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: &ListenerConfig,
+) -> Result<(), Box<dyn Error>> {
+    let peer_addr = stream.peer_addr()?;
+    let (stream_in, stream_out) = stream.into_split();
+    let reader = CommandReader::new(stream_in);
+    let writer = FrameWriter::new(stream_out);
+
+    let (tx, rx) = peer_queue();
+    let peer = RespPeer {
+        tx,
+        peer_id: connection_peer_id(config.kind, peer_addr),
+        subscriptions: Rc::default(),
+    };
+
+    let peer_name = addr.to_string();
+    tracing::info!("new Redis pub/sub connection from {}", &peer_name);
+
+    {
+        let mut state = state.borrow_mut();
+        state
+            .connections
+            .insert(peer.get_id().clone(), peer.tx.clone());
+        state.router.peer_add(&peer);
+    }
+
+    let peer_id = peer.get_id().clone();
+    let subscriptions = peer.subscriptions.clone();
+
+    // command replies, sent alongside the peer's messages
+    let (control_tx, control_rx) = flume::unbounded();
+
+    let from_client_handle = monoio::spawn(resp_from_client(
+        state.clone(),
+        reader,
+        peer,
+        config.token.clone(),
+        control_tx,
+    ));
+    let to_client_handle = monoio::spawn(resp_to_client(
+        state.clone(),
+        rx,
+        control_rx,
+        writer,
+        subscriptions,
+    ));
+
+    monoio::select!(
+        e = from_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        },
+        e = to_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        }
+    );
+
+    {
+        let mut state = state.borrow_mut();
+        state.connections.remove(&peer_id);
+        state.router.peer_remove(&peer_id);
+    }
+
+    tracing::info!("{peer_name} disconnected");
+
+    Ok(())
+}
+
+/// Runs the commands of a Redis client.
+///
+/// With a `token`, clients have to send it with `AUTH` first.
+async fn resp_from_client(
+    state: Rc<RefCell<Shared>>,
+    mut reader: CommandReader<OwnedReadHalf<TcpStream>>,
+    peer: RespPeer,
+    token: Option<String>,
+    control: flume::Sender<Bytes>,
+) -> Result<(), anyhow::Error> {
+    let mut authenticated = token.is_none();
+    loop {
+        let args = match reader.next().await {
+            Some(Ok(args)) => args,
+            Some(Err(e)) => {
+                count_decode_error(&e);
+                let _ = control.send(Value::Error(format!("ERR {e}")).to_bytes());
+                tracing::info!("resp_from_client error: {e}");
+                break;
+            }
+            None => break,
+        };
+
+        let command = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        if command == "QUIT" {
+            let _ = control.send(Value::Simple("OK".to_string()).to_bytes());
+            break;
+        }
+        if command == "AUTH" {
+            let reply = match (&token, args.last()) {
+                (_, _) if !(2..=3).contains(&args.len()) => {
+                    Value::Error("ERR wrong number of arguments for 'auth' command".to_string())
+                }
+                (None, _) => Value::Error("ERR no password is set".to_string()),
+                (Some(token), Some(sent)) if http::secret_eq(sent, token.as_bytes()) => {
+                    authenticated = true;
+                    Value::Simple("OK".to_string())
+                }
+                _ => Value::Error("WRONGPASS invalid password".to_string()),
+            };
+            let _ = control.send(reply.to_bytes());
+            continue;
+        }
+        if !authenticated {
+            let reply = Value::Error("NOAUTH Authentication required.".to_string());
+            let _ = control.send(reply.to_bytes());
+            continue;
+        }
+        for reply in resp_command(&state, &peer, &command, &args[1..]) {
+            let _ = control.send(reply.to_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes a Redis command, returning the replies.
+fn resp_command(
+    state: &Rc<RefCell<Shared>>,
+    peer: &RespPeer,
+    command: &str,
+    args: &[Bytes],
+) -> Vec<Value> {
+    let mut state = state.borrow_mut();
+    let mut subscriptions = peer.subscriptions.borrow_mut();
+
+    let subscribed = subscriptions.count() > 0;
+    let allowed = matches!(
+        command,
+        "SUBSCRIBE" | "PSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "PING"
+    );
+    if subscribed && !allowed {
+        return vec![Value::Error(format!(
+            "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+            command.to_lowercase()
+        ))];
+    }
+
+    // channel names and patterns, PUBLISH's message is binary
+    let names = match command {
+        "PING" => &args[..0],
+        "PUBLISH" => &args[..args.len().min(1)],
+        _ => args,
+    };
+    let names: Result<Vec<String>, _> = names
+        .iter()
+        .map(|arg| String::from_utf8(arg.to_vec()))
+        .collect();
+    let names = match names {
+        Ok(names) => names,
+        Err(_) => return vec![Value::Error("ERR channel names must be UTF-8".to_string())],
+    };
+    let wrong_arity = || {
+        vec![Value::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_lowercase()
+        ))]
+    };
+    let confirm = |kind: &str, name: Option<&str>, count: i64| {
+        Value::Array(vec![
+            Value::bulk(Bytes::copy_from_slice(kind.as_bytes())),
+            Value::Bulk(name.map(|name| Bytes::copy_from_slice(name.as_bytes()))),
+            Value::Integer(count),
+        ])
+    };
+
+    match command {
+        "PING" if subscribed => vec![Value::Array(vec![
+            Value::bulk("pong"),
+            Value::bulk(args.first().cloned().unwrap_or_default()),
+        ])],
+        "PING" => match args.first() {
+            Some(message) => vec![Value::bulk(message.clone())],
+            None => vec![Value::Simple("PONG".to_string())],
+        },
+        "PUBLISH" => {
+            if args.len() != 2 {
+                return wrong_arity();
+            }
+            let channel_id = state.router.channel_get_or_add(names[0].clone());
+            let msg = Msg::new_channel_msg(peer.get_id().clone(), channel_id, args[1].to_vec());
+            match state
+                .router
+                .forward(Frame::new(msg.framed()), channel_id, peer.get_id())
+            {
+                Ok(count) => vec![Value::Integer(count as i64)],
+                Err(e @ TxError::NotPermitted) => vec![Value::Error(format!("NOPERM {e}"))],
+                Err(e @ TxError::ShuttingDown) => vec![Value::Error(format!("ERR {e}"))],
+                Err(_) => vec![Value::Integer(0)],
+            }
+        }
+        "SUBSCRIBE" => {
+            if names.is_empty() {
+                return wrong_arity();
+            }
+            let mut replies = Vec::new();
+            for name in names {
+                let channel_id = state.router.channel_get_or_add(name.clone());
+                match state.router.attach(channel_id, peer) {
+                    Ok(()) => {
+                        subscriptions.channels.insert(channel_id, name.clone());
+                        replies.push(confirm("subscribe", Some(&name), subscriptions.count()));
+                    }
+                    Err(e) => replies.push(Value::Error(format!("NOPERM {e}"))),
+                }
+            }
+            replies
+        }
+        "PSUBSCRIBE" => {
+            if names.is_empty() {
+                return wrong_arity();
+            }
+            let mut replies = Vec::new();
+            for pattern in names {
+                state.router.attach_pattern(pattern.clone(), peer);
+                if !subscriptions.patterns.contains(&pattern) {
+                    subscriptions.patterns.push(pattern.clone());
+                }
+                replies.push(confirm("psubscribe", Some(&pattern), subscriptions.count()));
+            }
+            replies
+        }
+        "UNSUBSCRIBE" => {
+            let names = if names.is_empty() {
+                subscriptions.channels.values().cloned().collect()
+            } else {
+                names
+            };
+            if names.is_empty() {
+                return vec![confirm("unsubscribe", None, subscriptions.count())];
+            }
+            let mut replies = Vec::new();
+            for name in names {
+                let channel_ids: Vec<ChannelId> = subscriptions
+                    .channels
+                    .iter()
+                    .filter(|(_, channel)| **channel == name)
+                    .map(|(channel_id, _)| *channel_id)
+                    .collect();
+                for channel_id in channel_ids {
+                    subscriptions.channels.remove(&channel_id);
+                    if !subscriptions.covers(&name) {
+                        let _ = state.router.detach(channel_id, peer);
+                    }
+                }
+                replies.push(confirm("unsubscribe", Some(&name), subscriptions.count()));
+            }
+            replies
+        }
+        "PUNSUBSCRIBE" => {
+            let patterns = if names.is_empty() {
+                subscriptions.patterns.clone()
+            } else {
+                names
+            };
+            if patterns.is_empty() {
+                return vec![confirm("punsubscribe", None, subscriptions.count())];
+            }
+            let mut replies = Vec::new();
+            for pattern in patterns {
+                subscriptions.patterns.retain(|p| *p != pattern);
+                state.router.detach_pattern(&pattern, peer);
+                for (name, channel_id) in state.router.channel_list(None) {
+                    if pattern::matches(&pattern, &name) && !subscriptions.covers(&name) {
+                        let _ = state.router.detach(channel_id, peer);
+                    }
+                }
+                replies.push(confirm(
+                    "punsubscribe",
+                    Some(&pattern),
+                    subscriptions.count(),
+                ));
+            }
+            replies
+        }
+        _ => vec![Value::Error(format!(
+            "ERR unknown command '{}'",
+            command.to_lowercase()
+        ))],
+    }
+}
+
+async fn resp_to_client(
+    state: Rc<RefCell<Shared>>,
+    mut rx: PeerRx,
+    control: flume::Receiver<Bytes>,
+    mut writer: FrameWriter<OwnedWriteHalf<TcpStream>>,
+    subscriptions: Rc<RefCell<RespSubscriptions>>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        monoio::select! {
+            frame = rx.recv_async() => {
+                let mut next = Some(frame?);
+                while let Some(frame) = next.take() {
+                    if let Some(msg) = pubsub_message(&frame) {
+                        let subscriptions = subscriptions.borrow();
+                        let explicit = subscriptions.channels.get(&msg.channel()).cloned();
+                        let name = explicit.clone().or_else(|| {
+                            state.borrow().router.channel_name(msg.channel()).map(str::to_string)
+                        });
+                        if let Some(name) = name {
+                            let channel = Value::bulk(name.clone());
+                            let content = Value::bulk(msg.content().clone());
+                            if explicit.is_some() {
+                                let message = Value::Array(vec![
+                                    Value::bulk("message"),
+                                    channel.clone(),
+                                    content.clone(),
+                                ]);
+                                writer.push(message.to_bytes());
+                            }
+                            for pattern in &subscriptions.patterns {
+                                if pattern::matches(pattern, &name) {
+                                    let message = Value::Array(vec![
+                                        Value::bulk("pmessage"),
+                                        Value::bulk(pattern.clone()),
+                                        channel.clone(),
+                                        content.clone(),
+                                    ]);
+                                    writer.push(message.to_bytes());
+                                }
+                            }
+                        }
+                    }
+                    if !writer.is_full() {
+                        next = rx.try_recv();
+                    }
+                }
+            }
+            control = control.recv_async() => {
+                match control {
+                    Ok(reply) => writer.push(reply),
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command() {
+        let command = b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+
+        for len in 0..command.len() {
+            assert!(parse_command(&command[..len]).unwrap().is_none());
+        }
+
+        let (args, len) = parse_command(command).unwrap().unwrap();
+        assert_eq!(len, command.len());
+        assert_eq!(args, vec!["PUBLISH", "news", "hello"]);
+
+        assert!(parse_command(b"*1\r\n:1\r\n").is_err());
+        assert!(parse_command(b"*1\r\n$1\r\nab\r\n").is_err());
+    }
+
+    #[test]
+    fn incremental() {
+        let command = b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+
+        let mut parser = Parser::default();
+        let mut len = 0;
+        let args = loop {
+            match parser.parse(&command[..len]).unwrap() {
+                Parsed::Complete(args, parsed) => {
+                    assert_eq!(parsed, command.len());
+                    break args;
+                }
+                Parsed::Incomplete(needed) => {
+                    assert!(needed > len && needed <= command.len());
+                    len = needed;
+                }
+            }
+        };
+        assert_eq!(args, vec![8..15, 21..25, 31..36]);
+    }
+
+    #[test]
+    fn too_large() {
+        let command = format!("*1\r\n${}\r\n", MAX_COMMAND_LEN);
+        assert!(parse_command(command.as_bytes()).is_err());
+
+        let command = format!("*2\r\n${}\r\n", MAX_COMMAND_LEN / 2);
+        let mut parser = Parser::default();
+        let mut buf = command.into_bytes();
+        buf.resize(buf.len() + MAX_COMMAND_LEN / 2, b'a');
+        buf.extend_from_slice(format!("\r\n${}\r\n", MAX_COMMAND_LEN / 2).as_bytes());
+        assert!(parser.parse(&buf).is_err());
+    }
+
+    #[test]
+    fn inline() {
+        let (args, len) = parse_command(b"PING  hello\r\nPING").unwrap().unwrap();
+        assert_eq!(len, 13);
+        assert_eq!(args, vec!["PING", "hello"]);
+        assert!(parse_command(b"PING").unwrap().is_none());
+    }
+
+    #[test]
+    fn encode() {
+        let value = Value::Array(vec![
+            Value::bulk("message"),
+            Value::bulk("news"),
+            Value::Integer(-1),
+            Value::Bulk(None),
+            Value::Simple("OK".to_string()),
+            Value::Error("ERR unknown".to_string()),
+        ]);
+        assert_eq!(
+            &value.to_bytes()[..],
+            b"*6\r\n$7\r\nmessage\r\n$4\r\nnews\r\n:-1\r\n$-1\r\n+OK\r\n-ERR unknown\r\n"
+        );
+    }
+}
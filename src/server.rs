@@ -0,0 +1,663 @@
+//! Server state, and the connections of clients using the rsq protocol.
+//!
+//! The front-ends of the other protocols live next to their protocol code
+//! (`http`, `mqtt`, `resp`, `websocket`, `metrics` and `webhook`), and share
+//! the state and connection handling defined here.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use monoio::io::{AsyncReadRent, AsyncWriteRent, Splitable};
+use monoio::net::{TcpListener, TcpStream, UnixListener};
+
+use crate::compression::{self, Compression};
+use crate::http;
+use crate::listener::{Kind, ListenerConfig};
+use crate::messaging::errors::TxError;
+use crate::messaging::frame::Frame;
+use crate::messaging::msg::{ChannelMsg, ControlMsg, Msg, StatusMsg, Welcome};
+use crate::messaging::peer::{Peer, PeerId, PeerRx, PeerTx};
+use crate::messaging::queue::peer_queue;
+use crate::messaging::router::Router;
+use crate::metrics;
+use crate::monoio_bincode::{CompressionHandle, Framed};
+use crate::msg_stream::{FrameDecoder, FrameWriter};
+use crate::unix;
+use crate::wire::{self, Encoding, EncodingHandle};
+
+/// Connections open on all listeners.
+pub(crate) static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Connection limit of the whole server.
+pub static MAX_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of connections accepted, for naming their peers.
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// How often tasks check whether the server is shutting down.
+pub const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Waits until the server is asked to shut down.
+pub async fn shutdown_requested(state: &Rc<RefCell<Shared>>) {
+    while !state.borrow().shutdown.load(Ordering::Relaxed) {
+        monoio::time::sleep(SHUTDOWN_POLL).await;
+    }
+}
+
+/// Accepts TCP connections.
+pub async fn tcp_listener(
+    state: Rc<RefCell<Shared>>,
+    listener: TcpListener,
+    endpoint: Rc<Endpoint>,
+) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        // Asynchronously wait for an inbound TcpStream.
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("accept error: {e}");
+                continue;
+            }
+        };
+        if !endpoint.config.allows(addr.ip()) {
+            tracing::info!("rejecting connection from {}", addr);
+            continue;
+        }
+        let _ = stream.set_nodelay(true);
+
+        // Clone a handle to the `Shared` state for the new connection.
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+
+        // Spawn our handler to be run asynchronously.
+        monoio::spawn(async move {
+            tracing::debug!("accepted connection");
+            endpoint.opened();
+            if let Err(e) = process(state, stream, addr, &endpoint.config).await {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+            endpoint.closed();
+        });
+    }
+}
+
+/// Accepts connections on a Unix socket.
+///
+/// Clients are named as described in the `unix` module.
+pub async fn unix_listener(
+    state: Rc<RefCell<Shared>>,
+    listener: UnixListener,
+    endpoint: Rc<Endpoint>,
+) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, _) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Unix socket accept error: {e}");
+                continue;
+            }
+        };
+
+        let connection = next_connection();
+        let peer_name = match unix::peer_name(&endpoint.config, &stream, connection) {
+            Ok(peer_name) => peer_name,
+            Err(e) => {
+                tracing::info!("rejecting Unix socket client: {e}");
+                continue;
+            }
+        };
+
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+        monoio::spawn(async move {
+            endpoint.opened();
+            tracing::info!("new connection from {}", &peer_name);
+            let (stream_in, stream_out) = stream.into_split();
+            let (rx, peer) = ConnectionPeer::new(PeerId::new(&peer_name));
+            serve(
+                state,
+                stream_in,
+                stream_out,
+                rx,
+                peer,
+                &peer_name,
+                &endpoint.config,
+            )
+            .await;
+            tracing::info!("{peer_name} disconnected");
+            endpoint.closed();
+        });
+    }
+}
+
+/// Returns the number of a newly accepted connection.
+fn next_connection() -> u64 {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Returns the peer id of a client connected from `addr` to a listener of
+/// kind `kind`.
+///
+/// Ids are `<kind>:<addr>:<n>`, with `n` counting the connections, so they
+/// stay unique when a client connects to several listeners from the same
+/// address, or several clients share one (behind NAT or a proxy).
+pub(crate) fn connection_peer_id(kind: Kind, addr: SocketAddr) -> PeerId {
+    PeerId::new(&format!("{kind}:{addr}:{}", next_connection()))
+}
+
+/// Data that is shared between all client connections
+pub struct Shared {
+    pub connections: HashMap<PeerId, PeerTx>,
+    pub router: Router,
+    pub compression_threshold: usize,
+
+    /// Set to shut the server down, by SIGTERM, SIGINT or the admin command
+    /// of the HTTP gateway.
+    pub shutdown: Arc<AtomicBool>,
+}
+
+/// Protocol settings of a connection, shared between its reading and
+/// writing task.
+#[derive(Clone, Default)]
+pub(crate) struct Session {
+    /// Compression negotiated for frames sent to the peer.
+    pub compression: CompressionHandle,
+
+    /// Encoding used by the peer, detected from its first frame.
+    pub encoding: EncodingHandle,
+
+    /// Set for WebSocket peers sending JSON text messages. Frames are sent
+    /// to them as text messages, without length prefix or compression.
+    pub text: Rc<Cell<bool>>,
+
+    /// Token the peer has to send with `ControlMsg::Auth`, if the listener
+    /// requires one.
+    pub token: Option<String>,
+
+    /// Set once the peer sent the token.
+    pub authenticated: Rc<Cell<bool>>,
+}
+
+/// A listener's settings, and the connections open on it.
+pub struct Endpoint {
+    pub config: ListenerConfig,
+
+    /// Connections open on this listener.
+    open: Cell<u64>,
+}
+
+/// `Peer` handle for TCP connections.
+struct ConnectionPeer {
+    /// Sender message channel.
+    ///
+    /// Messages sent here will be sent to the Rx half.
+    tx: PeerTx,
+
+    peer_id: PeerId,
+
+    session: Session,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Shared {
+            connections: HashMap::new(),
+            router: Router::new(),
+            compression_threshold: compression::DEFAULT_THRESHOLD,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Shared {
+    /// Create a new, empty, instance of `Shared`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Endpoint {
+    pub fn new(config: ListenerConfig) -> Self {
+        Self {
+            config,
+            open: Cell::new(0),
+        }
+    }
+
+    /// Waits until both the server and this listener are below their
+    /// connection limit.
+    pub(crate) async fn wait_for_capacity(&self) {
+        loop {
+            let max_connections = MAX_CONNECTIONS.load(Ordering::Relaxed);
+            if OPEN_CONNECTIONS.load(Ordering::Relaxed) >= max_connections {
+                tracing::info!("connection limit ({}) reached", max_connections);
+            } else if matches!(self.config.max_connections, Some(max) if self.open.get() >= max) {
+                tracing::info!(
+                    "{}:{}: connection limit reached",
+                    self.config.kind,
+                    self.config.addr
+                );
+            } else {
+                return;
+            }
+            monoio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    pub(crate) fn opened(&self) {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        self.open.set(self.open.get() + 1);
+    }
+
+    pub(crate) fn closed(&self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        self.open.set(self.open.get() - 1);
+    }
+}
+
+impl ConnectionPeer {
+    /// Create a new instance of `Connection`.
+    fn new(peer_id: PeerId) -> (PeerRx, ConnectionPeer) {
+        // Create a channel for this peer
+        let (tx, rx) = peer_queue();
+
+        (
+            rx,
+            ConnectionPeer {
+                tx,
+                peer_id,
+                session: Session::default(),
+            },
+        )
+    }
+}
+
+impl Peer for ConnectionPeer {
+    fn get_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        &self.tx
+    }
+}
+
+/// Process an individual chat client
+async fn process(
+    state: Rc<RefCell<Shared>>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: &ListenerConfig,
+) -> Result<(), Box<dyn Error>> {
+    let peer_addr = stream.peer_addr()?;
+    let (stream_in, stream_out) = stream.into_split();
+
+    // Register our peer with state which internally sets up some channels.
+    let peer_id = connection_peer_id(config.kind, peer_addr);
+    let (rx, peer) = ConnectionPeer::new(peer_id.clone());
+
+    let peer_name = addr.to_string();
+    let msg = format!("new connection from {}", &peer_name);
+    tracing::info!("{}", msg);
+
+    state
+        .borrow_mut()
+        .connections
+        .insert(peer_id.clone(), peer.tx.clone());
+
+    serve(
+        state.clone(),
+        stream_in,
+        stream_out,
+        rx,
+        peer,
+        &peer_name,
+        config,
+    )
+    .await;
+
+    state.borrow_mut().connections.remove(&peer_id);
+
+    tracing::info!("{peer_name} disconnected");
+
+    Ok(())
+}
+
+/// Runs the protocol with a client, until either side closes the connection.
+async fn serve<R, W>(
+    state: Rc<RefCell<Shared>>,
+    stream_in: R,
+    stream_out: W,
+    rx: PeerRx,
+    mut peer: ConnectionPeer,
+    peer_name: &str,
+    config: &ListenerConfig,
+) where
+    R: AsyncReadRent + 'static,
+    W: AsyncWriteRent + 'static,
+{
+    //let msgs_in = FramedRead::new(stream_in, BincodeCodec::<Msg>::new());
+    let msgs_in = FrameDecoder::new(stream_in).with_max_len(config.max_frame_len);
+    peer.session.token = config.token.clone();
+
+    // A client has connected
+    state.borrow_mut().router.peer_add(&peer);
+
+    let peer_id = peer.get_id().clone();
+    let session = peer.session.clone();
+    let compression_threshold = state.borrow().compression_threshold;
+
+    let from_client_handle = monoio::spawn(from_client(state.clone(), msgs_in, peer));
+    let to_client_handle = monoio::spawn(to_client(rx, stream_out, session, compression_threshold));
+
+    //
+    monoio::select!(
+        e = from_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        },
+        e = to_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        }
+    );
+
+    // If this section is reached it means that the client was disconnected!
+    state.borrow_mut().router.peer_remove(&peer_id);
+}
+
+async fn from_client<R: AsyncReadRent>(
+    state: Rc<RefCell<Shared>>,
+    mut msgs_in: FrameDecoder<R>,
+    peer: ConnectionPeer,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match msgs_in.next().await {
+            Some(Ok(bytes)) => handle_frame(&state, &peer, &peer.session, bytes).await?,
+            Some(Err(e)) => {
+                count_decode_error(&e);
+                tracing::info!("from_client error: {e}");
+                break;
+            }
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles a frame received from `peer`.
+pub(crate) async fn handle_frame(
+    state: &Rc<RefCell<Shared>>,
+    peer: &dyn Peer,
+    session: &Session,
+    bytes: BytesMut,
+) -> Result<(), anyhow::Error> {
+    let bytes = compression::decompress_frame(bytes)
+        .map_err(decode_error)?
+        .freeze();
+
+    // The first frame decides the encoding of the connection.
+    // Frames are passed around as bincode internally.
+    let encoding = match session.encoding.get() {
+        Some(encoding) => encoding,
+        None => {
+            let encoding = Encoding::detect(&bytes[wire::PREFIX_LEN..])
+                .ok_or_else(|| decode_error(anyhow::anyhow!("unknown frame encoding")))?;
+            session.encoding.set(Some(encoding));
+            encoding
+        }
+    };
+    let bytes = match encoding {
+        Encoding::Bincode => bytes,
+        _ => wire::translate::<Msg>(&bytes, encoding, Encoding::Bincode).map_err(decode_error)?,
+    };
+
+    // Deserialize message header
+    let (msg, _content_len) = Msg::decode_header(&bytes).map_err(decode_error)?;
+
+    // Until the listener's token was sent, only `Hello` and `Auth` are
+    // accepted.
+    if let Some(token) = &session.token {
+        if !session.authenticated.get() {
+            match &msg {
+                Msg::ControlMsg(ControlMsg::Hello(_)) => {}
+                Msg::ControlMsg(ControlMsg::Auth(sent))
+                    if http::secret_eq(sent.as_bytes(), token.as_bytes()) =>
+                {
+                    session.authenticated.set(true);
+                    return Ok(());
+                }
+                Msg::ControlMsg(ControlMsg::Auth(_)) => {
+                    reply(peer, StatusMsg::Error("invalid token".to_string())).await?;
+                    anyhow::bail!("invalid token");
+                }
+                _ => {
+                    reply(
+                        peer,
+                        StatusMsg::Error("authentication required".to_string()),
+                    )
+                    .await?;
+                    anyhow::bail!("message before authentication");
+                }
+            }
+        }
+    }
+
+    // handle message
+    match msg {
+        Msg::ChannelMsg(msg) => {
+            //tracing::info!("msg len: {}", msg.content().len());
+            let frame = Frame::new(bytes)
+                .with_expiry(msg.headers().expires)
+                .with_priority(msg.headers().priority);
+            let denied = {
+                let mut state = state.borrow_mut();
+                match msg.headers().deliver_at {
+                    Some(due) if msg.headers().is_deferred() => state
+                        .router
+                        .schedule(frame, msg.channel(), peer.get_id(), due)
+                        .err(),
+                    _ => state
+                        .router
+                        .forward(frame, msg.channel(), peer.get_id())
+                        .err(),
+                }
+            };
+            if let Some(e @ (TxError::NotPermitted | TxError::ShuttingDown)) = denied {
+                reply(peer, StatusMsg::Error(e.to_string())).await?;
+            }
+        }
+        Msg::ControlMsg(controlmsg) => match controlmsg {
+            ControlMsg::Hello(hello) => {
+                // text messages can't carry compressed frames
+                let compression = if session.text.get() {
+                    None
+                } else {
+                    Compression::negotiate(&hello.compression)
+                };
+                reply(peer, StatusMsg::Welcome(Welcome { compression })).await?;
+                session.compression.set(compression);
+            }
+            // sent again, or to a listener without token
+            ControlMsg::Auth(_) => {}
+            ControlMsg::ChannelJoin(channel) => {
+                let res = {
+                    let mut state = state.borrow_mut();
+                    let channel_id = state.router.channel_get_or_add(channel);
+                    state.router.attach(channel_id, peer)
+                };
+                if let Err(e) = res {
+                    reply(peer, StatusMsg::Error(e.to_string())).await?;
+                }
+            }
+            ControlMsg::ChannelLeave(channel) => {
+                let mut state = state.borrow_mut();
+                state.router.detach(channel, peer)?;
+            }
+            ControlMsg::ChannelCreate(name) => {
+                let channel_id = state
+                    .borrow_mut()
+                    .router
+                    .channel_create(name.clone(), peer.get_id());
+                reply(peer, StatusMsg::ChannelId(name, channel_id)).await?;
+            }
+            ControlMsg::ChannelMembers(channel_id) => {
+                let status = {
+                    let state = state.borrow();
+                    match state.router.members(channel_id, peer) {
+                        Ok(members) => StatusMsg::ChannelMembers(channel_id, members),
+                        Err(e) => StatusMsg::Error(e.to_string()),
+                    }
+                };
+                reply(peer, status).await?;
+            }
+            ControlMsg::ChannelList(prefix) => {
+                let list = state.borrow().router.channel_list(prefix.as_deref());
+                reply(peer, StatusMsg::ChannelList(list)).await?;
+            }
+            ControlMsg::ChannelInfo(channel_id) => {
+                let status = match state.borrow().router.channel_info(channel_id) {
+                    Ok(info) => StatusMsg::ChannelInfo(info),
+                    Err(e) => StatusMsg::Error(e.to_string()),
+                };
+                reply(peer, status).await?;
+            }
+            ControlMsg::ChannelConfigure(channel_id, options) => {
+                let status = {
+                    let mut state = state.borrow_mut();
+                    match state.router.channel_configure(channel_id, options, peer) {
+                        Ok(()) => StatusMsg::ChannelInfo(state.router.channel_info(channel_id)?),
+                        Err(e) => StatusMsg::Error(e.to_string()),
+                    }
+                };
+                reply(peer, status).await?;
+            }
+            ControlMsg::ChannelDelete(channel_id) => {
+                let res = state.borrow_mut().router.channel_delete(channel_id, peer);
+                let status = match res {
+                    Ok(()) => StatusMsg::ChannelDeleted(channel_id),
+                    Err(e) => StatusMsg::Error(e.to_string()),
+                };
+                reply(peer, status).await?;
+            }
+        },
+        _ => {
+            tracing::error!("unhandled message: {:?}", msg);
+        }
+    };
+
+    Ok(())
+}
+
+/// Counts an error decoding a received frame.
+fn decode_error<E>(e: E) -> E {
+    metrics::DECODE_ERRORS.inc();
+    e
+}
+
+/// Counts a read error, if it's caused by a client sending data that can't
+/// be decoded.
+pub(crate) fn count_decode_error(e: &io::Error) {
+    if e.kind() == io::ErrorKind::InvalidData {
+        metrics::DECODE_ERRORS.inc();
+    }
+}
+
+/// Sends a status message back to `peer`.
+async fn reply(peer: &dyn Peer, status: StatusMsg) -> Result<(), anyhow::Error> {
+    peer.get_sink()
+        .send_async(Frame::status(Msg::StatusMsg(status).framed()))
+        .await?;
+    Ok(())
+}
+
+/// Prepares a frame for sending it to a peer, translating and compressing
+/// it according to `session`.
+///
+/// Returns `None` if the frame should be dropped.
+pub(crate) fn prepare_frame(
+    frame: Frame,
+    session: &Session,
+    compression_threshold: usize,
+) -> Option<Bytes> {
+    if frame.is_expired() {
+        metrics::DROPPED.inc();
+        return None;
+    }
+    let data = match session.encoding.get().unwrap_or_default() {
+        Encoding::Bincode => frame.data,
+        to => match wire::translate::<Msg>(&frame.data, Encoding::Bincode, to) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("dropping frame that can't be translated to {to:?}: {e}");
+                metrics::DROPPED.inc();
+                return None;
+            }
+        },
+    };
+    Some(match session.compression.get() {
+        Some(algorithm) => compression::compress_frame(data, algorithm, compression_threshold),
+        None => data,
+    })
+}
+
+async fn to_client<W: AsyncWriteRent>(
+    mut rx: PeerRx,
+    writer: W,
+    session: Session,
+    compression_threshold: usize,
+) -> Result<(), anyhow::Error> {
+    // Frames are sent as they are, so pass them on to the TCP stream in
+    // batches of whatever is queued.
+    let mut writer = FrameWriter::new(writer);
+    loop {
+        let mut next = Some(rx.recv_async().await?);
+        while let Some(frame) = next.take() {
+            if let Some(data) = prepare_frame(frame, &session, compression_threshold) {
+                writer.push(data);
+            }
+            if !writer.is_full() {
+                next = rx.try_recv();
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+/// Returns the channel message in `frame`, unless it can't be sent to MQTT
+/// or Redis pub/sub clients.
+///
+/// Status messages and fragments of streamed messages are skipped.
+pub(crate) fn pubsub_message(frame: &Frame) -> Option<ChannelMsg> {
+    if frame.is_expired() {
+        metrics::DROPPED.inc();
+        return None;
+    }
+    match Msg::decode_frame(&frame.data) {
+        Ok(Msg::ChannelMsg(msg)) if msg.headers().fragment.is_none() => Some(msg),
+        _ => None,
+    }
+}
@@ -0,0 +1,148 @@
+//! Unix domain socket listeners.
+//!
+//! Clients are named `unix:<n>`, with `n` counting the connections. On
+//! listeners with `peercred` set, their user and process id are added
+//! (`unix:<uid>:<pid>:<n>`), so ACL rules can match clients by user.
+
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use monoio::net::{ListenerOpts, UnixListener, UnixStream};
+
+use crate::listener::ListenerConfig;
+
+/// Binds a Unix socket at `path`, replacing a socket left behind by an
+/// earlier run.
+///
+/// A socket another server still accepts connections on is left alone.
+pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another server", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        _ => {}
+    }
+    // port reuse doesn't apply to Unix sockets, asking for it fails
+    UnixListener::bind_with_config(path, &ListenerOpts::default().reuse_port(false))
+}
+
+/// Returns the name of the client at the other end of `stream`, the
+/// `connection`th one accepted.
+///
+/// Fails if `config` requires client credentials that can't be read or
+/// don't match.
+pub fn peer_name(
+    config: &ListenerConfig,
+    stream: &UnixStream,
+    connection: u64,
+) -> io::Result<String> {
+    if !config.peercred {
+        return Ok(format!("unix:{connection}"));
+    }
+
+    let cred = peer_credentials(stream)?;
+    if !config.allows_uid(cred.uid) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("clients of user {} aren't allowed", cred.uid),
+        ));
+    }
+    Ok(format!("unix:{}:{}:{}", cred.uid, cred.pid, connection))
+}
+
+/// Returns the credentials of the process at the other end of `stream`.
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rsq-{name}-{}.sock", std::process::id()))
+    }
+
+    #[monoio::test]
+    async fn stale_socket() {
+        let path = socket_path("stale");
+        let _ = std::fs::remove_file(&path);
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        // a server is still listening
+        let err = bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // its socket stays behind
+        drop(listener);
+        assert!(path.exists());
+        let _listener = bind(&path).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[monoio::test]
+    async fn names() {
+        let path = socket_path("names");
+        let _ = std::fs::remove_file(&path);
+        let listener = bind(&path).unwrap();
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let uid = unsafe { libc::getuid() };
+        let config = |settings: &str| -> ListenerConfig {
+            format!("unix:{}{settings}", path.display())
+                .parse()
+                .unwrap()
+        };
+
+        assert_eq!(peer_name(&config(""), &stream, 1).unwrap(), "unix:1");
+        assert_eq!(
+            peer_name(&config(",peercred"), &stream, 2).unwrap(),
+            format!("unix:{uid}:{}:2", std::process::id())
+        );
+        assert_eq!(
+            peer_name(&config(&format!(",uid={uid}")), &stream, 3).unwrap(),
+            format!("unix:{uid}:{}:3", std::process::id())
+        );
+        let err = peer_name(&config(&format!(",uid={}", uid + 1)), &stream, 4)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
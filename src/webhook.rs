@@ -0,0 +1,523 @@
+//! Delivery of channel messages to HTTP endpoints (outbound webhooks).
+//!
+//! Every message is posted to the endpoint on its own connection. Failed
+//! deliveries are retried with exponential backoff, and the outcome of every
+//! delivery can be recorded in a `DeliveryLog`.
+//!
+//! The channel name is sent percent encoded in the `X-Rsq-Channel` header,
+//! see `http::percent_encode_header()`.
+//!
+//! `subscriber()` subscribes an endpoint to a channel.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use monoio::io::AsyncWriteRentExt;
+use monoio::net::TcpStream;
+use serde::Serialize;
+
+use crate::http;
+use crate::messaging::msg::Msg;
+use crate::messaging::peer::{Peer, PeerId, PeerTx};
+use crate::messaging::queue::peer_queue;
+use crate::messaging::util::unix_time_ms;
+use crate::metrics;
+use crate::msg_stream::ReadBuffer;
+use crate::server::Shared;
+
+/// Number of delivery attempts before giving up.
+pub const DEFAULT_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Time a single attempt may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which name resolution is checked for completion.
+const RESOLVE_POLL: Duration = Duration::from_millis(10);
+
+/// An HTTP endpoint messages get posted to.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    url: String,
+    /// `host:port` to connect to.
+    authority: String,
+    path: String,
+    /// Secret for signing requests (`X-Hub-Signature-256`).
+    secret: Option<String>,
+    attempts: u32,
+    backoff: Duration,
+}
+
+/// Outcome of a delivery, as recorded in the delivery log.
+#[derive(Clone, Debug, Serialize)]
+pub struct Delivery {
+    pub url: String,
+    pub channel: String,
+    pub seq: Option<u64>,
+    /// Time of the last attempt, in milliseconds since the Unix epoch.
+    pub time: u64,
+    pub attempts: u32,
+    /// Status code of the last response, if any.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl Webhook {
+    /// Creates a webhook posting to `url`, which has to be an `http://` URL.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid_input("only http:// URLs are supported"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid_input("URL without host"));
+        }
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{authority}:80")
+        };
+
+        Ok(Self {
+            url: url.to_string(),
+            authority,
+            path: path.to_string(),
+            secret: None,
+            attempts: DEFAULT_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+        })
+    }
+
+    /// Signs requests with `secret`.
+    pub fn with_secret(mut self, secret: String) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Sets the number of attempts and the delay before the first retry,
+    /// which doubles with every further retry.
+    pub fn with_retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Posts a message of `channel` to the endpoint, retrying until it
+    /// responds with a success status, a status that makes retrying
+    /// pointless, or the attempts are used up.
+    pub async fn deliver(
+        &self,
+        channel: &str,
+        seq: Option<u64>,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Delivery {
+        let request = self.request(channel, seq, content_type, body);
+        let mut delivery = Delivery {
+            url: self.url.clone(),
+            channel: channel.to_string(),
+            seq,
+            time: 0,
+            attempts: 0,
+            status: None,
+            error: None,
+        };
+
+        let mut backoff = self.backoff;
+        loop {
+            delivery.attempts += 1;
+            delivery.time = unix_time_ms();
+
+            let retry = match monoio::time::timeout(TIMEOUT, self.post(request.clone())).await {
+                Ok(Ok(status)) => {
+                    delivery.status = Some(status);
+                    delivery.error = None;
+                    matches!(status, 408 | 429 | 500..=599)
+                }
+                Ok(Err(e)) => {
+                    delivery.error = Some(e.to_string());
+                    true
+                }
+                Err(_) => {
+                    delivery.error = Some("timed out".to_string());
+                    true
+                }
+            };
+
+            if !retry || delivery.attempts >= self.attempts {
+                return delivery;
+            }
+
+            monoio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn request(
+        &self,
+        channel: &str,
+        seq: Option<u64>,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Bytes {
+        let host = self
+            .authority
+            .strip_suffix(":80")
+            .unwrap_or(&self.authority);
+        // the content type is set by publishers
+        let content_type = content_type
+            .filter(|content_type| !content_type.chars().any(|c| c.is_ascii_control()))
+            .unwrap_or("application/octet-stream");
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             User-Agent: rsq\r\n\
+             Connection: close\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             X-Rsq-Channel: {}\r\n",
+            self.path,
+            host,
+            content_type,
+            body.len(),
+            http::percent_encode_header(channel)
+        );
+        if let Some(seq) = seq {
+            head.push_str(&format!("X-Rsq-Seq: {seq}\r\n"));
+        }
+        if let Some(secret) = &self.secret {
+            head.push_str(&format!(
+                "X-Hub-Signature-256: {}\r\n",
+                http::sign(secret.as_bytes(), body)
+            ));
+        }
+        head.push_str("\r\n");
+
+        let mut request = BytesMut::from(head.as_bytes());
+        request.extend_from_slice(body);
+        request.freeze()
+    }
+
+    /// Sends a request, returning the response status.
+    async fn post(&self, request: Bytes) -> io::Result<u16> {
+        let mut stream = self.connect().await?;
+        let (res, _) = stream.write_all(request).await;
+        res?;
+
+        let mut reader = ReadBuffer::new(stream);
+        loop {
+            if let Some(status) = http::parse_status(reader.buffered())? {
+                return Ok(status);
+            }
+            let buffered = reader.buffered().len();
+            reader.fill(buffered + 1).await?;
+        }
+    }
+
+    /// Connects to the first address of the endpoint that accepts the
+    /// connection.
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let mut error = None;
+        for addr in resolve(&self.authority).await? {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses")))
+    }
+}
+
+/// Resolves `authority` (`host:port`).
+///
+/// Names are resolved on a thread of their own, as resolving blocks and
+/// would hold up the whole server. The runtime isn't woken by other
+/// threads, so the result is polled for.
+async fn resolve(authority: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = authority.parse() {
+        return Ok(vec![addr]);
+    }
+
+    let (tx, rx) = flume::bounded(1);
+    let authority = authority.to_string();
+    std::thread::Builder::new()
+        .name("rsq-resolve".to_string())
+        .spawn(move || {
+            let _ = tx.send(
+                authority
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.collect::<Vec<_>>()),
+            );
+        })?;
+    loop {
+        match rx.try_recv() {
+            Ok(addrs) => return addrs,
+            Err(flume::TryRecvError::Empty) => monoio::time::sleep(RESOLVE_POLL).await,
+            Err(flume::TryRecvError::Disconnected) => {
+                return Err(io::Error::other("resolver thread failed"))
+            }
+        }
+    }
+}
+
+impl Delivery {
+    pub fn is_success(&self) -> bool {
+        matches!(self.status, Some(200..=299))
+    }
+}
+
+/// Log of webhook deliveries, one JSON object per line.
+pub struct DeliveryLog {
+    file: File,
+}
+
+impl DeliveryLog {
+    /// Opens the log at `path` for appending.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, delivery: &Delivery) -> io::Result<()> {
+        let mut line = serde_json::to_vec(delivery)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+fn invalid_input<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// `Peer` handle for webhook endpoints subscribed to a channel.
+struct WebhookPeer {
+    tx: PeerTx,
+
+    peer_id: PeerId,
+}
+
+impl Peer for WebhookPeer {
+    fn get_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        &self.tx
+    }
+}
+
+/// Largest number of messages waiting for delivery to a webhook endpoint.
+/// While the endpoint lags behind further messages are dropped.
+const WEBHOOK_BACKLOG: usize = 1024;
+
+/// Subscribes a webhook endpoint to `channel` and posts the channel's
+/// messages to it, one after the other.
+///
+/// Messages published while the backlog is full are dropped and logged.
+///
+/// The endpoint's peer is named `webhook:<number>:<url>`, `number` telling
+/// apart webhooks posting to the same URL.
+pub async fn subscriber(
+    state: Rc<RefCell<Shared>>,
+    number: usize,
+    channel: String,
+    webhook: Webhook,
+    delivery_log: Option<Rc<RefCell<DeliveryLog>>>,
+) {
+    let (tx, mut rx) = peer_queue();
+    let peer = WebhookPeer {
+        tx,
+        peer_id: PeerId::new(&format!("webhook:{}:{}", number, webhook.url())),
+    };
+
+    {
+        let mut state = state.borrow_mut();
+        state.router.peer_add(&peer);
+        let channel_id = state.router.channel_get_or_add(channel.clone());
+        if let Err(e) = state.router.attach(channel_id, &peer) {
+            tracing::error!("can't subscribe {} to {}: {e}", webhook.url(), channel);
+            state.router.peer_remove(peer.get_id());
+            return;
+        }
+    }
+
+    let mut backlog = VecDeque::new();
+    let mut dropped = 0;
+    loop {
+        let frame = match backlog.pop_front() {
+            Some(frame) => frame,
+            None => match rx.recv_async().await {
+                Ok(frame) => frame,
+                Err(_) => break,
+            },
+        };
+        if frame.is_expired() {
+            metrics::DROPPED.inc();
+            continue;
+        }
+        let msg = match Msg::decode_frame(&frame.data) {
+            Ok(Msg::ChannelMsg(msg)) if msg.headers().fragment.is_none() => msg,
+            _ => continue,
+        };
+        let content_type = msg.headers().content_type.as_deref();
+
+        // keep taking messages off the queue while delivering, so only the
+        // backlog grows
+        let mut delivery =
+            std::pin::pin!(webhook.deliver(&channel, frame.seq, content_type, msg.content()));
+        let delivery = loop {
+            monoio::select! {
+                delivery = &mut delivery => break delivery,
+                frame = rx.recv_async() => match frame {
+                    Ok(frame) if backlog.len() < WEBHOOK_BACKLOG => {
+                        backlog.push_back(frame.detach());
+                    }
+                    Ok(_) => {
+                        metrics::DROPPED.inc();
+                        dropped += 1;
+                    }
+                    Err(_) => break delivery.await,
+                },
+            }
+        };
+        if dropped > 0 {
+            tracing::warn!(
+                "dropped {dropped} messages of {} for {}, its backlog is full",
+                channel,
+                webhook.url()
+            );
+            dropped = 0;
+        }
+        if !delivery.is_success() {
+            tracing::warn!(
+                "giving up delivering message {:?} of {} to {} after {} attempts: {:?} {:?}",
+                delivery.seq,
+                channel,
+                webhook.url(),
+                delivery.attempts,
+                delivery.status,
+                delivery.error
+            );
+        }
+        if let Some(delivery_log) = &delivery_log {
+            if let Err(e) = delivery_log.borrow_mut().record(&delivery) {
+                tracing::warn!("error writing webhook delivery log: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::RequestReader;
+    use monoio::net::TcpListener;
+
+    #[test]
+    fn url() {
+        let webhook = Webhook::new("http://example.com/hooks/rsq").unwrap();
+        assert_eq!(webhook.authority, "example.com:80");
+        assert_eq!(webhook.path, "/hooks/rsq");
+
+        let webhook = Webhook::new("http://127.0.0.1:8080").unwrap();
+        assert_eq!(webhook.authority, "127.0.0.1:8080");
+        assert_eq!(webhook.path, "/");
+
+        assert!(Webhook::new("https://example.com/").is_err());
+        assert!(Webhook::new("http:///").is_err());
+    }
+
+    #[test]
+    fn header_injection() {
+        let webhook = Webhook::new("http://example.com/").unwrap();
+        let request = webhook.request("a\r\nX-Evil: 1", None, Some("text/plain\r\nX-Evil: 2"), b"");
+        let request = std::str::from_utf8(&request).unwrap();
+        assert!(request.contains("X-Rsq-Channel: a%0D%0AX-Evil: 1\r\n"));
+        assert!(request.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(!request.contains("\nX-Evil"));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn resolve_names() {
+        let addrs = resolve("localhost:80").await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        assert_eq!(
+            resolve("127.0.0.1:80").await.unwrap(),
+            vec!["127.0.0.1:80".parse().unwrap()]
+        );
+        assert!(resolve("no port").await.is_err());
+    }
+
+    /// Answers requests with `statuses`, one connection each, returning the
+    /// requests.
+    async fn stub(listener: TcpListener, statuses: &[u16]) -> Vec<http::Request> {
+        let mut requests = Vec::new();
+        for status in statuses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = RequestReader::new(stream);
+            requests.push(reader.next().await.unwrap().unwrap());
+
+            let mut stream = reader.into_inner();
+            let (res, _) = stream.write_all(http::text_response(*status, "")).await;
+            res.unwrap();
+        }
+        requests
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn deliver() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let stub = monoio::spawn(stub(listener, &[503, 200, 400]));
+
+        let webhook = Webhook::new(&url)
+            .unwrap()
+            .with_secret("secret".to_string())
+            .with_retries(3, Duration::from_millis(10));
+
+        let delivery = webhook.deliver("builds", Some(7), None, b"data").await;
+        assert!(delivery.is_success());
+        assert_eq!(delivery.attempts, 2);
+
+        // client errors aren't retried
+        let delivery = webhook.deliver("builds", Some(8), None, b"data").await;
+        assert!(!delivery.is_success());
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.status, Some(400));
+
+        let requests = stub.await;
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/hook");
+        assert_eq!(&requests[0].body[..], b"data");
+        assert_eq!(requests[0].header("x-rsq-channel"), Some("builds"));
+        assert_eq!(requests[2].header("x-rsq-seq"), Some("8"));
+        assert!(http::verify_signature(
+            b"secret",
+            requests[1].signature().unwrap(),
+            &requests[1].body
+        ));
+    }
+}
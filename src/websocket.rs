@@ -0,0 +1,632 @@
+//! Server side WebSocket (RFC 6455) support.
+//!
+//! Only what rsq needs: the opening handshake, reading (possibly fragmented)
+//! messages from clients, and framing unfragmented messages to clients.
+//! Extensions are not supported.
+//!
+//! Clients of the listener (`listener()`) speak the rsq protocol, one frame
+//! per message.
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use monoio::io::{AsyncReadRent, OwnedReadHalf, OwnedWriteHalf, Splitable};
+use monoio::net::{TcpListener, TcpStream};
+use sha1::{Digest, Sha1};
+
+use crate::listener::ListenerConfig;
+use crate::messaging::peer::{Peer, PeerId, PeerRx, PeerTx};
+use crate::messaging::queue::peer_queue;
+use crate::msg_stream::{FrameWriter, ReadBuffer};
+use crate::server::{
+    connection_peer_id, count_decode_error, handle_frame, prepare_frame, shutdown_requested,
+    Endpoint, Session, Shared,
+};
+use crate::wire;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest handshake request accepted.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Largest message accepted, enough for one maximum size rsq frame.
+pub const MAX_MESSAGE_LEN: usize = wire::PREFIX_LEN + wire::MAX_FRAME_LEN;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Opcode {
+    Continuation = 0,
+    Text = 1,
+    Binary = 2,
+    Close = 8,
+    Ping = 9,
+    Pong = 10,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> io::Result<Self> {
+        Ok(match bits {
+            0 => Opcode::Continuation,
+            1 => Opcode::Text,
+            2 => Opcode::Binary,
+            8 => Opcode::Close,
+            9 => Opcode::Ping,
+            10 => Opcode::Pong,
+            _ => return Err(invalid_data(format!("unknown opcode {bits}"))),
+        })
+    }
+
+    fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Message {
+    Text(BytesMut),
+    Binary(BytesMut),
+    Ping(BytesMut),
+    Pong(BytesMut),
+    Close,
+}
+
+/// A WebSocket upgrade request.
+#[derive(Debug)]
+pub struct Request {
+    pub path: String,
+    key: String,
+}
+
+impl Request {
+    /// Parses an upgrade request from the start of `buf`.
+    ///
+    /// Returns the request and its length, or `None` if `buf` doesn't hold a
+    /// complete request yet.
+    pub fn parse(buf: &[u8]) -> io::Result<Option<(Request, usize)>> {
+        let len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if buf.len() > MAX_REQUEST_LEN => {
+                return Err(invalid_data("request too large"));
+            }
+            None => return Ok(None),
+        };
+
+        let head = std::str::from_utf8(&buf[..len]).map_err(invalid_data)?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let path = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some(path)) => path.to_string(),
+            _ => return Err(invalid_data("expected a GET request")),
+        };
+
+        let mut upgrade = false;
+        let mut key = None;
+        let mut version = None;
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = value.eq_ignore_ascii_case("websocket");
+            } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("sec-websocket-version") {
+                version = Some(value.to_string());
+            }
+        }
+
+        if !upgrade {
+            return Err(invalid_data("not a WebSocket upgrade request"));
+        }
+        if version.as_deref() != Some("13") {
+            return Err(invalid_data("unsupported WebSocket version"));
+        }
+        let key = key.ok_or_else(|| invalid_data("missing Sec-WebSocket-Key"))?;
+
+        Ok(Some((Request { path, key }, len)))
+    }
+
+    /// Returns the response accepting the upgrade.
+    pub fn accept(&self) -> Bytes {
+        Bytes::from(format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&self.key)
+        ))
+    }
+}
+
+/// Returns the response rejecting an upgrade request.
+pub fn bad_request(reason: &str) -> Bytes {
+    Bytes::from(format!(
+        "HTTP/1.1 400 Bad Request\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        reason.len(),
+        reason
+    ))
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.finalize())
+}
+
+/// Returns the header of an unfragmented, unmasked frame with a payload of
+/// `len` bytes. The payload is written separately.
+pub fn frame_header(opcode: Opcode, len: usize) -> Bytes {
+    let mut header = BytesMut::with_capacity(10);
+    header.put_u8(0x80 | opcode as u8);
+    if len < 126 {
+        header.put_u8(len as u8);
+    } else if len <= u16::MAX as usize {
+        header.put_u8(126);
+        header.put_u16(len as u16);
+    } else {
+        header.put_u8(127);
+        header.put_u64(len as u64);
+    }
+    header.freeze()
+}
+
+/// Returns a complete unfragmented, unmasked frame.
+pub fn frame(opcode: Opcode, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::from(&frame_header(opcode, payload.len())[..]);
+    frame.extend_from_slice(payload);
+    frame.freeze()
+}
+
+/// Reads the handshake and messages sent by a client.
+pub struct MessageReader<IO> {
+    reader: ReadBuffer<IO>,
+    /// Data message being reassembled from fragments.
+    partial: Option<(Opcode, BytesMut)>,
+    /// Largest message accepted.
+    max_len: usize,
+}
+
+impl<IO: AsyncReadRent> MessageReader<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            reader: ReadBuffer::new(io),
+            partial: None,
+            max_len: MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// Rejects messages larger than `max_len`.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Reads the upgrade request.
+    pub async fn handshake(&mut self) -> io::Result<Request> {
+        loop {
+            if let Some((request, len)) = Request::parse(self.reader.buffered())? {
+                self.reader.split_to(len);
+                return Ok(request);
+            }
+            let buffered = self.reader.buffered().len();
+            self.reader.fill(buffered + 1).await?;
+        }
+    }
+
+    /// Returns the next complete message.
+    pub async fn next(&mut self) -> Option<io::Result<Message>> {
+        loop {
+            let (fin, opcode, payload) = match self.next_frame().await {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    if self.reader.buffered().is_empty() && self.partial.is_none() {
+                        return None;
+                    }
+                    return Some(Err(e));
+                }
+                Err(e) => return Some(Err(e)),
+            };
+
+            let (opcode, payload) = match (opcode, self.partial.take()) {
+                (Opcode::Continuation, Some((opcode, mut data))) => {
+                    if data.len() + payload.len() > self.max_len {
+                        return Some(Err(invalid_data("message too large")));
+                    }
+                    data.extend_from_slice(&payload);
+                    (opcode, data)
+                }
+                (Opcode::Continuation, None) => {
+                    return Some(Err(invalid_data("unexpected continuation frame")));
+                }
+                (opcode, partial) if opcode.is_control() => {
+                    // control frames may arrive between fragments
+                    self.partial = partial;
+                    (opcode, payload)
+                }
+                (_, Some(_)) => {
+                    return Some(Err(invalid_data("expected continuation frame")));
+                }
+                (opcode, None) => (opcode, payload),
+            };
+
+            if !fin {
+                self.partial = Some((opcode, payload));
+                continue;
+            }
+
+            return Some(Ok(match opcode {
+                Opcode::Text => Message::Text(payload),
+                Opcode::Binary => Message::Binary(payload),
+                Opcode::Ping => Message::Ping(payload),
+                Opcode::Pong => Message::Pong(payload),
+                Opcode::Close => Message::Close,
+                Opcode::Continuation => unreachable!(),
+            }));
+        }
+    }
+
+    /// Reads a single frame, returning its fin bit, opcode and unmasked
+    /// payload.
+    async fn next_frame(&mut self) -> io::Result<(bool, Opcode, BytesMut)> {
+        self.reader.fill(2).await?;
+        let (b0, b1) = (self.reader.buffered()[0], self.reader.buffered()[1]);
+
+        let fin = b0 & 0x80 != 0;
+        if b0 & 0x70 != 0 {
+            return Err(invalid_data("reserved bits set"));
+        }
+        let opcode = Opcode::from_bits(b0 & 0x0f)?;
+        if b1 & 0x80 == 0 {
+            return Err(invalid_data("unmasked client frame"));
+        }
+
+        let header_len = match b1 & 0x7f {
+            126 => 2 + 2 + 4,
+            127 => 2 + 8 + 4,
+            _ => 2 + 4,
+        };
+        self.reader.fill(header_len).await?;
+
+        let header = &self.reader.buffered()[..header_len];
+        let len = match b1 & 0x7f {
+            126 => u16::from_be_bytes([header[2], header[3]]) as u64,
+            127 => u64::from_be_bytes(header[2..10].try_into().unwrap()),
+            len => len as u64,
+        };
+        if len > self.max_len as u64 || (opcode.is_control() && (len > 125 || !fin)) {
+            return Err(invalid_data("invalid frame length"));
+        }
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&header[header_len - 4..]);
+
+        self.reader.fill(header_len + len as usize).await?;
+        let mut payload = self
+            .reader
+            .split_to(header_len + len as usize)
+            .split_off(header_len);
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok((fin, opcode, payload))
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// `Peer` handle for WebSocket connections.
+///
+/// Each binary WebSocket message carries one frame. Alternatively, clients
+/// can send JSON encoded messages as text messages (see `Session::text`).
+struct WebSocketPeer {
+    tx: PeerTx,
+
+    peer_id: PeerId,
+
+    session: Session,
+}
+
+impl WebSocketPeer {
+    fn new(peer_id: PeerId) -> (PeerRx, WebSocketPeer) {
+        let (tx, rx) = peer_queue();
+
+        (
+            rx,
+            WebSocketPeer {
+                tx,
+                peer_id,
+                session: Session::default(),
+            },
+        )
+    }
+}
+
+impl Peer for WebSocketPeer {
+    fn get_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    fn get_sink(&self) -> &PeerTx {
+        &self.tx
+    }
+}
+
+/// Accepts WebSocket connections.
+pub async fn listener(state: Rc<RefCell<Shared>>, listener: TcpListener, endpoint: Rc<Endpoint>) {
+    loop {
+        endpoint.wait_for_capacity().await;
+
+        let accepted = monoio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(&state) => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("WebSocket accept error: {e}");
+                continue;
+            }
+        };
+        if !endpoint.config.allows(addr.ip()) {
+            tracing::info!("rejecting connection from {}", addr);
+            continue;
+        }
+        let _ = stream.set_nodelay(true);
+
+        let state = Rc::clone(&state);
+        let endpoint = Rc::clone(&endpoint);
+        monoio::spawn(async move {
+            endpoint.opened();
+            if let Err(e) = process_websocket(state, stream, addr, &endpoint.config).await {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+            endpoint.closed();
+        });
+    }
+}
+
+/// Process an individual WebSocket client
+async fn process_websocket(
+    state: Rc<RefCell<Shared>>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: &ListenerConfig,
+) -> Result<(), Box<dyn Error>> {
+    let peer_addr = stream.peer_addr()?;
+    let (stream_in, stream_out) = stream.into_split();
+    let mut reader =
+        MessageReader::new(stream_in).with_max_len(wire::PREFIX_LEN + config.max_frame_len);
+    let mut writer = FrameWriter::new(stream_out);
+
+    match reader.handshake().await {
+        Ok(request) => writer.push(request.accept()),
+        Err(e) => {
+            writer.push(bad_request(&e.to_string()));
+            writer.flush().await?;
+            return Err(e.into());
+        }
+    }
+    writer.flush().await?;
+
+    let (rx, mut peer) = WebSocketPeer::new(connection_peer_id(config.kind, peer_addr));
+    peer.session.token = config.token.clone();
+
+    let peer_name = addr.to_string();
+    tracing::info!("new WebSocket connection from {}", &peer_name);
+
+    {
+        let mut state = state.borrow_mut();
+        state
+            .connections
+            .insert(peer.get_id().clone(), peer.tx.clone());
+        state.router.peer_add(&peer);
+    }
+
+    let peer_id = peer.get_id().clone();
+    let session = peer.session.clone();
+    let compression_threshold = state.borrow().compression_threshold;
+
+    // pongs and close replies, sent alongside the peer's frames
+    let (control_tx, control_rx) = flume::unbounded();
+
+    let from_client_handle = monoio::spawn(websocket_from_client(
+        state.clone(),
+        reader,
+        peer,
+        control_tx,
+    ));
+    let to_client_handle = monoio::spawn(websocket_to_client(
+        rx,
+        control_rx,
+        writer,
+        session,
+        compression_threshold,
+    ));
+
+    monoio::select!(
+        e = from_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        },
+        e = to_client_handle => {
+            if let Err(e) = e {
+                tracing::error!("{peer_name}: {e}");
+            }
+        }
+    );
+
+    {
+        let mut state = state.borrow_mut();
+        state.connections.remove(&peer_id);
+        state.router.peer_remove(&peer_id);
+    }
+
+    tracing::info!("{peer_name} disconnected");
+
+    Ok(())
+}
+
+async fn websocket_from_client(
+    state: Rc<RefCell<Shared>>,
+    mut reader: MessageReader<OwnedReadHalf<TcpStream>>,
+    peer: WebSocketPeer,
+    control: flume::Sender<Bytes>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match reader.next().await {
+            Some(Ok(Message::Binary(bytes))) => {
+                match wire::parse_prefix(&bytes) {
+                    Some((len, _)) if wire::PREFIX_LEN + len == bytes.len() => {}
+                    _ => anyhow::bail!("binary message doesn't contain exactly one frame"),
+                }
+                handle_frame(&state, &peer, &peer.session, bytes).await?;
+            }
+            Some(Ok(Message::Text(text))) => {
+                peer.session.text.set(true);
+                let mut bytes = BytesMut::with_capacity(wire::PREFIX_LEN + text.len());
+                bytes.put_u32(text.len() as u32);
+                bytes.extend_from_slice(&text);
+                handle_frame(&state, &peer, &peer.session, bytes).await?;
+            }
+            Some(Ok(Message::Ping(payload))) => {
+                let _ = control.send(frame(Opcode::Pong, &payload));
+            }
+            Some(Ok(Message::Pong(_))) => {}
+            Some(Ok(Message::Close)) => {
+                let _ = control.send(frame(Opcode::Close, &[]));
+                break;
+            }
+            Some(Err(e)) => {
+                count_decode_error(&e);
+                tracing::info!("websocket_from_client error: {e}");
+                break;
+            }
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+async fn websocket_to_client(
+    mut rx: PeerRx,
+    control: flume::Receiver<Bytes>,
+    mut writer: FrameWriter<OwnedWriteHalf<TcpStream>>,
+    session: Session,
+    compression_threshold: usize,
+) -> Result<(), anyhow::Error> {
+    loop {
+        monoio::select! {
+            frame = rx.recv_async() => {
+                let mut next = Some(frame?);
+                while let Some(frame) = next.take() {
+                    if let Some(data) = prepare_frame(frame, &session, compression_threshold) {
+                        let (opcode, payload) = if session.text.get() {
+                            (Opcode::Text, data.slice(wire::PREFIX_LEN..))
+                        } else {
+                            (Opcode::Binary, data)
+                        };
+                        writer.push(frame_header(opcode, payload.len()));
+                        writer.push(payload);
+                    }
+                    if !writer.is_full() {
+                        next = rx.try_recv();
+                    }
+                }
+            }
+            control = control.recv_async() => {
+                match control {
+                    Ok(frame) => writer.push(frame),
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accept() {
+        // example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn request() {
+        let request = b"GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+
+        assert!(Request::parse(&request[..20]).unwrap().is_none());
+
+        let (parsed, len) = Request::parse(request).unwrap().unwrap();
+        assert_eq!(len, request.len());
+        assert_eq!(parsed.path, "/chat");
+        assert!(std::str::from_utf8(&parsed.accept())
+            .unwrap()
+            .contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        assert!(Request::parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[monoio::test]
+    async fn messages() {
+        // masked frames from RFC 6455, section 5.7: a fragmented "Hel" "lo"
+        // text message with a ping in between
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0x01, 0x83, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d]);
+        data.extend_from_slice(&[0x89, 0x80, 0x01, 0x02, 0x03, 0x04]);
+        data.extend_from_slice(&[0x80, 0x82, 0x37, 0xfa, 0x21, 0x3d, 0x5b, 0x95]);
+        data.extend_from_slice(&[0x88, 0x80, 0x01, 0x02, 0x03, 0x04]);
+
+        let mut reader = MessageReader::new(&data[..]);
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            Message::Ping(BytesMut::new())
+        );
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            Message::Text(BytesMut::from(&b"Hello"[..]))
+        );
+        assert_eq!(reader.next().await.unwrap().unwrap(), Message::Close);
+        assert!(reader.next().await.is_none());
+    }
+
+    #[test]
+    fn header() {
+        assert_eq!(&frame_header(Opcode::Binary, 5)[..], &[0x82, 5]);
+        assert_eq!(&frame_header(Opcode::Text, 256)[..], &[0x81, 126, 1, 0]);
+        assert_eq!(
+            &frame_header(Opcode::Binary, 65536)[..],
+            &[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,520 @@
+//! Wire format.
+//!
+//! Every message is sent as a frame:
+//!
+//! ```text
+//! | length: u32 BE | body |
+//! ```
+//!
+//! The body is the message encoded with bincode's standard configuration
+//! (little endian, variable length integers). If the top bit of the length is
+//! set, the body is compressed (see `compression`) and the remaining 31 bits
+//! are the compressed length.
+//!
+//! Enums are encoded as their variant index followed by their fields, so new
+//! variants must only ever be appended. A channel message is encoded as:
+//!
+//! ```text
+//! | 0 (Msg::ChannelMsg) | sender | channel | headers | content |
+//! ```
+//!
+//! `content` comes last, so the server can decode everything before it (see
+//! `Msg::decode_header`) and forward the frame without re-encoding it.
+//! `headers` is a length prefixed byte string, see `Headers`.
+//!
+//! Both the server (`msg_stream`) and the client (`monoio_bincode`) use the
+//! functions in this module to read and write frames.
+//!
+//! For clients not written in Rust, the server also accepts bodies encoded as
+//! JSON or CBOR, using the serde representation of `Msg` (an externally
+//! tagged enum, so a map with a single entry). The framing is the same. The
+//! encoding of a connection is detected from its first frame, and the server
+//! translates frames to and from bincode as needed (see `Encoding`).
+
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::io;
+use std::rc::Rc;
+
+use bincode::config::Configuration;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::compression;
+
+/// bincode configuration used for frame bodies.
+pub const CONFIG: Configuration = bincode::config::standard();
+
+/// Size of the length prefix.
+pub const PREFIX_LEN: usize = 4;
+
+/// Largest frame body clients accept.
+pub const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Parses the length prefix at the start of `src`.
+///
+/// Returns the body length and whether the body is compressed, or `None` if
+/// `src` is too short.
+pub fn parse_prefix(src: &[u8]) -> Option<(usize, bool)> {
+    let prefix = src.get(..PREFIX_LEN)?.try_into().unwrap();
+    Some(compression::frame_len(u32::from_be_bytes(prefix)))
+}
+
+struct BytesMutWriter<'a> {
+    bytes: &'a mut BytesMut,
+}
+
+impl bincode::enc::write::Writer for BytesMutWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.bytes.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+struct CountingWriter {
+    written: usize,
+}
+
+impl bincode::enc::write::Writer for CountingWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.written += bytes.len();
+        Ok(())
+    }
+}
+
+/// Appends `msg` to `dst` as a frame.
+pub fn encode_frame<T: Encode>(msg: &T, dst: &mut BytesMut) -> Result<(), EncodeError> {
+    let mut counter = CountingWriter { written: 0 };
+    bincode::encode_into_writer(msg, &mut counter, CONFIG)?;
+
+    dst.reserve(PREFIX_LEN + counter.written);
+    dst.put_u32(counter.written as u32);
+    bincode::encode_into_writer(msg, BytesMutWriter { bytes: dst }, CONFIG)
+}
+
+/// Returns `msg` encoded as a frame.
+pub fn frame<T: Encode>(msg: &T) -> Bytes {
+    let mut dst = BytesMut::new();
+    encode_frame(msg, &mut dst).expect("encoding went well");
+    dst.freeze()
+}
+
+/// Decodes an uncompressed frame body.
+///
+/// If `header_only` is set, the content of channel messages is skipped.
+/// Returns the message and the number of bytes decoded.
+pub fn decode_body<T: Decode<bool>>(
+    body: &[u8],
+    header_only: bool,
+) -> Result<(T, usize), DecodeError> {
+    bincode::decode_from_slice_with_context(body, CONFIG, header_only)
+}
+
+/// Encoding of frame bodies.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    Json,
+    Cbor,
+}
+
+/// Encoding of a connection, shared between its reader and writer. `None`
+/// until detected.
+pub type EncodingHandle = Rc<Cell<Option<Encoding>>>;
+
+impl Encoding {
+    /// Detects the encoding of an uncompressed frame body.
+    ///
+    /// bincode bodies start with the `Msg` variant index, JSON and CBOR bodies
+    /// with a map.
+    pub fn detect(body: &[u8]) -> Option<Encoding> {
+        match body.first()? {
+            0..=2 => Some(Encoding::Bincode),
+            b'{' | b' ' | b'\t' | b'\r' | b'\n' => Some(Encoding::Json),
+            // map with one entry
+            0xa1 => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Encodes `msg` as a frame.
+    pub fn frame<T: Encode + Serialize>(self, msg: &T) -> io::Result<Bytes> {
+        let body = match self {
+            Encoding::Bincode => return Ok(frame(msg)),
+            Encoding::Json => serde_json::to_vec(msg).map_err(invalid_data)?,
+            Encoding::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(msg, &mut body).map_err(invalid_data)?;
+                body
+            }
+        };
+
+        let mut dst = BytesMut::with_capacity(PREFIX_LEN + body.len());
+        dst.put_u32(body.len() as u32);
+        dst.extend_from_slice(&body);
+        Ok(dst.freeze())
+    }
+
+    /// Decodes an uncompressed frame body.
+    pub fn decode_body<T: Decode<bool> + DeserializeOwned>(self, body: &[u8]) -> io::Result<T> {
+        match self {
+            Encoding::Bincode => Ok(decode_body(body, false).map_err(invalid_data)?.0),
+            Encoding::Json => serde_json::from_slice(body).map_err(invalid_data),
+            Encoding::Cbor => ciborium::from_reader(body).map_err(invalid_data),
+        }
+    }
+}
+
+/// Re-encodes an uncompressed frame.
+pub fn translate<T>(frame: &[u8], from: Encoding, to: Encoding) -> io::Result<Bytes>
+where
+    T: Encode + Decode<bool> + Serialize + DeserializeOwned,
+{
+    if from == to {
+        return Ok(Bytes::copy_from_slice(frame));
+    }
+    let body = frame
+        .get(PREFIX_LEN..)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "short frame"))?;
+    to.frame(&from.decode_body::<T>(body)?)
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use monoio_codec::{Decoded, Decoder, Encoder};
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+    use slotmap::KeyData;
+
+    use super::*;
+    use crate::compression::Compression;
+    use crate::messaging::channel::{ChannelId, ChannelInfo, ChannelOptions};
+    use crate::messaging::fragment::Fragment;
+    use crate::messaging::headers::{Headers, Priority};
+    use crate::messaging::msg::*;
+    use crate::messaging::peer::PeerId;
+    use crate::monoio_bincode::BincodeCodec;
+    use crate::msg_stream::FrameDecoder;
+
+    fn channel_id() -> ChannelId {
+        // index 5, version 1
+        ChannelId::from(KeyData::from_ffi((1 << 32) | 5))
+    }
+
+    /// Messages and their frames. These must never change.
+    fn golden() -> Vec<(Msg, &'static [u8])> {
+        vec![
+            (
+                Msg::new_channel_msg(PeerId::new("peer"), channel_id(), b"data".to_vec()),
+                &[
+                    0, 0, 0, 30, 0, 4, 112, 101, 101, 114, 253, 5, 0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 0,
+                    0, 0, 0, 0, 1, 0, 4, 100, 97, 116, 97,
+                ],
+            ),
+            (
+                Msg::ChannelMsg(
+                    ChannelMsg::new(PeerId::new("peer"), channel_id(), b"data".to_vec())
+                        .with_headers(
+                            Headers::new()
+                                .with_key("k".to_string())
+                                .with_priority(Priority::High),
+                        ),
+                ),
+                &[
+                    0, 0, 0, 32, 0, 4, 112, 101, 101, 114, 253, 5, 0, 0, 0, 1, 0, 0, 0, 11, 0, 0,
+                    0, 1, 1, 107, 0, 0, 0, 2, 0, 4, 100, 97, 116, 97,
+                ],
+            ),
+            (
+                Msg::channel_join("chan".to_string()),
+                &[0, 0, 0, 7, 1, 0, 4, 99, 104, 97, 110],
+            ),
+            (Msg::new_status(StatusMsg::Connected), &[0, 0, 0, 2, 2, 1]),
+            (
+                Msg::new_status(StatusMsg::ChannelId("chan".to_string(), channel_id())),
+                &[
+                    0, 0, 0, 16, 2, 3, 4, 99, 104, 97, 110, 253, 5, 0, 0, 0, 1, 0, 0, 0,
+                ],
+            ),
+        ]
+    }
+
+    /// Checks that `msg` round-trips through the client codec and the
+    /// server's decoding, and that both encode it the same way.
+    fn check_roundtrip(msg: &Msg) {
+        let frame = frame(msg);
+
+        let mut codec = BincodeCodec::<Msg>::new();
+        let mut encoded = BytesMut::new();
+        codec.encode(Arc::new(msg.clone()), &mut encoded).unwrap();
+        assert_eq!(encoded, frame);
+
+        match codec.decode(&mut encoded).unwrap() {
+            Decoded::Some(decoded) => assert_eq!(*decoded, *msg),
+            _ => panic!("codec didn't decode a whole frame"),
+        }
+        assert!(encoded.is_empty());
+
+        assert_eq!(Msg::decode_frame(&frame).unwrap(), *msg);
+
+        for encoding in [Encoding::Json, Encoding::Cbor] {
+            let translated = translate::<Msg>(&frame, Encoding::Bincode, encoding).unwrap();
+            assert_eq!(Encoding::detect(&translated[PREFIX_LEN..]), Some(encoding));
+            assert_eq!(
+                translate::<Msg>(&translated, encoding, Encoding::Bincode).unwrap(),
+                frame
+            );
+        }
+
+        let (header, content_len) = Msg::decode_header(&frame).unwrap();
+        match (msg, header) {
+            (Msg::ChannelMsg(msg), Msg::ChannelMsg(header)) => {
+                assert_eq!(header.sender(), msg.sender());
+                assert_eq!(header.channel(), msg.channel());
+                assert_eq!(header.headers(), msg.headers());
+                assert!(header.content().is_empty());
+                assert_eq!(content_len, msg.content().len());
+            }
+            (msg, header) => {
+                assert_eq!(header, *msg);
+                assert_eq!(content_len, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn golden_frames() {
+        for (msg, bytes) in golden() {
+            assert_eq!(&frame(&msg)[..], bytes, "{:?}", msg);
+            check_roundtrip(&msg);
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(parse_prefix(&[0, 0, 1]), None);
+        assert_eq!(parse_prefix(&[0, 0, 1, 2]), Some((258, false)));
+        assert_eq!(parse_prefix(&[0x80, 0, 1, 2, 0xff]), Some((258, true)));
+    }
+
+    #[test]
+    fn json() {
+        let body = br#"{"ControlMsg":{"ChannelJoin":"chan"}}"#;
+        let msg: Msg = Encoding::Json.decode_body(body).unwrap();
+        assert_eq!(msg, Msg::channel_join("chan".to_string()));
+        assert_eq!(
+            &Encoding::Json.frame(&msg).unwrap()[PREFIX_LEN..],
+            &body[..]
+        );
+    }
+
+    #[monoio::test]
+    async fn frame_decoder() {
+        let stream: Vec<u8> = golden()
+            .iter()
+            .flat_map(|(_, bytes)| bytes.iter().copied())
+            .collect();
+
+        let mut decoder = FrameDecoder::new(&stream[..]);
+        for (msg, bytes) in golden() {
+            let frame = decoder.next().await.unwrap().unwrap();
+            assert_eq!(&frame[..], bytes);
+            assert_eq!(Msg::decode_frame(&frame).unwrap(), msg);
+        }
+        assert!(decoder.next().await.is_none());
+
+        let bytes = golden()[0].1;
+        let mut decoder = FrameDecoder::new(bytes).with_max_len(bytes.len() - PREFIX_LEN - 1);
+        assert!(decoder.next().await.unwrap().is_err());
+    }
+
+    fn peer_id() -> impl Strategy<Value = PeerId> {
+        any::<String>().prop_map(|id| PeerId::new(&id))
+    }
+
+    fn any_channel_id() -> impl Strategy<Value = ChannelId> {
+        any::<u64>().prop_map(|ffi| ChannelId::from(KeyData::from_ffi(ffi)))
+    }
+
+    fn priority() -> impl Strategy<Value = Priority> {
+        prop_oneof![
+            Just(Priority::Low),
+            Just(Priority::Normal),
+            Just(Priority::High)
+        ]
+    }
+
+    fn compression() -> impl Strategy<Value = Compression> {
+        prop_oneof![Just(Compression::Zstd), Just(Compression::Lz4)]
+    }
+
+    fn fragment() -> impl Strategy<Value = Fragment> {
+        (any::<u64>(), any::<u64>(), any::<bool>()).prop_map(|(stream, seq, last)| Fragment {
+            stream,
+            seq,
+            last,
+        })
+    }
+
+    fn headers() -> impl Strategy<Value = Headers> {
+        (
+            any::<Option<String>>(),
+            any::<Option<String>>(),
+            any::<Option<u64>>(),
+            any::<Option<String>>(),
+            btree_map(any::<String>(), any::<String>(), 0..4),
+            any::<Option<u64>>(),
+            any::<Option<u64>>(),
+            priority(),
+            proptest::option::of(fragment()),
+        )
+            .prop_map(
+                |(
+                    content_type,
+                    correlation_id,
+                    timestamp,
+                    key,
+                    extra,
+                    expires,
+                    deliver_at,
+                    priority,
+                    fragment,
+                )| Headers {
+                    content_type,
+                    correlation_id,
+                    timestamp,
+                    key,
+                    extra,
+                    expires,
+                    deliver_at,
+                    priority,
+                    fragment,
+                },
+            )
+    }
+
+    fn channel_options() -> impl Strategy<Value = ChannelOptions> {
+        (
+            any::<Option<String>>(),
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(description, persistent, retain, compact)| ChannelOptions {
+                    description,
+                    persistent,
+                    retain,
+                    compact,
+                },
+            )
+    }
+
+    fn channel_info() -> impl Strategy<Value = ChannelInfo> {
+        (
+            any_channel_id(),
+            any::<String>(),
+            any::<u64>(),
+            proptest::option::of(peer_id()),
+            any::<u64>(),
+            any::<u64>(),
+            any::<u64>(),
+            any::<u64>(),
+            channel_options(),
+        )
+            .prop_map(
+                |(id, name, created, owner, subscribers, messages, bytes, deliveries, options)| {
+                    ChannelInfo {
+                        id,
+                        name,
+                        created,
+                        owner,
+                        subscribers,
+                        messages,
+                        bytes,
+                        deliveries,
+                        options,
+                    }
+                },
+            )
+    }
+
+    fn channel_msg() -> impl Strategy<Value = ChannelMsg> {
+        (
+            peer_id(),
+            any_channel_id(),
+            headers(),
+            vec(any::<u8>(), 0..256),
+        )
+            .prop_map(|(sender, channel, headers, content)| {
+                ChannelMsg::new(sender, channel, content).with_headers(headers)
+            })
+    }
+
+    fn control_msg() -> impl Strategy<Value = ControlMsg> {
+        prop_oneof![
+            any::<String>().prop_map(ControlMsg::ChannelJoin),
+            any::<String>().prop_map(ControlMsg::ChannelCreate),
+            any_channel_id().prop_map(ControlMsg::ChannelLeave),
+            any_channel_id().prop_map(ControlMsg::ChannelMembers),
+            any::<Option<String>>().prop_map(ControlMsg::ChannelList),
+            any_channel_id().prop_map(ControlMsg::ChannelInfo),
+            (any_channel_id(), channel_options())
+                .prop_map(|(id, options)| ControlMsg::ChannelConfigure(id, options)),
+            any_channel_id().prop_map(ControlMsg::ChannelDelete),
+            vec(compression(), 0..3)
+                .prop_map(|compression| ControlMsg::Hello(Hello { compression })),
+            any::<String>().prop_map(ControlMsg::Auth),
+        ]
+    }
+
+    fn status_msg() -> impl Strategy<Value = StatusMsg> {
+        prop_oneof![
+            Just(StatusMsg::Connecting),
+            Just(StatusMsg::Connected),
+            Just(StatusMsg::Disconnected),
+            (any::<String>(), any_channel_id())
+                .prop_map(|(name, id)| StatusMsg::ChannelId(name, id)),
+            (any_channel_id(), peer_id()).prop_map(|(id, peer)| StatusMsg::ChannelJoined(id, peer)),
+            (any_channel_id(), peer_id()).prop_map(|(id, peer)| StatusMsg::ChannelLeft(id, peer)),
+            (any_channel_id(), vec(peer_id(), 0..4))
+                .prop_map(|(id, peers)| StatusMsg::ChannelMembers(id, peers)),
+            vec((any::<String>(), any_channel_id()), 0..4).prop_map(StatusMsg::ChannelList),
+            channel_info().prop_map(StatusMsg::ChannelInfo),
+            any_channel_id().prop_map(StatusMsg::ChannelDeleted),
+            any::<String>().prop_map(StatusMsg::Error),
+            proptest::option::of(compression())
+                .prop_map(|compression| StatusMsg::Welcome(Welcome { compression })),
+            Just(StatusMsg::ShuttingDown),
+        ]
+    }
+
+    fn msg() -> impl Strategy<Value = Msg> {
+        prop_oneof![
+            channel_msg().prop_map(Msg::ChannelMsg),
+            control_msg().prop_map(Msg::ControlMsg),
+            status_msg().prop_map(Msg::StatusMsg),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(msg in msg()) {
+            check_roundtrip(&msg);
+        }
+    }
+}